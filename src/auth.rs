@@ -0,0 +1,301 @@
+use actix_web::{dev::Payload, http::header, web, FromRequest, HttpRequest};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::future::{ready, Ready};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::error::ApiError;
+use crate::AppState;
+
+const TOKEN_TTL_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String, // username
+    pub exp: u64,    // expiry, seconds since the epoch
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Moderator, // may moderate any room, not just the ones they created
+    Admin,     // moderator rights plus the /admin endpoints
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+// Argon2id hash in PHC string form, with a fresh random salt
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .is_ok_and(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+}
+
+// Ed25519 public key registered at signup, base64 of the raw 32 bytes
+pub fn parse_public_key(encoded: &str) -> Result<VerifyingKey, String> {
+    let bytes = BASE64.decode(encoded).map_err(|_| "public_key must be base64".to_string())?;
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| "public_key must be a 32-byte Ed25519 key".to_string())?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| "public_key is not a valid Ed25519 key".to_string())
+}
+
+// Checks a base64 Ed25519 signature over the message text; the server never holds private keys
+pub fn verify_signature(key: &VerifyingKey, text: &str, signature: &str) -> bool {
+    let Ok(bytes) = BASE64.decode(signature) else {
+        return false;
+    };
+    Signature::from_slice(&bytes).is_ok_and(|signature| key.verify(text.as_bytes(), &signature).is_ok())
+}
+
+// Issues an HS256 token for a user who has just logged in
+pub fn issue_token(username: &str, secret: &str) -> Result<String, jsonwebtoken::errors::Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let claims = Claims {
+        sub: username.to_string(),
+        exp: now + TOKEN_TTL_SECS,
+    };
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes()))
+}
+
+// Checks the signature and expiry of a token issued by `issue_token`
+pub fn validate_token(token: &str, secret: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+    decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::default(),
+    )
+    .map(|data| data.claims)
+}
+
+// The JWT signing keys. Tokens are issued with the current key; a rotation retires it, and
+// tokens signed with a retired key keep validating until its grace window is over, so that
+// rotating doesn't log everybody out at once.
+pub struct KeyRing {
+    keys: RwLock<Keys>,
+    grace: Duration,
+}
+
+struct Keys {
+    current: String,
+    retired: Vec<RetiredKey>, // newest first
+}
+
+struct RetiredKey {
+    secret: String,
+    valid_until: Instant,
+}
+
+const GENERATED_KEY_BYTES: usize = 32;
+
+impl KeyRing {
+    pub fn new(current: String, grace: Duration) -> Self {
+        KeyRing {
+            keys: RwLock::new(Keys {
+                current,
+                retired: Vec::new(),
+            }),
+            grace,
+        }
+    }
+
+    pub fn issue(&self, username: &str) -> Result<String, jsonwebtoken::errors::Error> {
+        issue_token(username, &self.keys.read().unwrap().current)
+    }
+
+    // Tries the current key, then the retired keys still inside their grace window
+    pub fn validate(&self, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+        let keys = self.keys.read().unwrap();
+        let now = Instant::now();
+        let mut result = validate_token(token, &keys.current);
+        for retired in keys.retired.iter().filter(|retired| now < retired.valid_until) {
+            if result.is_ok() {
+                break;
+            }
+            result = validate_token(token, &retired.secret);
+        }
+        result
+    }
+
+    // Makes `secret` (or a freshly generated key) the current one; returns how many retired
+    // keys are still accepted afterwards
+    pub fn rotate(&self, secret: Option<String>) -> usize {
+        let secret = secret.unwrap_or_else(|| {
+            let mut bytes = [0u8; GENERATED_KEY_BYTES];
+            OsRng.fill_bytes(&mut bytes);
+            BASE64.encode(bytes)
+        });
+        let mut keys = self.keys.write().unwrap();
+        let previous = std::mem::replace(&mut keys.current, secret);
+        let now = Instant::now();
+        keys.retired.retain(|retired| now < retired.valid_until);
+        keys.retired.insert(
+            0,
+            RetiredKey {
+                secret: previous,
+                valid_until: now + self.grace,
+            },
+        );
+        keys.retired.len()
+    }
+
+    pub fn grace(&self) -> Duration {
+        self.grace
+    }
+}
+
+// Extractor for handlers that need a logged-in caller (`Authorization: Bearer <token>`).
+// Roles come from the user store, so grants apply without logging in again.
+pub struct AuthUser {
+    pub username: String,
+    pub roles: HashSet<Role>,
+}
+
+impl AuthUser {
+    pub fn has_role(&self, role: Role) -> bool {
+        self.roles.contains(&role)
+    }
+
+    pub fn require_role(&self, role: Role) -> Result<(), ApiError> {
+        if self.has_role(role) {
+            Ok(())
+        } else {
+            Err(ApiError::forbidden(format!("The {} role is required", role.as_str())))
+        }
+    }
+
+    // Moderators and admins may moderate every room
+    pub fn is_moderator(&self) -> bool {
+        self.has_role(Role::Moderator) || self.has_role(Role::Admin)
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(authenticate(req))
+    }
+}
+
+// The `Authorization: Bearer <token>` value, if the request has one
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+fn authenticate(req: &HttpRequest) -> Result<AuthUser, ApiError> {
+    let data = req
+        .app_data::<web::Data<Arc<AppState>>>()
+        .ok_or_else(|| ApiError::internal("Application state missing"))?;
+
+    let token = bearer_token(req).ok_or_else(|| ApiError::unauthorized("Missing bearer token"))?;
+
+    let claims = data
+        .jwt_keys
+        .validate(token)
+        .map_err(|_| ApiError::unauthorized("Invalid or expired token"))?;
+    let roles = data
+        .users
+        .lock()
+        .unwrap()
+        .get(&claims.sub)
+        .map(|user| user.roles.clone())
+        .unwrap_or_default();
+    Ok(AuthUser {
+        username: claims.sub,
+        roles,
+    })
+}
+
+// Extractor for /admin endpoints: either the `X-Admin-Token` header matches the configured
+// ADMIN_TOKEN, or the bearer token belongs to a user with the admin role
+pub struct AdminAuth;
+
+impl FromRequest for AdminAuth {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(authenticate_admin(req))
+    }
+}
+
+fn authenticate_admin(req: &HttpRequest) -> Result<AdminAuth, ApiError> {
+    let data = req
+        .app_data::<web::Data<Arc<AppState>>>()
+        .ok_or_else(|| ApiError::internal("Application state missing"))?;
+
+    let provided = req
+        .headers()
+        .get("X-Admin-Token")
+        .and_then(|value| value.to_str().ok());
+    if let (Some(expected), Some(provided)) = (&data.admin_token, provided) {
+        if constant_time_eq(expected.as_bytes(), provided.as_bytes()) {
+            return Ok(AdminAuth);
+        }
+        return Err(ApiError::unauthorized("Admin token required"));
+    }
+
+    if req.headers().contains_key(header::AUTHORIZATION) {
+        authenticate(req)?.require_role(Role::Admin)?;
+        return Ok(AdminAuth);
+    }
+    Err(ApiError::unauthorized("Admin token required"))
+}
+
+// Comparison time doesn't depend on where the inputs differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retired_keys_validate_only_during_the_grace_window() {
+        let grace = Duration::from_millis(200);
+        let keys = KeyRing::new("first-secret-0123456789abcdef012345".to_string(), grace);
+        let old_token = keys.issue("alice").unwrap();
+
+        assert_eq!(keys.rotate(Some("second-secret-0123456789abcdef01234".to_string())), 1);
+        let new_token = keys.issue("alice").unwrap();
+        assert_ne!(new_token, old_token);
+        assert_eq!(keys.validate(&old_token).unwrap().sub, "alice");
+        assert_eq!(keys.validate(&new_token).unwrap().sub, "alice");
+
+        std::thread::sleep(grace);
+        assert!(keys.validate(&old_token).is_err());
+        assert!(keys.validate(&new_token).is_ok());
+        // The expired key is dropped at the next rotation
+        assert_eq!(keys.rotate(None), 1);
+        assert!(keys.validate(&new_token).is_ok());
+    }
+}
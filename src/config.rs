@@ -0,0 +1,429 @@
+use std::env;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use uuid::Uuid;
+
+pub const MIN_JWT_SECRET_LEN: usize = 32;
+
+// Runtime settings read from the environment at startup
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub port: u16,              // listens on 127.0.0.1 at this port
+    pub workers: Option<usize>, // None -> actix default (one per CPU)
+    pub json_limit: usize,      // max JSON request body size in bytes
+    pub jwt_secret: String,     // HMAC key for auth tokens, at least MIN_JWT_SECRET_LEN bytes
+    pub allowed_origins: Option<Vec<String>>, // None -> any origin (development)
+    pub max_history: usize,     // upper bound (and default) for a room's stored history
+    pub admin_token: Option<String>, // X-Admin-Token value AdminAuth accepts besides the admin role
+    pub room_gc_ttl: Duration,  // idle rooms older than this are garbage collected
+    pub seed_admin: Option<SeedAdmin>, // admin account created at startup if it doesn't exist
+    pub store_backend: StoreBackend, // where chat history is kept
+    pub max_ws_per_ip: usize,   // concurrent WebSocket connections allowed from one address
+    pub ws_send_buffer: usize,  // unsent bytes a WebSocket client may fall behind before it is dropped
+    pub strict_events: bool,    // default for ?strict: reject unknown WebSocket event types
+    pub default_room: Option<Uuid>, // room every new account joins, created at startup if missing
+    pub edit_window_secs: u64,  // default for how long after sending a message can be edited
+    pub shutdown_grace: Duration, // how long SIGTERM waits for WebSocket clients to leave
+    pub sanitize_mode: SanitizeMode, // what happens to control characters in message text
+    pub username_blocklist: Vec<String>, // names nobody may register or use as a guest, lowercase
+    pub key_rotation_grace: Duration, // how long tokens signed with a rotated-out key stay valid
+    pub trusted_proxies: Vec<IpAddr>, // reverse proxies allowed to report the client address
+    pub debug_endpoints: bool,  // enables the /admin/debug test hooks; never set in production
+    pub max_rooms: Option<usize>, // rooms the server holds at most; None -> no limit
+    pub max_connections: Option<usize>, // WebSocket connections across all clients; None -> no limit
+    pub wordlist_path: Option<PathBuf>, // words refused in messages, reloaded by /admin/reload_wordlist
+    pub transient_rate: Option<usize>, // typing/presence frames per second per session; None -> unthrottled
+    pub new_member_cooldown: Option<Duration>, // how long newcomers to a room must wait to post; None -> off
+    pub max_reactions_per_user: usize, // different emojis one user may put on a single message
+    pub reaction_cooldown: Duration,   // least time between a session's reaction changes
+    pub max_active_polls: usize,       // polls a room may have open at once
+    pub record_client_ips: bool, // keep session addresses for /admin/connections and the connect log
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreBackend {
+    Memory,
+}
+
+// Control characters other than newlines and tabs are either removed or refuse the message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SanitizeMode {
+    Strip,
+    Reject,
+}
+
+#[derive(Debug, Clone)]
+pub struct SeedAdmin {
+    pub username: String,
+    pub password: String,
+}
+
+const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_JSON_LIMIT: usize = 16 * 1024;
+const DEFAULT_MAX_HISTORY: usize = 1000;
+const DEFAULT_ROOM_GC_TTL_SECS: usize = 24 * 60 * 60;
+const DEFAULT_MAX_WS_PER_IP: usize = 20;
+const DEFAULT_WS_SEND_BUFFER: usize = 1024 * 1024;
+const DEFAULT_EDIT_WINDOW_SECS: usize = 15 * 60;
+const DEFAULT_SHUTDOWN_GRACE_SECS: usize = 30;
+const DEFAULT_KEY_ROTATION_GRACE_SECS: usize = 60 * 60;
+const DEFAULT_MAX_REACTIONS_PER_USER: usize = 10;
+const DEFAULT_REACTION_COOLDOWN_MS: usize = 250;
+const DEFAULT_MAX_ACTIVE_POLLS: usize = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    MissingJwtSecret,
+    WeakJwtSecret { len: usize },
+    UnknownStoreBackend { name: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MissingJwtSecret => write!(f, "JWT_SECRET must be set"),
+            ConfigError::WeakJwtSecret { len } => write!(
+                f,
+                "JWT_SECRET is {} bytes long, it must be at least {} bytes",
+                len, MIN_JWT_SECRET_LEN
+            ),
+            ConfigError::UnknownStoreBackend { name } => {
+                write!(f, "STORE_BACKEND {:?} is not supported (available: memory)", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        ConfigBuilder::from_env().build()
+    }
+
+    // The defaults, with a throwaway secret
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        ConfigBuilder {
+            jwt_secret: Some("x".repeat(MIN_JWT_SECRET_LEN)),
+            ..ConfigBuilder::default()
+        }
+        .build()
+        .unwrap()
+    }
+}
+
+// Collects raw settings and validates them all at once in `build`
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    port: Option<u16>,
+    workers: Option<usize>,
+    json_limit: Option<usize>,
+    jwt_secret: Option<String>,
+    allowed_origins: Option<Vec<String>>,
+    max_history: Option<usize>,
+    admin_token: Option<String>,
+    room_gc_ttl_secs: Option<usize>,
+    seed_admin: Option<SeedAdmin>,
+    store_backend: Option<String>,
+    max_ws_per_ip: Option<usize>,
+    ws_send_buffer: Option<usize>,
+    strict_events: Option<bool>,
+    default_room: Option<Uuid>,
+    edit_window_secs: Option<usize>,
+    shutdown_grace_secs: Option<usize>,
+    sanitize_mode: Option<SanitizeMode>,
+    username_blocklist: Option<Vec<String>>,
+    key_rotation_grace_secs: Option<usize>,
+    trusted_proxies: Option<Vec<IpAddr>>,
+    debug_endpoints: Option<bool>,
+    max_rooms: Option<usize>,
+    max_connections: Option<usize>,
+    wordlist_path: Option<PathBuf>,
+    transient_rate: Option<usize>,
+    new_member_cooldown_secs: Option<usize>,
+    max_reactions_per_user: Option<usize>,
+    reaction_cooldown_ms: Option<usize>,
+    max_active_polls: Option<usize>,
+    record_client_ips: Option<bool>,
+}
+
+impl ConfigBuilder {
+    pub fn from_env() -> Self {
+        ConfigBuilder {
+            port: parse_port(env::var("PORT").ok().as_deref()),
+            workers: parse_workers(env::var("WORKERS").ok().as_deref()),
+            json_limit: parse_positive("JSON_LIMIT", env::var("JSON_LIMIT").ok().as_deref()),
+            jwt_secret: env::var("JWT_SECRET").ok(),
+            allowed_origins: parse_list(env::var("CORS_ALLOWED_ORIGINS").ok().as_deref()),
+            max_history: parse_positive("MAX_HISTORY", env::var("MAX_HISTORY").ok().as_deref()),
+            admin_token: env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+            room_gc_ttl_secs: parse_positive("ROOM_GC_TTL_SECS", env::var("ROOM_GC_TTL_SECS").ok().as_deref()),
+            seed_admin: parse_seed_admin(
+                env::var("SEED_ADMIN_USER").ok().as_deref(),
+                env::var("SEED_ADMIN_PASS").ok().as_deref(),
+            ),
+            store_backend: env::var("STORE_BACKEND").ok().filter(|name| !name.trim().is_empty()),
+            max_ws_per_ip: parse_positive("MAX_WS_PER_IP", env::var("MAX_WS_PER_IP").ok().as_deref()),
+            ws_send_buffer: parse_positive("WS_SEND_BUFFER_BYTES", env::var("WS_SEND_BUFFER_BYTES").ok().as_deref()),
+            strict_events: parse_bool("STRICT_EVENTS", env::var("STRICT_EVENTS").ok().as_deref()),
+            default_room: parse_uuid("DEFAULT_ROOM_ID", env::var("DEFAULT_ROOM_ID").ok().as_deref()),
+            edit_window_secs: parse_positive("EDIT_WINDOW_SECS", env::var("EDIT_WINDOW_SECS").ok().as_deref()),
+            shutdown_grace_secs: parse_positive(
+                "SHUTDOWN_GRACE_SECS",
+                env::var("SHUTDOWN_GRACE_SECS").ok().as_deref(),
+            ),
+            sanitize_mode: parse_sanitize_mode(env::var("SANITIZE_MODE").ok().as_deref()),
+            username_blocklist: parse_list(env::var("USERNAME_BLOCKLIST").ok().as_deref()),
+            key_rotation_grace_secs: parse_positive(
+                "KEY_ROTATION_GRACE_SECS",
+                env::var("KEY_ROTATION_GRACE_SECS").ok().as_deref(),
+            ),
+            trusted_proxies: parse_ip_list("TRUSTED_PROXIES", env::var("TRUSTED_PROXIES").ok().as_deref()),
+            debug_endpoints: parse_bool("DEBUG_ENDPOINTS", env::var("DEBUG_ENDPOINTS").ok().as_deref()),
+            max_rooms: parse_positive("MAX_ROOMS", env::var("MAX_ROOMS").ok().as_deref()),
+            max_connections: parse_positive("MAX_CONNECTIONS", env::var("MAX_CONNECTIONS").ok().as_deref()),
+            wordlist_path: env::var("WORDLIST_PATH").ok().filter(|path| !path.trim().is_empty()).map(PathBuf::from),
+            transient_rate: parse_positive(
+                "TRANSIENT_EVENTS_PER_SEC",
+                env::var("TRANSIENT_EVENTS_PER_SEC").ok().as_deref(),
+            ),
+            new_member_cooldown_secs: parse_positive(
+                "NEW_MEMBER_COOLDOWN_SECS",
+                env::var("NEW_MEMBER_COOLDOWN_SECS").ok().as_deref(),
+            ),
+            max_reactions_per_user: parse_positive(
+                "MAX_REACTIONS_PER_USER",
+                env::var("MAX_REACTIONS_PER_USER").ok().as_deref(),
+            ),
+            reaction_cooldown_ms: parse_positive("REACTION_COOLDOWN_MS", env::var("REACTION_COOLDOWN_MS").ok().as_deref()),
+            max_active_polls: parse_positive("MAX_ACTIVE_POLLS", env::var("MAX_ACTIVE_POLLS").ok().as_deref()),
+            record_client_ips: parse_bool("RECORD_CLIENT_IPS", env::var("RECORD_CLIENT_IPS").ok().as_deref()),
+        }
+    }
+
+    // There is deliberately no fallback secret: a guessable key would let anyone forge tokens
+    pub fn build(self) -> Result<Config, ConfigError> {
+        let jwt_secret = self.jwt_secret.ok_or(ConfigError::MissingJwtSecret)?;
+        if jwt_secret.len() < MIN_JWT_SECRET_LEN {
+            return Err(ConfigError::WeakJwtSecret { len: jwt_secret.len() });
+        }
+        // A typo here would silently lose data with a fallback, so unknown backends are fatal
+        let store_backend = match self.store_backend.as_deref().map(str::trim) {
+            None => StoreBackend::Memory,
+            Some(name) if name.eq_ignore_ascii_case("memory") => StoreBackend::Memory,
+            Some(name) => return Err(ConfigError::UnknownStoreBackend { name: name.to_string() }),
+        };
+
+        Ok(Config {
+            port: self.port.unwrap_or(DEFAULT_PORT),
+            workers: self.workers,
+            json_limit: self.json_limit.unwrap_or(DEFAULT_JSON_LIMIT),
+            jwt_secret,
+            allowed_origins: self.allowed_origins,
+            max_history: self.max_history.unwrap_or(DEFAULT_MAX_HISTORY),
+            admin_token: self.admin_token,
+            room_gc_ttl: Duration::from_secs(self.room_gc_ttl_secs.unwrap_or(DEFAULT_ROOM_GC_TTL_SECS) as u64),
+            seed_admin: self.seed_admin,
+            store_backend,
+            max_ws_per_ip: self.max_ws_per_ip.unwrap_or(DEFAULT_MAX_WS_PER_IP),
+            ws_send_buffer: self.ws_send_buffer.unwrap_or(DEFAULT_WS_SEND_BUFFER),
+            strict_events: self.strict_events.unwrap_or(false),
+            default_room: self.default_room,
+            edit_window_secs: self.edit_window_secs.unwrap_or(DEFAULT_EDIT_WINDOW_SECS) as u64,
+            shutdown_grace: Duration::from_secs(
+                self.shutdown_grace_secs.unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS) as u64,
+            ),
+            sanitize_mode: self.sanitize_mode.unwrap_or(SanitizeMode::Strip),
+            username_blocklist: self
+                .username_blocklist
+                .unwrap_or_default()
+                .iter()
+                .map(|name| name.to_lowercase())
+                .collect(),
+            key_rotation_grace: Duration::from_secs(
+                self.key_rotation_grace_secs.unwrap_or(DEFAULT_KEY_ROTATION_GRACE_SECS) as u64,
+            ),
+            trusted_proxies: self.trusted_proxies.unwrap_or_default(),
+            debug_endpoints: self.debug_endpoints.unwrap_or(false),
+            max_rooms: self.max_rooms,
+            max_connections: self.max_connections,
+            wordlist_path: self.wordlist_path,
+            transient_rate: self.transient_rate,
+            new_member_cooldown: self.new_member_cooldown_secs.map(|secs| Duration::from_secs(secs as u64)),
+            max_reactions_per_user: self.max_reactions_per_user.unwrap_or(DEFAULT_MAX_REACTIONS_PER_USER),
+            reaction_cooldown: Duration::from_millis(
+                self.reaction_cooldown_ms.unwrap_or(DEFAULT_REACTION_COOLDOWN_MS) as u64,
+            ),
+            max_active_polls: self.max_active_polls.unwrap_or(DEFAULT_MAX_ACTIVE_POLLS),
+            record_client_ips: self.record_client_ips.unwrap_or(true),
+        })
+    }
+}
+
+// Accepts a positive integer; anything else falls back to the default worker count
+pub fn parse_workers(value: Option<&str>) -> Option<usize> {
+    parse_positive("WORKERS", value)
+}
+
+// 1-65535; anything else falls back to the default port
+fn parse_port(value: Option<&str>) -> Option<u16> {
+    let port = parse_positive("PORT", value)?;
+    match u16::try_from(port) {
+        Ok(port) => Some(port),
+        Err(_) => {
+            log::warn!("Ignoring invalid PORT value {:?}, using default", port);
+            None
+        }
+    }
+}
+
+// Parses a positive integer setting, warning about and ignoring invalid values
+fn parse_positive(name: &str, value: Option<&str>) -> Option<usize> {
+    let value = value?;
+    match value.trim().parse::<usize>() {
+        Ok(parsed) if parsed > 0 => Some(parsed),
+        _ => {
+            log::warn!("Ignoring invalid {} value {:?}, using default", name, value);
+            None
+        }
+    }
+}
+
+// Parses a true/false setting (also 1/0), warning about and ignoring invalid values
+fn parse_bool(name: &str, value: Option<&str>) -> Option<bool> {
+    let value = value?;
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => {
+            log::warn!("Ignoring invalid {} value {:?}, using default", name, value);
+            None
+        }
+    }
+}
+
+// Parses a UUID setting, warning about and ignoring invalid values
+fn parse_uuid(name: &str, value: Option<&str>) -> Option<Uuid> {
+    let value = value?;
+    match Uuid::parse_str(value.trim()) {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            log::warn!("Ignoring invalid {} value {:?}", name, value);
+            None
+        }
+    }
+}
+
+// "strip" or "reject"; anything else is ignored with a warning
+fn parse_sanitize_mode(value: Option<&str>) -> Option<SanitizeMode> {
+    let value = value?;
+    match value.trim().to_ascii_lowercase().as_str() {
+        "strip" => Some(SanitizeMode::Strip),
+        "reject" => Some(SanitizeMode::Reject),
+        _ => {
+            log::warn!("Ignoring invalid SANITIZE_MODE value {:?}, using default", value);
+            None
+        }
+    }
+}
+
+// Comma-separated list; unset or empty means "not configured"
+fn parse_list(value: Option<&str>) -> Option<Vec<String>> {
+    let items: Vec<String> = value?
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect();
+    (!items.is_empty()).then_some(items)
+}
+
+// Comma-separated IP addresses; invalid entries are skipped with a warning
+fn parse_ip_list(name: &str, value: Option<&str>) -> Option<Vec<IpAddr>> {
+    let items = parse_list(value)?;
+    let ips: Vec<IpAddr> = items
+        .iter()
+        .filter_map(|item| match item.parse() {
+            Ok(ip) => Some(ip),
+            Err(_) => {
+                log::warn!("Ignoring invalid {} entry {:?}", name, item);
+                None
+            }
+        })
+        .collect();
+    (!ips.is_empty()).then_some(ips)
+}
+
+// Both variables are needed; setting only one of them is almost certainly a mistake
+fn parse_seed_admin(username: Option<&str>, password: Option<&str>) -> Option<SeedAdmin> {
+    let username = username.map(str::trim).filter(|value| !value.is_empty());
+    let password = password.filter(|value| !value.is_empty());
+    match (username, password) {
+        (Some(username), Some(password)) => Some(SeedAdmin {
+            username: username.to_string(),
+            password: password.to_string(),
+        }),
+        (None, None) => None,
+        _ => {
+            log::warn!("SEED_ADMIN_USER and SEED_ADMIN_PASS must be set together, not seeding an admin");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_workers_accepts_positive_numbers() {
+        assert_eq!(parse_workers(Some("4")), Some(4));
+        assert_eq!(parse_workers(Some(" 2 ")), Some(2));
+    }
+
+    #[test]
+    fn parse_workers_falls_back_on_invalid_values() {
+        assert_eq!(parse_workers(None), None);
+        assert_eq!(parse_workers(Some("0")), None);
+        assert_eq!(parse_workers(Some("-1")), None);
+        assert_eq!(parse_workers(Some("four")), None);
+        assert_eq!(parse_workers(Some("")), None);
+    }
+
+    #[test]
+    fn parse_port_rejects_out_of_range_values() {
+        assert_eq!(parse_port(Some("9000")), Some(9000));
+        assert_eq!(parse_port(Some("65536")), None);
+        assert_eq!(parse_port(Some("0")), None);
+    }
+
+    fn with_secret(secret: Option<&str>) -> ConfigBuilder {
+        ConfigBuilder {
+            jwt_secret: secret.map(str::to_string),
+            ..ConfigBuilder::default()
+        }
+    }
+
+    #[test]
+    fn build_requires_a_jwt_secret() {
+        assert_eq!(with_secret(None).build().unwrap_err(), ConfigError::MissingJwtSecret);
+    }
+
+    #[test]
+    fn build_rejects_a_short_jwt_secret() {
+        let secret = "x".repeat(MIN_JWT_SECRET_LEN - 1);
+        assert_eq!(
+            with_secret(Some(&secret)).build().unwrap_err(),
+            ConfigError::WeakJwtSecret { len: MIN_JWT_SECRET_LEN - 1 }
+        );
+        assert_eq!(with_secret(Some("")).build().unwrap_err(), ConfigError::WeakJwtSecret { len: 0 });
+    }
+
+    #[test]
+    fn build_accepts_a_long_enough_jwt_secret() {
+        let secret = "x".repeat(MIN_JWT_SECRET_LEN);
+        assert_eq!(with_secret(Some(&secret)).build().unwrap().jwt_secret, secret);
+    }
+}
@@ -0,0 +1,50 @@
+// Length limits for user-supplied text, in characters, checked after surrounding whitespace
+// has been trimmed
+pub const MIN_USERNAME_LEN: usize = 3;
+pub const MAX_USERNAME_LEN: usize = 32;
+pub const MAX_ROOM_NAME_LEN: usize = 64;
+pub const MAX_MESSAGE_LEN: usize = 4000;
+pub const MAX_TOPIC_LEN: usize = 256;
+pub const MAX_STATUS_LEN: usize = 100;
+pub const MAX_WELCOME_LEN: usize = 1000;
+pub const MAX_REPORT_REASON_LEN: usize = 500;
+pub const MAX_TEMPLATE_NAME_LEN: usize = 32;
+pub const MAX_MOTD_LEN: usize = 500;
+pub const MAX_POLL_QUESTION_LEN: usize = 256;
+pub const MAX_POLL_OPTION_LEN: usize = 100;
+
+// The error names the field, so clients can tell which input to shorten
+pub fn check_len(field: &str, value: &str, max: usize) -> Result<(), String> {
+    if value.chars().count() > max {
+        Err(format!("{} must be at most {} characters", field, max))
+    } else {
+        Ok(())
+    }
+}
+
+// Trims in place, so what gets checked is also what gets stored
+pub fn trim(value: &mut String) {
+    let trimmed = value.trim();
+    if trimmed.len() != value.len() {
+        *value = trimmed.to_string();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_len_counts_characters_and_names_the_field() {
+        assert!(check_len("topic", &"é".repeat(MAX_TOPIC_LEN), MAX_TOPIC_LEN).is_ok());
+        let err = check_len("topic", &"a".repeat(MAX_TOPIC_LEN + 1), MAX_TOPIC_LEN).unwrap_err();
+        assert_eq!(err, "topic must be at most 256 characters");
+    }
+
+    #[test]
+    fn trim_strips_surrounding_whitespace_only() {
+        let mut value = "  two words \n".to_string();
+        trim(&mut value);
+        assert_eq!(value, "two words");
+    }
+}
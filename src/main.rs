@@ -1,259 +1,5889 @@
 use actix::prelude::*;
-use actix_web::{web, App, HttpServer, HttpResponse, HttpRequest};
+use actix_web::{http::{header, StatusCode}, web, App, HttpServer, HttpResponse, HttpRequest, ResponseError};
+use actix_web::dev::Service;
 use actix_web_actors::ws;
+use actix_http::ws::Item;
 use actix_cors::Cors;
 use serde::{Deserialize, Serialize};
+use url::Url;
 use uuid::Uuid;
-use std::collections::{HashMap, HashSet};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ed25519_dalek::VerifyingKey;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+mod auth;
+mod config;
+mod error;
+mod limits;
+mod metrics;
+mod outbound;
+mod rate_limit;
+mod store;
+mod webhook;
+mod wordlist;
+
+use auth::{AdminAuth, AuthUser, Role};
+use config::{Config, SanitizeMode};
+use error::{json_error_handler, path_error_handler, route_not_found, ApiError};
+use limits::{
+    check_len, MAX_MESSAGE_LEN, MAX_MOTD_LEN, MAX_POLL_OPTION_LEN, MAX_POLL_QUESTION_LEN, MAX_REPORT_REASON_LEN,
+    MAX_ROOM_NAME_LEN, MAX_STATUS_LEN, MAX_TEMPLATE_NAME_LEN, MAX_TOPIC_LEN, MAX_USERNAME_LEN, MAX_WELCOME_LEN,
+    MIN_USERNAME_LEN,
+};
+use rate_limit::{too_many_requests, LockoutTracker, RateLimiter, TokenBucket};
+use store::MessageStore;
 
 #[derive(Serialize, Deserialize, Clone)]
 struct Room {
     id: Uuid,
     name: String,
     creator: String,
-    users: HashSet<String>,
-    history: Vec<ChatMessage>,
+    // Members are paged through /rooms/{room_id}/members; room JSON only carries the count
+    #[serde(rename = "user_count", serialize_with = "serialize_len", skip_deserializing)]
+    users: HashMap<String, u64>, // username -> when they joined, epoch ms
+    #[serde(default)]
+    ephemeral: bool, // no history is kept for ephemeral rooms
+    #[serde(default)]
+    topic: String,
+    #[serde(default)]
+    last_seq: u64, // sequence number of the room's latest message
+    #[serde(skip)]
+    banned: HashSet<String>, // only visible to the creator via /rooms/{room_id}/banned
+    history_size: usize,     // how many messages the room keeps, at most the global max
+    #[serde(default)]
+    private: bool, // only the creator and invited members can see and join it
+    #[serde(default)]
+    suppress_duplicates: bool, // drop a user's accidental double-sends
+    #[serde(skip)]
+    last_messages: HashMap<String, (String, Instant)>, // username -> last text, for duplicate suppression
+    #[serde(default)]
+    persistent: bool, // never garbage collected, even when idle
+    #[serde(skip)]
+    last_activity_ms: u64, // creation or latest message, for idle room GC
+    #[serde(skip)]
+    webhook_url: Option<Url>, // POSTed every message; set by the creator
+    #[serde(skip)]
+    muted: HashMap<String, Instant>, // username -> when the mute ends
+    #[serde(skip)]
+    typing: HashMap<String, Instant>, // username -> last typing event, expires after TYPING_EXPIRY
+    #[serde(skip)]
+    last_session_end_ms: Option<u64>, // when a session last left, for /rooms/{room_id}/idle
+    #[serde(skip)]
+    templates: HashMap<String, String>, // name -> text; canned replies managed by moderators
+    #[serde(skip)]
+    arrivals: HashMap<String, u64>, // non-member -> first connect, epoch ms; for the new member cooldown
+    #[serde(skip)]
+    polls: Vec<Poll>, // open polls, oldest first; at most AppState::max_active_polls
+    #[serde(default)]
+    created_at: u64, // epoch ms
+    #[serde(default)]
+    require_signatures: bool, // every message must be signed with the sender's registered key
+    #[serde(default)]
+    edit_window_secs: u64, // authors may edit a message for this long after sending it
+    #[serde(default)]
+    welcome_message: String, // sent to every session that connects; empty for none
+    #[serde(default)]
+    archived: bool, // read-only: history stays readable, but nobody can post or connect
+    #[serde(default)]
+    moderators: HashSet<String>, // appointed by the creator, may moderate like them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_message_at: Option<u64>, // epoch ms; None until the first message
+    #[serde(default)]
+    share_read_cursors: bool, // tell other sessions how far each member has read; off for privacy
+    #[serde(default)]
+    message_ttl_secs: u64, // messages sent without their own ttl_secs expire after this; 0 for never
+    #[serde(default = "all_message_kinds")]
+    allowed_message_types: BTreeSet<MessageKind>, // what members may post; everything by default
+}
+
+// What a room can be limited to through allowed_message_types
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+enum MessageKind {
+    Message, // chat text without an attachment
+    Reaction,
+    File, // a message carrying an attachment
+    Poll,
+}
+
+impl MessageKind {
+    fn refusal(self) -> ServerEvent {
+        let message = match self {
+            MessageKind::Message => "This room doesn't allow chat messages",
+            MessageKind::Reaction => "This room doesn't allow reactions",
+            MessageKind::File => "This room doesn't allow attachments",
+            MessageKind::Poll => "This room doesn't allow polls",
+        };
+        ServerEvent::Error {
+            code: WsErrorCode::TypeNotAllowed,
+            message: message.to_string(),
+        }
+    }
+}
+
+fn all_message_kinds() -> BTreeSet<MessageKind> {
+    [MessageKind::Message, MessageKind::Reaction, MessageKind::File, MessageKind::Poll].into()
+}
+
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(3);
+const TYPING_EXPIRY: Duration = Duration::from_secs(3);
+
+// The adjustable part of a room, as sent in settings frames
+#[derive(Serialize, Clone)]
+struct RoomSettings {
+    topic: String,
+    ephemeral: bool,
+    history_size: usize,
+    private: bool,
+    suppress_duplicates: bool,
+    persistent: bool,
+    require_signatures: bool,
+    edit_window_secs: u64,
+    welcome_message: String,
+    archived: bool, // changed through /rooms/{room_id}/archive and /unarchive only
+    share_read_cursors: bool,
+    message_ttl_secs: u64,
+    allowed_message_types: BTreeSet<MessageKind>,
+}
+
+// update_settings: fields left out keep their current value
+#[derive(Deserialize)]
+struct SettingsUpdate {
+    topic: Option<String>,
+    ephemeral: Option<bool>, // only accepted if unchanged, see `validate`
+    history_size: Option<usize>,
+    private: Option<bool>,
+    suppress_duplicates: Option<bool>,
+    persistent: Option<bool>,
+    require_signatures: Option<bool>,
+    edit_window_secs: Option<u64>,
+    welcome_message: Option<String>,
+    share_read_cursors: Option<bool>,
+    message_ttl_secs: Option<u64>, // 0 turns the room default off
+    allowed_message_types: Option<BTreeSet<MessageKind>>, // replaces the whole set
+}
+
+const MAX_EDIT_WINDOW_SECS: u64 = 7 * 24 * 60 * 60;
+const MAX_MESSAGE_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+impl SettingsUpdate {
+    // Moderators may only change allowed_message_types; any other field needs the creator.
+    // Destructured so that a new field has to be sorted into one group or the other.
+    fn needs_creator(&self) -> bool {
+        let SettingsUpdate {
+            topic,
+            ephemeral,
+            history_size,
+            private,
+            suppress_duplicates,
+            persistent,
+            require_signatures,
+            edit_window_secs,
+            welcome_message,
+            share_read_cursors,
+            message_ttl_secs,
+            allowed_message_types: _,
+        } = self;
+        topic.is_some()
+            || ephemeral.is_some()
+            || history_size.is_some()
+            || private.is_some()
+            || suppress_duplicates.is_some()
+            || persistent.is_some()
+            || require_signatures.is_some()
+            || edit_window_secs.is_some()
+            || welcome_message.is_some()
+            || share_read_cursors.is_some()
+            || message_ttl_secs.is_some()
+    }
+
+    // Checks every field before anything is applied, so an update is all or nothing
+    fn validate(&self, room: &Room, max_history: usize) -> Result<(), String> {
+        if let Some(topic) = &self.topic {
+            check_len("topic", topic, MAX_TOPIC_LEN)?;
+        }
+        // Switching would either orphan or lose the stored history
+        if self.ephemeral.is_some_and(|ephemeral| ephemeral != room.ephemeral) {
+            return Err("ephemeral can only be chosen when the room is created".to_string());
+        }
+        if self.history_size.is_some_and(|size| !(1..=max_history).contains(&size)) {
+            return Err(format!("history_size must be between 1 and {}", max_history));
+        }
+        if self.edit_window_secs.is_some_and(|secs| secs > MAX_EDIT_WINDOW_SECS) {
+            return Err(format!("edit_window_secs must be at most {}", MAX_EDIT_WINDOW_SECS));
+        }
+        if let Some(text) = &self.welcome_message {
+            check_len("welcome_message", text, MAX_WELCOME_LEN)?;
+        }
+        if self.message_ttl_secs.is_some_and(|secs| secs > MAX_MESSAGE_TTL_SECS) {
+            return Err(format!("message_ttl_secs must be at most {}", MAX_MESSAGE_TTL_SECS));
+        }
+        Ok(())
+    }
+
+    fn apply(self, room: &mut Room) {
+        if let Some(topic) = self.topic {
+            room.topic = topic;
+        }
+        if let Some(history_size) = self.history_size {
+            room.history_size = history_size;
+        }
+        if let Some(private) = self.private {
+            room.private = private;
+        }
+        if let Some(suppress_duplicates) = self.suppress_duplicates {
+            room.suppress_duplicates = suppress_duplicates;
+        }
+        if let Some(persistent) = self.persistent {
+            room.persistent = persistent;
+        }
+        if let Some(require_signatures) = self.require_signatures {
+            room.require_signatures = require_signatures;
+        }
+        if let Some(edit_window_secs) = self.edit_window_secs {
+            room.edit_window_secs = edit_window_secs;
+        }
+        if let Some(welcome_message) = self.welcome_message {
+            room.welcome_message = welcome_message;
+        }
+        if let Some(share_read_cursors) = self.share_read_cursors {
+            room.share_read_cursors = share_read_cursors;
+        }
+        if let Some(message_ttl_secs) = self.message_ttl_secs {
+            room.message_ttl_secs = message_ttl_secs;
+        }
+        if let Some(allowed_message_types) = self.allowed_message_types {
+            room.allowed_message_types = allowed_message_types;
+        }
+    }
+}
+
+// Counts for room list sidebars, without any message content
+#[derive(Serialize, Clone)]
+struct RoomSummary {
+    room_id: Uuid,
+    member_count: usize,
+    online_count: usize, // distinct users with a session in the room
+    message_count: usize,
+    last_message_at: Option<u64>, // epoch ms
+}
+
+const MAX_SYNC_MEMBERS: usize = 200;
+
+// Everything a reconnecting client needs in one frame; history is fetched separately
+#[derive(Serialize, Clone)]
+struct SyncState {
+    room_id: Uuid,
+    member_count: usize,
+    members: Vec<String>, // sorted, at most MAX_SYNC_MEMBERS of them
+    online: Vec<String>,  // sorted, distinct users with a session in the room
+    last_seq: u64,
+    settings: RoomSettings,
+    unread: usize, // messages from others after this user's read marker
+}
+
+// What the broadcast path needs to know about a message the room accepted
+struct AcceptedMessage {
+    seq: u64,
+    ephemeral: bool,
+    history_size: usize,
+    webhook_url: Option<Url>,
+    message_ttl_secs: u64,
+}
+
+impl Room {
+    fn settings(&self) -> RoomSettings {
+        RoomSettings {
+            topic: self.topic.clone(),
+            ephemeral: self.ephemeral,
+            history_size: self.history_size,
+            private: self.private,
+            suppress_duplicates: self.suppress_duplicates,
+            persistent: self.persistent,
+            require_signatures: self.require_signatures,
+            edit_window_secs: self.edit_window_secs,
+            welcome_message: self.welcome_message.clone(),
+            archived: self.archived,
+            share_read_cursors: self.share_read_cursors,
+            message_ttl_secs: self.message_ttl_secs,
+            allowed_message_types: self.allowed_message_types.clone(),
+        }
+    }
+
+    fn allows(&self, kind: MessageKind) -> bool {
+        self.allowed_message_types.contains(&kind)
+    }
+
+    // Checks an incoming message against the room's rules and assigns its sequence number.
+    // On rejection returns the event to send back to the sender.
+    fn accept_message(
+        &mut self,
+        username: &str,
+        text: &str,
+        kind: MessageKind,
+        cooldown: Option<Duration>,
+    ) -> Result<AcceptedMessage, Box<ServerEvent>> {
+        if self.archived {
+            return Err(Box::new(ServerEvent::Error {
+                code: WsErrorCode::RoomArchived,
+                message: "This room is archived".to_string(),
+            }));
+        }
+        if !self.allows(kind) {
+            return Err(Box::new(kind.refusal()));
+        }
+        if let Some(&until) = self.muted.get(username) {
+            if Instant::now() < until {
+                return Err(Box::new(ServerEvent::Muted));
+            }
+            self.muted.remove(username);
+        }
+        if let Some(retry_after) = cooldown.and_then(|cooldown| self.cooldown_left(username, cooldown)) {
+            return Err(Box::new(ServerEvent::Cooldown { retry_after }));
+        }
+
+        if self.suppress_duplicates {
+            let now = Instant::now();
+            let previous = self.last_messages.insert(username.to_string(), (text.to_string(), now));
+            if let Some((previous_text, sent_at)) = previous {
+                if previous_text == text && now.duration_since(sent_at) < DUPLICATE_WINDOW {
+                    return Err(Box::new(ServerEvent::DuplicateSuppressed));
+                }
+            }
+        }
+
+        // Sending the message ends the typing indicator
+        self.typing.remove(username);
+        self.last_seq += 1;
+        self.last_activity_ms = now_ms();
+        self.last_message_at = Some(self.last_activity_ms);
+        Ok(AcceptedMessage {
+            seq: self.last_seq,
+            ephemeral: self.ephemeral,
+            history_size: self.history_size,
+            webhook_url: self.webhook_url.clone(),
+            message_ttl_secs: self.message_ttl_secs,
+        })
+    }
+
+    // Whole seconds until a newcomer may post, rounded up; None once they've been around for
+    // `cooldown`. Members count from when they joined, guests from their first connect.
+    fn cooldown_left(&self, username: &str, cooldown: Duration) -> Option<u64> {
+        if self.can_moderate(username) {
+            return None;
+        }
+        let arrived = self.users.get(username).or_else(|| self.arrivals.get(username))?;
+        let left_ms = (cooldown.as_millis() as u64).checked_sub(now_ms().saturating_sub(*arrived))?;
+        (left_ms > 0).then(|| left_ms.div_ceil(1000))
+    }
+
+    // Remembers when a guest first connected. Entries past the cooldown are dropped, so a guest
+    // who comes back later starts over; members keep their join time.
+    fn note_arrival(&mut self, username: &str, cooldown: Duration) {
+        let now = now_ms();
+        self.arrivals
+            .retain(|_, arrived| now.saturating_sub(*arrived) < cooldown.as_millis() as u64);
+        if !self.users.contains_key(username) {
+            self.arrivals.entry(username.to_string()).or_insert(now);
+        }
+    }
+
+    // Users whose latest typing event is recent enough, sorted
+    fn typing_users(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        self.typing.retain(|_, at| now.duration_since(*at) < TYPING_EXPIRY);
+        let mut usernames: Vec<String> = self.typing.keys().cloned().collect();
+        usernames.sort();
+        usernames
+    }
+
+    // Whether `username` may see the room in listings, join it and receive its events
+    fn visible_to(&self, username: &str) -> bool {
+        if self.banned.contains(username) {
+            return false;
+        }
+        !self.private || self.is_member(username)
+    }
+
+    // The creator and the moderators they appointed; global moderators are checked separately
+    fn can_moderate(&self, username: &str) -> bool {
+        self.creator == username || self.moderators.contains(username)
+    }
+
+    // The creator counts as a member without having been added
+    fn is_member(&self, username: &str) -> bool {
+        self.creator == username || self.users.contains_key(username)
+    }
+
+    // Adds a member, keeping the original join time if they already are one; true if newly added
+    fn add_member(&mut self, username: &str) -> bool {
+        if self.users.contains_key(username) {
+            return false;
+        }
+        self.users.insert(username.to_string(), now_ms());
+        true
+    }
+}
+
+// Milliseconds since the Unix epoch
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn serialize_len<S: serde::Serializer>(map: &HashMap<String, u64>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(map.len() as u64)
+}
+
+struct AppState {
+    users: Mutex<HashMap<String, User>>,                 // username -> account
+    rooms: Mutex<HashMap<Uuid, Room>>,                  // room_id -> Room
+    connections: Mutex<HashMap<Uuid, Vec<Connection>>>, // room_id -> WebSocket connections
+    messages: Box<dyn MessageStore>,                    // message history of every room
+    resume_points: Mutex<HashMap<Uuid, ResumePoint>>,   // resume token -> where the session left off
+    user_sessions: Mutex<HashMap<String, Vec<UserSession>>>, // username -> sessions in any room
+    watchers: Mutex<HashMap<String, Vec<Addr<WebSocketSession>>>>, // username -> sessions watching their presence
+    direct_messages: Mutex<HashMap<Uuid, DirectMessageRoute>>, // dm id -> sender/recipient, for receipts
+    dm_history: Mutex<HashMap<(String, String), VecDeque<DirectMessage>>>, // see `dm_conversation`
+    metrics: metrics::Metrics,
+    invites: Mutex<HashMap<Uuid, Invite>>,              // invite token -> room it grants membership to
+    scheduled: Mutex<HashMap<Uuid, ScheduledMessage>>,  // id -> message waiting for its send time
+    read_markers: Mutex<HashMap<String, HashMap<Uuid, u64>>>, // username -> room_id -> last read seq
+    ws_per_ip: Mutex<HashMap<String, usize>>,           // client ip -> open WebSocket connections
+    reports: Mutex<HashMap<Uuid, Vec<Report>>>,         // room_id -> abuse reports, oldest first
+    motd: Mutex<Motd>,                                  // banner shown by clients; lost on restart
+    expiries: Mutex<BTreeSet<(u64, Uuid, Uuid)>>,       // (expires_at, room_id, message_id), soonest first
+    started_at: u64,   // epoch ms, when the state was built at startup
+    started: Instant,  // the same moment; uptime is measured from this, immune to clock changes
+    max_ws_per_ip: usize,
+    ws_send_buffer: usize, // bytes a client may fall behind before it is disconnected
+    max_rooms: Option<usize>,
+    max_connections: Option<usize>, // WebSocket sessions across all addresses
+    transient_interval: Option<Duration>, // least time between a session's typing/presence frames
+    new_member_cooldown: Option<Duration>,  // see Room::cooldown_left
+    max_reactions_per_user: usize,          // per message
+    reaction_cooldown: Duration,            // per session, between adding or removing reactions
+    max_active_polls: usize,                // per room
+    strict_events: bool, // see Config::strict_events
+    record_client_ips: bool,
+    default_room: Option<Uuid>, // every newly registered user is added to it
+    edit_window_secs: u64,      // for rooms created without their own
+    draining: AtomicBool,       // set on SIGTERM; new WebSocket sessions are refused
+    sanitize_mode: SanitizeMode,
+    username_blocklist: HashSet<String>, // lowercase, see `check_username`
+    wordlist: wordlist::WordList,        // words refused in messages; reloadable at runtime
+    webhooks: Arc<webhook::Dispatcher>,
+    register_limiter: RateLimiter,                      // client ip -> register attempts
+    create_room_limiter: RateLimiter,                   // client ip -> room creations
+    availability_limiter: RateLimiter,                  // client ip -> username availability checks
+    reconnect_limiter: RateLimiter,                     // client ip, and username, -> WebSocket upgrades
+    schedule_limiter: RateLimiter,                      // username -> scheduled messages
+    login_lockout: LockoutTracker,                      // username and client ip -> failed login attempts
+    jwt_keys: auth::KeyRing,
+    trusted_proxies: Vec<IpAddr>, // peers whose forwarding headers name the real client
+    allowed_origins: Option<Vec<String>>,               // shared by CORS and the WebSocket upgrade
+    max_history: usize,                                 // global cap on a room's history_size
+    admin_token: Option<String>,
+}
+
+// A registered account; only the password's argon2 hash is kept
+struct User {
+    password_hash: String,
+    roles: HashSet<Role>,
+    created_at: u64,                 // epoch ms
+    public_key: Option<VerifyingKey>, // verifies messages in rooms with require_signatures
+}
+
+// A question put to a room by a moderator. Each member of the room may pick one option and
+// change their pick until the poll is closed.
+#[derive(Clone)]
+struct Poll {
+    id: Uuid,
+    question: String,
+    options: Vec<String>,
+    created_by: String,
+    votes: HashMap<String, usize>, // username -> option index
+}
+
+// Sent whenever a poll opens, its tallies change, or it closes
+#[derive(Serialize, Clone)]
+struct PollResults {
+    poll_id: Uuid,
+    question: String,
+    options: Vec<String>,
+    tallies: Vec<usize>, // votes per option, in the same order
+    created_by: String,
+    closed: bool,
+}
+
+const MIN_POLL_OPTIONS: usize = 2;
+const MAX_POLL_OPTIONS: usize = 10;
+
+impl Poll {
+    fn results(&self, closed: bool) -> PollResults {
+        let mut tallies = vec![0; self.options.len()];
+        for &option in self.votes.values() {
+            tallies[option] += 1;
+        }
+        PollResults {
+            poll_id: self.id,
+            question: self.question.clone(),
+            options: self.options.clone(),
+            tallies,
+            created_by: self.created_by.clone(),
+            closed,
+        }
+    }
+}
+
+// Checks a new poll after its question and options have been trimmed
+fn validate_poll(question: &str, options: &[String]) -> Result<(), String> {
+    if question.is_empty() {
+        return Err("A poll needs a question".to_string());
+    }
+    check_len("question", question, MAX_POLL_QUESTION_LEN)?;
+    if !(MIN_POLL_OPTIONS..=MAX_POLL_OPTIONS).contains(&options.len()) {
+        return Err(format!("A poll needs {} to {} options", MIN_POLL_OPTIONS, MAX_POLL_OPTIONS));
+    }
+    for option in options {
+        if option.is_empty() {
+            return Err("Poll options can't be empty".to_string());
+        }
+        check_len("option", option, MAX_POLL_OPTION_LEN)?;
+    }
+    Ok(())
+}
+
+// A live WebSocket session registered in a room
+#[derive(Clone)]
+struct Connection {
+    username: String,
+    addr: Addr<WebSocketSession>,
+    send_failures: Arc<AtomicU32>, // consecutive full-mailbox sends, shared with the session
+}
+
+// One of a user's sessions, as listed by /me/sessions and /admin/connections
+#[derive(Clone)]
+struct UserSession {
+    addr: Addr<WebSocketSession>,
+    session_id: Uuid,
+    room_id: Uuid,
+    connected_at: u64,          // epoch ms
+    client_ip: Option<String>,  // None unless record_client_ips is on
+    user_agent: Option<String>, // as sent with the upgrade, cut to MAX_USER_AGENT_LEN
+}
+
+#[derive(Serialize)]
+struct SessionInfo {
+    session_id: Uuid,
+    room_id: Uuid,
+    connected_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_agent: Option<String>,
+}
+
+impl UserSession {
+    fn info(&self) -> SessionInfo {
+        SessionInfo {
+            session_id: self.session_id,
+            room_id: self.room_id,
+            connected_at: self.connected_at,
+            client_ip: self.client_ip.clone(),
+            user_agent: self.user_agent.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AdminConnection {
+    username: String,
+    #[serde(flatten)]
+    session: SessionInfo,
+}
+
+const SESSION_MAILBOX_CAPACITY: usize = 256;
+const MAX_SEND_FAILURES: u32 = 3;
+const TOO_SLOW_REASON: &str = r#"{"type":"too_slow"}"#;
+
+impl Connection {
+    // Queues an event without blocking. A session whose mailbox stays full is marked as lagging
+    // and closes itself, rather than silently missing events.
+    fn send(&self, event: &ServerEvent) {
+        match self.addr.try_send(event.clone()) {
+            Ok(()) => self.send_failures.store(0, Ordering::Relaxed),
+            Err(SendError::Full(_)) => {
+                let failures = self.send_failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures == MAX_SEND_FAILURES {
+                    log::warn!("Closing lagging session of {}: mailbox full", self.username);
+                    // Bypasses the capacity, so the session is woken up even if nothing else is sent
+                    self.addr.do_send(Disconnect {
+                        code: ws::CloseCode::Policy,
+                        reason: TOO_SLOW_REASON.to_string(),
+                    });
+                }
+            }
+            Err(SendError::Closed(_)) => {}
+        }
+    }
+}
+
+// Membership grant created by a room's creator and redeemed through /join
+struct Invite {
+    room_id: Uuid,
+    single_use: bool,
+    expires_at: u64, // epoch ms
+}
+
+// A message posted later by a timer task; cancelling removes it before the timer fires
+struct ScheduledMessage {
+    room_id: Uuid,
+    username: String,
+    text: String,
+    format: MessageFormat,
+    signature: Option<String>,
+}
+
+// A member flagging a message for the room's moderators
+#[derive(Serialize, Clone)]
+struct Report {
+    id: Uuid,
+    message_id: Uuid,
+    reporter: String,
+    reason: String,
+    created_at: u64, // epoch ms
+}
+
+// Last message delivered to a session, so a reconnecting client only gets what it missed
+struct ResumePoint {
+    room_id: Uuid,
+    username: String,
+    last_message_id: Option<Uuid>,
+    expires_at: Instant,
+}
+
+// Who a direct message went between; receipts are only accepted from its recipient
+struct DirectMessageRoute {
+    from: String,
+    to: String,
+}
+
+// A stored direct message, as returned by GET /me/dms
+#[derive(Serialize, Clone)]
+struct DirectMessage {
+    id: Uuid,
+    from: String,
+    to: String,
+    text: String,
+    sent_at: u64, // epoch ms
+}
+
+const MAX_DM_HISTORY: usize = 1000; // per conversation
+
+// Key of the conversation between two users, the same whoever sent the message
+fn dm_conversation(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_string(), b.to_string())
+    } else {
+        (b.to_string(), a.to_string())
+    }
+}
+
+const RESUME_WINDOW: Duration = Duration::from_secs(120);
+
+impl AppState {
+    fn new(config: &Config) -> Self {
+        AppState {
+            users: Mutex::new(seed_users(config)),
+            rooms: Mutex::new(seed_rooms(config)),
+            connections: Mutex::new(HashMap::new()),
+            messages: store::from_backend(config.store_backend),
+            resume_points: Mutex::new(HashMap::new()),
+            user_sessions: Mutex::new(HashMap::new()),
+            watchers: Mutex::new(HashMap::new()),
+            direct_messages: Mutex::new(HashMap::new()),
+            dm_history: Mutex::new(HashMap::new()),
+            metrics: metrics::Metrics::default(),
+            invites: Mutex::new(HashMap::new()),
+            scheduled: Mutex::new(HashMap::new()),
+            read_markers: Mutex::new(HashMap::new()),
+            ws_per_ip: Mutex::new(HashMap::new()),
+            reports: Mutex::new(HashMap::new()),
+            motd: Mutex::new(Motd::default()),
+            expiries: Mutex::new(BTreeSet::new()),
+            started_at: now_ms(),
+            started: Instant::now(),
+            max_ws_per_ip: config.max_ws_per_ip,
+            ws_send_buffer: config.ws_send_buffer,
+            max_rooms: config.max_rooms,
+            max_connections: config.max_connections,
+            new_member_cooldown: config.new_member_cooldown,
+            max_reactions_per_user: config.max_reactions_per_user,
+            reaction_cooldown: config.reaction_cooldown,
+            max_active_polls: config.max_active_polls,
+            transient_interval: config.transient_rate.map(|rate| Duration::from_secs_f64(1.0 / rate as f64)),
+            strict_events: config.strict_events,
+            record_client_ips: config.record_client_ips,
+            default_room: config.default_room,
+            edit_window_secs: config.edit_window_secs,
+            draining: AtomicBool::new(false),
+            sanitize_mode: config.sanitize_mode,
+            username_blocklist: config.username_blocklist.iter().cloned().collect(),
+            wordlist: wordlist::WordList::load(config.wordlist_path.clone()),
+            webhooks: webhook::Dispatcher::start(),
+            register_limiter: RateLimiter::new(5, 0.1),
+            create_room_limiter: RateLimiter::new(10, 0.5),
+            availability_limiter: RateLimiter::new(20, 1.0),
+            reconnect_limiter: RateLimiter::new(20, 1.0),
+            schedule_limiter: RateLimiter::new(MESSAGE_BURST, MESSAGE_REFILL_PER_SEC),
+            login_lockout: LockoutTracker::new(5, Duration::from_secs(15 * 60), Duration::from_secs(5 * 60)),
+            jwt_keys: auth::KeyRing::new(config.jwt_secret.clone(), config.key_rotation_grace),
+            allowed_origins: config.allowed_origins.clone(),
+            trusted_proxies: config.trusted_proxies.clone(),
+            max_history: config.max_history,
+            admin_token: config.admin_token.clone(),
+        }
+    }
+}
+
+// The initial user store: empty, or just the SEED_ADMIN_* account
+fn seed_users(config: &Config) -> HashMap<String, User> {
+    let mut users = HashMap::new();
+    let Some(seed) = &config.seed_admin else {
+        return users;
+    };
+    if let Err(err) = validate_username(&seed.username) {
+        log::error!("Not seeding admin {:?}: {}", seed.username, err);
+        return users;
+    }
+    match auth::hash_password(&seed.password) {
+        Ok(password_hash) => {
+            users.insert(
+                seed.username.clone(),
+                User {
+                    password_hash,
+                    roles: HashSet::from([Role::User, Role::Admin]),
+                    created_at: now_ms(),
+                    public_key: None,
+                },
+            );
+            log::info!("Seeded admin account {}", seed.username);
+        }
+        Err(err) => log::error!("Failed to hash the seed admin password: {}", err),
+    }
+    users
+}
+
+// Owner of rooms the server creates itself; nobody can register under this name
+const SYSTEM_USER: &str = "system";
+const DEFAULT_ROOM_NAME: &str = "general";
+
+// The initial rooms: empty, or just the DEFAULT_ROOM_ID room. It is persistent so the
+// idle-room collector never removes it.
+fn seed_rooms(config: &Config) -> HashMap<Uuid, Room> {
+    let mut rooms = HashMap::new();
+    if let Some(id) = config.default_room {
+        let room = Room {
+            id,
+            name: DEFAULT_ROOM_NAME.to_string(),
+            creator: SYSTEM_USER.to_string(),
+            users: HashMap::new(),
+            ephemeral: false,
+            topic: String::new(),
+            last_seq: 0,
+            banned: HashSet::new(),
+            history_size: config.max_history,
+            private: false,
+            suppress_duplicates: false,
+            last_messages: HashMap::new(),
+            persistent: true,
+            last_activity_ms: now_ms(),
+            webhook_url: None,
+            muted: HashMap::new(),
+            typing: HashMap::new(),
+            last_session_end_ms: None,
+            templates: HashMap::new(),
+            arrivals: HashMap::new(),
+            polls: Vec::new(),
+            created_at: now_ms(),
+            require_signatures: false,
+            edit_window_secs: config.edit_window_secs,
+            welcome_message: String::new(),
+            archived: false,
+            moderators: HashSet::new(),
+            last_message_at: None,
+            share_read_cursors: false,
+            message_ttl_secs: 0,
+            allowed_message_types: all_message_kinds(),
+        };
+        rooms.insert(id, room);
+        log::info!("Created default room {}", id);
+    }
+    rooms
+}
+
+#[derive(Debug, Deserialize)]
+struct RegisterRequest {
+    username: String,
+    password: String,
+    public_key: Option<String>, // for rooms that require signed messages
+}
+
+#[derive(Deserialize)]
+struct AvailabilityQuery {
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct CreateRoomRequest {
+    name: String,
+    #[serde(default)]
+    ephemeral: bool,
+    history_size: Option<usize>, // defaults to the global max
+    #[serde(default)]
+    private: bool,
+    #[serde(default)]
+    suppress_duplicates: bool,
+    #[serde(default)]
+    persistent: bool,
+    #[serde(default)]
+    require_signatures: bool,
+    edit_window_secs: Option<u64>, // defaults to EDIT_WINDOW_SECS
+    #[serde(default)]
+    join: bool, // also make the creator a member, in the same step
+    slug: Option<String>, // derives a stable room id instead of a random one, see `slug_room_id`
+}
+
+#[derive(Deserialize)]
+struct CreateRoomQuery {
+    #[serde(default)]
+    dry_run: bool, // validate only, don't create anything
+}
+
+#[derive(Deserialize)]
+struct ListRoomsQuery {
+    #[serde(default)]
+    include_archived: bool,
+}
+
+#[derive(Deserialize)]
+struct LeaderboardQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct LeaderboardEntry {
+    username: String,
+    messages: usize,
+}
+
+#[derive(Serialize)]
+struct ReactionCount {
+    emoji: String,
+    count: usize,
+}
+
+#[derive(Deserialize)]
+struct ActiveRoomsQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct AddUserRequest {
+    room_id: Uuid,
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct WebhookRequest {
+    url: String,
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    event: &'static str,
+    room_id: Uuid,
+    message: &'a ChatMessage,
+}
+
+#[derive(Deserialize)]
+struct BanRequest {
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct CreateInviteRequest {
+    #[serde(default = "default_single_use")]
+    single_use: bool,
+    ttl_secs: Option<u64>,
+}
+
+fn default_single_use() -> bool {
+    true
+}
+
+#[derive(Serialize)]
+struct InviteCreated {
+    invite_token: Uuid,
+    expires_at: u64,
+}
+
+#[derive(Deserialize)]
+struct ScheduleRequest {
+    text: String,
+    send_at: u64, // epoch ms
+    #[serde(default)]
+    format: MessageFormat,
+    signature: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReportRequest {
+    message_id: Uuid,
+    reason: String,
+}
+
+#[derive(Deserialize)]
+struct JoinQuery {
+    invite: Uuid,
+}
+
+#[derive(Deserialize)]
+struct MembersQuery {
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct MembersPage {
+    total: usize,
+    limit: usize,
+    offset: usize,
+    members: Vec<String>,
+}
+
+// Declared in sidebar order: the full member list sorts by role first
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum MemberRole {
+    Creator,
+    Moderator,
+    Member,
+}
+
+#[derive(Serialize)]
+struct MemberEntry {
+    username: String,
+    role: MemberRole,
+    online: bool, // has a session in this room
+}
+
+#[derive(Deserialize, Clone, Serialize)]
+struct ChatMessage {
+    id: Uuid,
+    room_id: Uuid,
+    seq: u64, // per-room, increases by one with every message
+    username: String,
+    message: String,
+    #[serde(default)]
+    deleted: bool,
+    #[serde(default)]
+    reactions: HashMap<String, HashSet<String>>, // emoji -> usernames
+    #[serde(default)]
+    pinned: bool,
+    #[serde(default)]
+    format: MessageFormat,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>, // lets other clients verify the sender themselves
+    #[serde(default)]
+    sent_at: u64, // epoch ms
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    edited_at: Option<u64>, // epoch ms of the latest edit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    forwarded_from: Option<ForwardedFrom>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attachment: Option<Attachment>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>, // epoch ms; removed from history and announced as expired then
+}
+
+// The original of a forwarded message
+#[derive(Serialize, Deserialize, Clone)]
+struct ForwardedFrom {
+    room_id: Uuid,
+    message_id: Uuid,
+    username: String, // who wrote the original
+}
+
+// How clients should render a message; the server only stores and echoes it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MessageFormat {
+    #[default]
+    Plain,
+    Markdown,
+}
+
+// A chat message as submitted, before a room accepts it
+#[derive(Deserialize)]
+struct Draft {
+    text: String,
+    #[serde(default)]
+    format: MessageFormat,
+    signature: Option<String>, // covers `text` only
+    attachment: Option<Attachment>,
+    ttl_secs: Option<u64>, // overrides the room's message_ttl_secs
+    #[serde(skip)]
+    forwarded_from: Option<ForwardedFrom>, // set by the server when forwarding
+}
+
+impl Draft {
+    fn plain(text: String) -> Self {
+        Draft {
+            text,
+            format: MessageFormat::Plain,
+            signature: None,
+            attachment: None,
+            ttl_secs: None,
+            forwarded_from: None,
+        }
+    }
+}
+
+// A small file sent inline with a message
+#[derive(Serialize, Deserialize, Clone)]
+struct Attachment {
+    mime: String,
+    data: String, // base64
+}
+
+const MAX_ATTACHMENT_BYTES: usize = 64 * 1024; // decoded
+const ALLOWED_ATTACHMENT_MIMES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+impl Attachment {
+    fn validate(&self) -> Result<(), String> {
+        if !ALLOWED_ATTACHMENT_MIMES.contains(&self.mime.as_str()) {
+            return Err(format!(
+                "Attachment type {} is not allowed (allowed: {})",
+                self.mime,
+                ALLOWED_ATTACHMENT_MIMES.join(", ")
+            ));
+        }
+        // Checked on the encoded length first so oversized data isn't decoded at all
+        if self.data.len() > MAX_ATTACHMENT_BYTES.div_ceil(3) * 4 {
+            return Err(format!("Attachments must be at most {} bytes", MAX_ATTACHMENT_BYTES));
+        }
+        let decoded = BASE64.decode(&self.data).map_err(|_| "Attachment data must be base64".to_string())?;
+        if decoded.len() > MAX_ATTACHMENT_BYTES {
+            return Err(format!("Attachments must be at most {} bytes", MAX_ATTACHMENT_BYTES));
+        }
+        Ok(())
+    }
+}
+
+// Events sent by clients over the WebSocket; anything else is treated as a plain chat message
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientEvent {
+    Message(Draft),
+    PurgeMine,
+    React { message_id: Uuid, emoji: String },
+    Unreact { message_id: Uuid, emoji: String },
+    SetTopic { text: String },
+    Status { text: String },
+    History { limit: Option<usize>, after_seq: Option<u64> },
+    SetBatching { interval_ms: Option<u64> }, // missing or 0 turns batching off
+    Dm { to: String, text: String },
+    Seen { message_id: Uuid },
+    ListRooms,
+    Pin { message_id: Uuid },
+    Unpin { message_id: Uuid },
+    GetMessage { message_id: Uuid },
+    Context { message_id: Uuid, before: Option<usize>, after: Option<usize> },
+    RateStatus,
+    Mute { username: String, duration_secs: u64 },
+    Unmute { username: String },
+    Typing,
+    WhoTyping,
+    ClearHistory,
+    Unread,
+    Watch { usernames: Vec<String> },
+    Leave,
+    RoomInfo,
+    Time,
+    Edit { message_id: Uuid, text: String, signature: Option<String> },
+    GetSettings,
+    UpdateSettings(SettingsUpdate),
+    Forward { message_id: Uuid, to_room: Uuid },
+    RoomSummary { room_id: Uuid },
+    SetMeta { color: Option<String> }, // null clears it
+    Sync,
+    Reactions { message_id: Uuid },
+    MarkRead { up_to_seq: u64 },
+    Pause,
+    Resume,
+    Subscribe { events: Option<Vec<String>> }, // null or missing: every event type again
+    Template { name: String },
+    Hello { supports: Vec<String> },
+    Poll { question: String, options: Vec<String> },
+    Vote { poll_id: Uuid, option: usize },
+    ClosePoll { poll_id: Uuid },
+    #[serde(other)]
+    Unknown,
+}
+
+// Codes carried by WebSocket warning frames
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum WarningCode {
+    ApproachingLimit, // few message tokens left; `remaining` more go through right away
+}
+
+// Stable machine-readable codes carried by WebSocket error frames
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum WsErrorCode {
+    InvalidUtf8,
+    BadJson,
+    InvalidEvent,
+    MessageNotFound,
+    EphemeralRoom,
+    Forbidden,
+    TooLong,
+    PinLimit,
+    RateLimited,
+    NameTaken,
+    UnsupportedVersion,
+    InvalidSignature,
+    UnknownEvent,
+    EditWindowExpired,
+    InvalidCharacters,
+    InvalidAttachment,
+    RoomNotFound,
+    UserNotFound,
+    RoomArchived,
+    BlockedWord,
+    TemplateNotFound,
+    ReactionLimited,
+    PollNotFound,
+    PollLimit,
+    TypeNotAllowed,
+}
+
+// Events pushed by the server to WebSocket clients
+#[derive(Serialize, Message, Clone)]
+#[rtype(result = "()")]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerEvent {
+    Connected { room: Box<Room>, resume_token: Uuid, session_id: Uuid },
+    Session { session_id: Uuid, room_id: Uuid, username: String }, // always the first frame
+    Message(ChatMessage),
+    Deleted { message_id: Uuid },
+    Expired { message_id: Uuid }, // gone from history; clients should drop their copy
+    Purged { count: usize },
+    ReactionAdded { message_id: Uuid, username: String, emoji: String },
+    ReactionRemoved { message_id: Uuid, username: String, emoji: String },
+    Error { code: WsErrorCode, message: String },
+    Warning { code: WarningCode, remaining: u32 }, // the request went through, but slow down
+    Presence {
+        username: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status: Option<PresenceStatus>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status_text: Option<String>,
+    },
+    Topic { text: String },
+    History {
+        messages: Vec<ChatMessage>,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        more: bool, // a paced replay has further chunks coming
+    },
+    Batch { events: Vec<ServerEvent> },
+    Dm { id: Uuid, from: String, to: String, text: String },
+    Delivered { message_id: Uuid },
+    Seen { message_id: Uuid, by: String },
+    Rooms { rooms: Vec<Room> },
+    DuplicateSuppressed,
+    Pinned { message_id: Uuid },
+    Unpinned { message_id: Uuid },
+    MessageDetail { message: ChatMessage },
+    Context { message_id: Uuid, messages: Vec<ChatMessage> }, // oldest first, the target included
+    RateStatus { remaining: u32, reset_in_ms: u64 },
+    Muted,
+    MuteUpdated { username: String, muted: bool },
+    Typing { username: String },
+    WhoTyping { usernames: Vec<String> },
+    HistoryCleared,
+    Unread { counts: HashMap<Uuid, usize> },
+    Left { username: String },
+    RoomInfo { room: Box<Room> },
+    Time { now: String, epoch_ms: u64 }, // `now` is RFC 3339 in UTC
+    Settings(RoomSettings),
+    Forwarded { message_id: Uuid, to_room: Uuid },
+    ShuttingDown { grace_ms: u64 }, // the server closes the connection after this long
+    Welcome { text: String },
+    RoomSummary(RoomSummary),
+    Meta { username: String, color: Option<String> },
+    Sync(SyncState),
+    MarkedRead { read_seq: u64, unread: usize }, // the room's read marker after the update
+    Read { username: String, up_to_seq: u64 },   // another member's read marker moved
+    Reactions {
+        message_id: Uuid,
+        summary: HashMap<String, usize>, // emoji -> how many users reacted with it
+        mine: Vec<String>,               // sorted emojis the requesting user reacted with
+    },
+    Edited {
+        message_id: Uuid,
+        text: String,
+        edited_at: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        signature: Option<String>,
+    },
+    Missed { count: usize }, // chat messages that arrived while the session was paused
+    Cooldown { retry_after: u64 }, // seconds until a newcomer to the room may post
+    Subscribed { events: Option<Vec<String>> }, // sorted; None when everything is delivered
+    HelloAck { enabled: Vec<String> }, // the capabilities of the hello the server supports
+    Poll(PollResults),
+    // Injected verbatim through /admin/debug/broadcast: a JSON object carrying its own "type".
+    // serde requires untagged variants to come last.
+    #[serde(untagged)]
+    Debug(serde_json::Map<String, serde_json::Value>),
+}
+
+// Version of the JSON protocol, sent as "v" in every frame. Clients may send it too;
+// events without one are taken to be the current version.
+const PROTOCOL_VERSION: u64 = 1;
+
+// Outbound envelope: the event's own fields plus the protocol version
+#[derive(Serialize)]
+struct Versioned<'a> {
+    v: u64,
+    #[serde(flatten)]
+    event: &'a ServerEvent,
+}
+
+impl<'a> Versioned<'a> {
+    fn new(event: &'a ServerEvent) -> Self {
+        Versioned {
+            v: PROTOCOL_VERSION,
+            event,
+        }
+    }
+}
+
+// Reads only the "v" field of an inbound event
+#[derive(Deserialize)]
+struct VersionProbe {
+    v: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum PresenceStatus {
+    Online,
+    Offline,
+    Unknown, // neither connected nor registered; only reported by /presence/batch
+}
+
+fn unsigned_rejected() -> ServerEvent {
+    ServerEvent::Error {
+        code: WsErrorCode::InvalidSignature,
+        message: "This room only accepts signed messages".to_string(),
+    }
+}
+
+// Trims and length-checks message text. Signed text is left as it is: the signature covers
+// the exact bytes the client sent.
+fn prepare_text(text: &mut String, signed: bool) -> Result<(), String> {
+    if !signed {
+        limits::trim(text);
+    }
+    check_len("text", text, MAX_MESSAGE_LEN)
+}
+
+impl AppState {
+    // Runs `f` on a stored, non-deleted message of the room; None if there is no such message
+    fn with_message<R>(
+        &self,
+        room_id: Uuid,
+        message_id: Uuid,
+        f: impl FnOnce(&mut ChatMessage) -> R,
+    ) -> Option<R> {
+        let mut f = Some(f);
+        let mut result = None;
+        self.messages.edit(room_id, message_id, &mut |message| {
+            result = f.take().map(|f| f(message));
+        });
+        result
+    }
+
+    // Messages stored after `last_message_id`; falls back to recent history if it is unknown or trimmed
+    fn history_since(&self, room_id: Uuid, last_message_id: Option<Uuid>) -> Vec<ChatMessage> {
+        let history = self.messages.recent(room_id, MAX_HISTORY_LIMIT);
+        let start = last_message_id
+            .and_then(|id| history.iter().position(|message| message.id == id))
+            .map_or(0, |index| index + 1);
+        history.into_iter().skip(start).collect()
+    }
+
+    fn latest_message_id(&self, room_id: Uuid) -> Option<Uuid> {
+        self.messages.recent(room_id, 1).pop().map(|message| message.id)
+    }
+
+    // Records where a session left off when it ends; the token can be used for RESUME_WINDOW
+    fn save_resume_point(&self, token: Uuid, room_id: Uuid, username: &str, last_message_id: Option<Uuid>) {
+        self.resume_points.lock().unwrap().insert(
+            token,
+            ResumePoint {
+                room_id,
+                username: username.to_string(),
+                last_message_id,
+                expires_at: Instant::now() + RESUME_WINDOW,
+            },
+        );
+    }
+
+    // Drops resume points nobody came back for; run periodically rather than on every save
+    fn sweep_resume_points(&self) {
+        let now = Instant::now();
+        self.resume_points.lock().unwrap().retain(|_, point| point.expires_at > now);
+    }
+
+    // Consumes a resume token; it must be unexpired and issued for the same room and user
+    fn take_resume_point(&self, token: Uuid, room_id: Uuid, username: &str) -> Option<ResumePoint> {
+        let mut resume_points = self.resume_points.lock().unwrap();
+        let point = resume_points.remove(&token)?;
+        (point.expires_at > Instant::now() && point.room_id == room_id && point.username == username)
+            .then_some(point)
+    }
+
+    // Key used by the per-client rate limiters and connection caps. Behind a trusted proxy the
+    // client is the nearest forwarded address that isn't another trusted proxy; anyone else's
+    // forwarding headers are ignored, since they could name any address.
+    fn client_key(&self, http_req: &HttpRequest) -> String {
+        let Some(peer) = http_req.peer_addr().map(|addr| addr.ip()) else {
+            return "unknown".to_string();
+        };
+        if !self.trusted_proxies.contains(&peer) {
+            return peer.to_string();
+        }
+        let chain = forwarded_chain(http_req);
+        chain
+            .iter()
+            .rev()
+            .find(|ip| !self.trusted_proxies.contains(ip))
+            .or(chain.first())
+            .unwrap_or(&peer)
+            .to_string()
+    }
+
+    // Requests without an Origin header don't come from a browser page, so CORS doesn't apply to them
+    fn origin_allowed(&self, origin: Option<&str>) -> bool {
+        match (&self.allowed_origins, origin) {
+            (Some(allowed), Some(origin)) => allowed.iter().any(|allowed| allowed == origin),
+            _ => true,
+        }
+    }
+
+    fn is_ephemeral(&self, room_id: Uuid) -> bool {
+        let rooms = self.rooms.lock().unwrap();
+        rooms.get(&room_id).is_some_and(|room| room.ephemeral)
+    }
+
+    fn is_archived(&self, room_id: Uuid) -> bool {
+        let rooms = self.rooms.lock().unwrap();
+        rooms.get(&room_id).is_some_and(|room| room.archived)
+    }
+
+    // The user's first session anywhere brings them online for their watchers
+    fn register_session(&self, username: &str, session: UserSession) {
+        let mut user_sessions = self.user_sessions.lock().unwrap();
+        let sessions = user_sessions.entry(username.to_string()).or_default();
+        sessions.push(session);
+        if sessions.len() == 1 {
+            self.notify_watchers(username, PresenceStatus::Online);
+        }
+    }
+
+    fn unregister_session(&self, username: &str, addr: &Addr<WebSocketSession>) {
+        let mut user_sessions = self.user_sessions.lock().unwrap();
+        if let Some(sessions) = user_sessions.get_mut(username) {
+            sessions.retain(|session| session.addr != *addr);
+            if sessions.is_empty() {
+                user_sessions.remove(username);
+                self.notify_watchers(username, PresenceStatus::Offline);
+            }
+        }
+    }
+
+    // Called with `user_sessions` held, so watchers see online/offline transitions in order
+    fn notify_watchers(&self, username: &str, status: PresenceStatus) {
+        let watchers = self.watchers.lock().unwrap();
+        let event = ServerEvent::Presence {
+            username: username.to_string(),
+            status: Some(status),
+            status_text: None,
+        };
+        for watcher in watchers.get(username).into_iter().flatten() {
+            watcher.do_send(event.clone());
+        }
+    }
+
+    fn is_online(&self, username: &str) -> bool {
+        self.user_sessions.lock().unwrap().contains_key(username)
+    }
+
+    // Guests (sessions without a token) can't use an account's name or share a name with another
+    // session in the room; an account may be connected from several tabs
+    fn guest_name_taken(&self, room_id: Uuid, username: &str) -> bool {
+        if self.users.lock().unwrap().contains_key(username) {
+            return true;
+        }
+        let connections = self.connections.lock().unwrap();
+        connections
+            .get(&room_id)
+            .is_some_and(|users| users.iter().any(|user| user.username == username))
+    }
+
+    // Reserves a WebSocket slot for the address, unless it already has max_ws_per_ip open or
+    // the whole server has max_connections
+    fn acquire_ws_slot(&self, ip: &str) -> Result<(), SlotRefused> {
+        let mut ws_per_ip = self.ws_per_ip.lock().unwrap();
+        if self.max_connections.is_some_and(|max| ws_per_ip.values().sum::<usize>() >= max) {
+            return Err(SlotRefused::ServerFull);
+        }
+        let count = ws_per_ip.entry(ip.to_string()).or_default();
+        if *count >= self.max_ws_per_ip {
+            return Err(SlotRefused::PerAddress);
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    // Reserved WebSocket slots, i.e. open sessions plus handshakes in progress
+    fn ws_connection_count(&self) -> usize {
+        self.ws_per_ip.lock().unwrap().values().sum()
+    }
+
+    fn release_ws_slot(&self, ip: &str) {
+        let mut ws_per_ip = self.ws_per_ip.lock().unwrap();
+        if let Some(count) = ws_per_ip.get_mut(ip) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                ws_per_ip.remove(ip);
+            }
+        }
+    }
+
+    // Sends an event to all of a user's sessions, whatever room they are in; returns how many got it
+    fn send_to_user(&self, username: &str, event: &ServerEvent) -> usize {
+        let user_sessions = self.user_sessions.lock().unwrap();
+        let sessions = user_sessions.get(username).map(Vec::as_slice).unwrap_or_default();
+        for session in sessions {
+            session.addr.do_send(event.clone());
+        }
+        sessions.len()
+    }
+
+    // Closes every session the user has open in the room; returns how many were closed
+    fn disconnect_in_room(&self, room_id: Uuid, username: &str, reason: &str) -> usize {
+        let connections = self.connections.lock().unwrap();
+        let Some(users) = connections.get(&room_id) else {
+            return 0;
+        };
+        let sessions: Vec<_> = users.iter().filter(|user| user.username == username).collect();
+        for session in &sessions {
+            session.addr.do_send(Disconnect {
+                code: ws::CloseCode::Policy,
+                reason: reason.to_string(),
+            });
+        }
+        sessions.len()
+    }
+
+    // None if the room doesn't exist or `username` may not see it
+    fn room_summary(&self, room_id: Uuid, username: &str) -> Option<RoomSummary> {
+        let member_count = {
+            let rooms = self.rooms.lock().unwrap();
+            let room = rooms.get(&room_id).filter(|room| room.visible_to(username))?;
+            room.users.len()
+        };
+        let online_count = self
+            .connections
+            .lock()
+            .unwrap()
+            .get(&room_id)
+            .map_or(0, |users| users.iter().map(|user| &user.username).collect::<HashSet<_>>().len());
+        Some(RoomSummary {
+            room_id,
+            member_count,
+            online_count,
+            message_count: self.messages.count(room_id),
+            last_message_at: self.messages.recent(room_id, 1).pop().map(|message| message.sent_at),
+        })
+    }
+
+    // None if the room is gone. Locks are taken one after another, so the parts may be a few
+    // messages apart under load; the client catches up through live events.
+    fn sync_state(&self, room_id: Uuid, username: &str) -> Option<SyncState> {
+        let (member_count, members, last_seq, settings) = {
+            let rooms = self.rooms.lock().unwrap();
+            let room = rooms.get(&room_id)?;
+            let mut members: Vec<String> = room.users.keys().cloned().collect();
+            members.sort();
+            members.truncate(MAX_SYNC_MEMBERS);
+            (room.users.len(), members, room.last_seq, room.settings())
+        };
+        let mut online: Vec<String> = self
+            .connections
+            .lock()
+            .unwrap()
+            .get(&room_id)
+            .map(|users| users.iter().map(|user| user.username.clone()).collect::<HashSet<_>>())
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        online.sort();
+        Some(SyncState {
+            room_id,
+            member_count,
+            members,
+            online,
+            last_seq,
+            settings,
+            unread: self.unread_count(room_id, username),
+        })
+    }
+
+    // validate_username plus the USERNAME_BLOCKLIST, which is matched case-insensitively
+    fn check_username(&self, username: &str) -> Result<(), ApiError> {
+        validate_username(username)?;
+        if self.username_blocklist.contains(&username.to_lowercase()) {
+            return Err(ApiError::bad_request("This username is not allowed"));
+        }
+        Ok(())
+    }
+
+    // Closes every session the user has open, in any room; returns how many were closed
+    fn disconnect_everywhere(&self, username: &str, reason: &str) -> usize {
+        let user_sessions = self.user_sessions.lock().unwrap();
+        let sessions = user_sessions.get(username).map_or(&[][..], Vec::as_slice);
+        for session in sessions {
+            session.addr.do_send(Disconnect {
+                code: ws::CloseCode::Policy,
+                reason: reason.to_string(),
+            });
+        }
+        sessions.len()
+    }
+
+    // Closes one of the user's sessions; false if they have no session with that id
+    fn disconnect_session(&self, username: &str, session_id: Uuid, reason: &str) -> bool {
+        let user_sessions = self.user_sessions.lock().unwrap();
+        let Some(session) = user_sessions
+            .get(username)
+            .and_then(|sessions| sessions.iter().find(|session| session.session_id == session_id))
+        else {
+            return false;
+        };
+        session.addr.do_send(Disconnect {
+            code: ws::CloseCode::Policy,
+            reason: reason.to_string(),
+        });
+        true
+    }
+
+    // Removes rooms with no members, no connections and no activity within `ttl`, unless persistent.
+    // Nobody is connected to a collected room, so nothing needs to be broadcast.
+    fn collect_idle_rooms(&self, ttl: Duration) -> Vec<Uuid> {
+        let mut connections = self.connections.lock().unwrap();
+        let mut rooms = self.rooms.lock().unwrap();
+
+        let cutoff = now_ms().saturating_sub(ttl.as_millis() as u64);
+        let idle: Vec<Uuid> = rooms
+            .values()
+            .filter(|room| {
+                !room.persistent
+                    && !room.archived
+                    && room.users.is_empty()
+                    && connections.get(&room.id).is_none_or(Vec::is_empty)
+                    && room.last_activity_ms < cutoff
+            })
+            .map(|room| room.id)
+            .collect();
+
+        for room_id in &idle {
+            rooms.remove(room_id);
+            self.messages.clear(*room_id);
+            connections.remove(room_id);
+            self.webhooks.reset(*room_id);
+        }
+        if !idle.is_empty() {
+            self.reports.lock().unwrap().retain(|room_id, _| !idle.contains(room_id));
+            let mut read_markers = self.read_markers.lock().unwrap();
+            for markers in read_markers.values_mut() {
+                markers.retain(|room_id, _| !idle.contains(room_id));
+            }
+        }
+        idle
+    }
+
+    // Removes control characters (other than newlines and tabs) or refuses the text, per
+    // SANITIZE_MODE. Stripping would break a signature, so signed text is always refused.
+    fn sanitize(&self, text: String, signed: bool) -> Result<String, Box<ServerEvent>> {
+        let disallowed = |c: char| c.is_control() && !matches!(c, '\n' | '\r' | '\t');
+        if !text.contains(disallowed) {
+            return Ok(text);
+        }
+        if signed || self.sanitize_mode == SanitizeMode::Reject {
+            return Err(Box::new(ServerEvent::Error {
+                code: WsErrorCode::InvalidCharacters,
+                message: "Message text must not contain control characters".to_string(),
+            }));
+        }
+        Ok(text.chars().filter(|&c| !disallowed(c)).collect())
+    }
+
+    // Refuses text containing a word from WORDLIST_PATH. Nothing is masked, since that would
+    // break signatures.
+    fn check_wordlist(&self, text: &str) -> Result<(), Box<ServerEvent>> {
+        match self.wordlist.find_in(text) {
+            Some(word) => Err(Box::new(ServerEvent::Error {
+                code: WsErrorCode::BlockedWord,
+                message: format!("The message contains a blocked word: {}", word),
+            })),
+            None => Ok(()),
+        }
+    }
+
+    // A signature is optional outside signed rooms, but a wrong one is never accepted.
+    // Returns whether the text was signed.
+    fn check_signature(&self, username: &str, text: &str, signature: Option<&str>) -> Result<bool, Box<ServerEvent>> {
+        match signature {
+            Some(signature) if !self.signature_valid(username, text, signature) => Err(Box::new(ServerEvent::Error {
+                code: WsErrorCode::InvalidSignature,
+                message: "The message signature is invalid".to_string(),
+            })),
+            Some(_) => Ok(true),
+            None => Ok(false),
+        }
+    }
+
+    // Whether `signature` is the user's registered key signing `text`; false without a key
+    fn signature_valid(&self, username: &str, text: &str, signature: &str) -> bool {
+        let public_key = self.users.lock().unwrap().get(username).and_then(|user| user.public_key);
+        public_key.is_some_and(|key| auth::verify_signature(&key, text, signature))
+    }
+
+    // The path every chat message takes: room rules, broadcast, webhook and history.
+    // On rejection returns the event meant for the sender.
+    //
+    // `connections` is held from assigning the sequence number until the message is stored, so
+    // concurrent senders are serialized here: every recipient's mailbox, and the history, get
+    // messages in sequence order, and all recipients observe the same order.
+    fn post_message(
+        &self,
+        room_id: Uuid,
+        username: &str,
+        draft: Draft,
+    ) -> Result<(), Box<ServerEvent>> {
+        let Draft {
+            mut text,
+            format,
+            signature,
+            attachment,
+            ttl_secs,
+            forwarded_from,
+        } = draft;
+        if ttl_secs.is_some_and(|secs| !(1..=MAX_MESSAGE_TTL_SECS).contains(&secs)) {
+            return Err(Box::new(ServerEvent::Error {
+                code: WsErrorCode::InvalidEvent,
+                message: format!("ttl_secs must be between 1 and {}", MAX_MESSAGE_TTL_SECS),
+            }));
+        }
+        if let Some(Err(message)) = attachment.as_ref().map(Attachment::validate) {
+            return Err(Box::new(ServerEvent::Error {
+                code: WsErrorCode::InvalidAttachment,
+                message,
+            }));
+        }
+        if let Err(message) = prepare_text(&mut text, signature.is_some()) {
+            return Err(Box::new(ServerEvent::Error {
+                code: WsErrorCode::TooLong,
+                message,
+            }));
+        }
+        let text = self.sanitize(text, signature.is_some())?;
+        self.check_wordlist(&text)?;
+        let signed = self.check_signature(username, &text, signature.as_deref())?;
+
+        let connections = self.connections.lock().unwrap();
+        let accepted = {
+            let mut rooms = self.rooms.lock().unwrap();
+            let Some(room) = rooms.get_mut(&room_id) else {
+                return Ok(()); // the room is gone, there is nobody to deliver to
+            };
+            if room.require_signatures && !signed {
+                return Err(Box::new(unsigned_rejected()));
+            }
+            let kind = if attachment.is_some() { MessageKind::File } else { MessageKind::Message };
+            room.accept_message(username, &text, kind, self.new_member_cooldown)?
+        };
+        // Posting implies having read everything before it
+        self.mark_read(room_id, username, accepted.seq);
+
+        let sent_at = now_ms();
+        let ttl_secs = ttl_secs.or((accepted.message_ttl_secs > 0).then_some(accepted.message_ttl_secs));
+        let chat_message = ChatMessage {
+            id: Uuid::new_v4(),
+            room_id,
+            seq: accepted.seq,
+            username: username.to_string(),
+            message: text,
+            deleted: false,
+            reactions: HashMap::new(),
+            pinned: false,
+            format,
+            signature,
+            sent_at,
+            edited_at: None,
+            forwarded_from,
+            attachment,
+            expires_at: ttl_secs.map(|secs| sent_at + secs * 1000),
+        };
+
+        // Broadcast the message to all users in the room
+        let event = ServerEvent::Message(chat_message.clone());
+        for user in self.recipients(&connections, room_id) {
+            user.send(&event);
+        }
+
+        if let Some(url) = accepted.webhook_url {
+            let payload = WebhookPayload {
+                event: "message",
+                room_id,
+                message: &chat_message,
+            };
+            self.webhooks.enqueue(room_id, url, &payload);
+        }
+
+        // Ephemeral rooms keep no history, but their clients still drop expired copies
+        if let Some(expires_at) = chat_message.expires_at {
+            self.schedule_expiry(expires_at, room_id, chat_message.id);
+        }
+
+        // Add the message to the room's history
+        if !accepted.ephemeral {
+            self.messages.store(chat_message, accepted.history_size);
+        }
+        Ok(())
+    }
+
+    fn schedule_expiry(&self, expires_at: u64, room_id: Uuid, message_id: Uuid) {
+        self.expiries.lock().unwrap().insert((expires_at, room_id, message_id));
+    }
+
+    // Removes messages whose expiry has passed and tells their rooms; returns how many expired
+    fn expire_messages(&self) -> usize {
+        let due: Vec<(u64, Uuid, Uuid)> = {
+            let mut expiries = self.expiries.lock().unwrap();
+            let now = now_ms();
+            let later = expiries.split_off(&(now + 1, Uuid::nil(), Uuid::nil()));
+            std::mem::replace(&mut *expiries, later).into_iter().collect()
+        };
+        for &(_, room_id, message_id) in &due {
+            self.messages.remove(room_id, message_id);
+            self.broadcast(room_id, &ServerEvent::Expired { message_id });
+        }
+        due.len()
+    }
+
+    // Moves the user's read marker in the room forward to `seq`; it never moves back.
+    // Returns whether it moved.
+    fn mark_read(&self, room_id: Uuid, username: &str, seq: u64) -> bool {
+        let mut read_markers = self.read_markers.lock().unwrap();
+        let marker = read_markers
+            .entry(username.to_string())
+            .or_default()
+            .entry(room_id)
+            .or_default();
+        let moved = seq > *marker;
+        *marker = (*marker).max(seq);
+        moved
+    }
+
+    // Stored messages from others after the user's read marker, for every room they belong to
+    fn unread_counts(&self, username: &str) -> HashMap<Uuid, usize> {
+        let room_ids: Vec<Uuid> = {
+            let rooms = self.rooms.lock().unwrap();
+            rooms
+                .values()
+                .filter(|room| room.is_member(username))
+                .map(|room| room.id)
+                .collect()
+        };
+        room_ids
+            .into_iter()
+            .map(|room_id| (room_id, self.unread_count(room_id, username)))
+            .collect()
+    }
+
+    fn read_marker(&self, room_id: Uuid, username: &str) -> u64 {
+        self.read_markers
+            .lock()
+            .unwrap()
+            .get(username)
+            .and_then(|markers| markers.get(&room_id).copied())
+            .unwrap_or(0)
+    }
+
+    // Stored messages from others in the room after the user's read marker
+    fn unread_count(&self, room_id: Uuid, username: &str) -> usize {
+        let read = self.read_marker(room_id, username);
+        self.messages
+            .recent(room_id, usize::MAX)
+            .iter()
+            .filter(|message| message.seq > read && !message.deleted && message.username != username)
+            .count()
+    }
+
+    // Empties the room's history and tells connected clients to wipe their view of it
+    fn clear_history(&self, room_id: Uuid) {
+        self.messages.clear(room_id);
+        self.broadcast(room_id, &ServerEvent::HistoryCleared);
+    }
+
+    // Graceful shutdown: refuses new WebSocket sessions, warns the open ones, gives their clients
+    // up to `grace` to leave and then closes whatever is left
+    async fn drain(&self, grace: Duration) {
+        self.draining.store(true, Ordering::Relaxed);
+        let event = ServerEvent::ShuttingDown {
+            grace_ms: grace.as_millis() as u64,
+        };
+        let open = self.open_sessions();
+        log::info!("Draining {} WebSocket sessions", open.len());
+        for session in &open {
+            session.send(&event);
+        }
+
+        let deadline = Instant::now() + grace;
+        while Instant::now() < deadline && !self.open_sessions().is_empty() {
+            actix_web::rt::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+
+        let remaining = self.open_sessions();
+        if !remaining.is_empty() {
+            log::info!("Closing {} WebSocket sessions still open after the grace period", remaining.len());
+        }
+        for session in remaining {
+            session.addr.do_send(Disconnect {
+                code: ws::CloseCode::Away,
+                reason: "Server shutting down".to_string(),
+            });
+        }
+    }
+
+    fn open_sessions(&self) -> Vec<Connection> {
+        self.connections.lock().unwrap().values().flatten().cloned().collect()
+    }
+
+    // Sends an event to every WebSocket connected to the room, see `recipients`
+    fn broadcast(&self, room_id: Uuid, event: &ServerEvent) {
+        let connections = self.connections.lock().unwrap();
+        for user in self.recipients(&connections, room_id) {
+            user.send(event);
+        }
+    }
+
+    // For echoes of a user's own activity (typing, reactions, presence): none of their
+    // sessions get it, not just the one it came from
+    fn broadcast_to_others(&self, room_id: Uuid, username: &str, event: &ServerEvent) {
+        let connections = self.connections.lock().unwrap();
+        for user in self.recipients(&connections, room_id) {
+            if user.username != username {
+                user.send(event);
+            }
+        }
+    }
+
+    // The room's sessions that may still receive its events. Being connected isn't enough:
+    // once a private room no longer counts a user as a member, for instance because it was
+    // made private after they connected, their sessions get nothing more from it. Takes the
+    // rooms lock, so the caller holds `connections` already.
+    fn recipients<'a>(&self, connections: &'a HashMap<Uuid, Vec<Connection>>, room_id: Uuid) -> Vec<&'a Connection> {
+        let Some(users) = connections.get(&room_id) else {
+            return Vec::new();
+        };
+        let rooms = self.rooms.lock().unwrap();
+        let Some(room) = rooms.get(&room_id) else {
+            return users.iter().collect();
+        };
+        users.iter().filter(|user| room.visible_to(&user.username)).collect()
+    }
+}
+
+enum SlotRefused {
+    PerAddress,
+    ServerFull,
+}
+
+// Asks a session to close itself, e.g. after its user was banned from the room
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Disconnect {
+    code: ws::CloseCode,
+    reason: String,
+}
+
+// WebSocket Session
+struct WebSocketSession {
+    room_id: Uuid,
+    username: String,
+    authenticated: bool, // connected with a token; otherwise a guest
+    app_state: Arc<AppState>,
+    status_text: Option<String>, // custom status, cleared when the session ends
+    color: Option<String>,       // display color, "#rrggbb"; like the status it ends with the session
+    replay_history: bool,        // send recent history on connect (?history=false disables)
+    session_id: Uuid,            // identifies this connection to the client and in logs
+    resume_token: Uuid,          // handed to the client to resume after a reconnect
+    resume_from: Option<Uuid>,   // token the client connected with (?resume=...)
+    last_delivered: Option<Uuid>, // latest message sent to the client, where a resume picks up
+    batching: Option<Batching>,  // coalesce broadcast events into periodic batch frames
+    message_bucket: TokenBucket, // limits how fast this session can post chat messages
+    send_failures: Arc<AtomicU32>, // see Connection::send
+    client_ip: String,             // holds one of the address's max_ws_per_ip slots
+    user_agent: Option<String>,    // see UserSession::user_agent
+    watching: HashSet<String>,     // users whose presence this session follows across rooms
+    strict: bool,                  // unknown event types get an error instead of being sent as chat
+    replay: Option<Replay>,        // history replay still in progress
+    connected_at: Instant,
+    close_reason: Option<String>,  // why the server closed the session; None if the client left
+    paused: Option<Paused>,        // away mode: chat messages are held back until resume
+    throttle: Throttle,            // typing/presence frames over the transient_interval rate
+    subscribed: Option<HashSet<String>>, // live event types the client wants; None for all
+    fragments: Option<Fragments>,  // a message arriving as continuation frames
+    last_reaction: Option<Instant>, // latest reaction added or removed, for the reaction cooldown
+    heard_from: bool,               // the client has sent a frame; only the first may be a hello
+    withheld: HashSet<&'static str>, // live event types of capabilities the hello left out
+}
+
+struct Replay {
+    remaining: VecDeque<ChatMessage>,
+    deferred: Vec<ServerEvent>, // live events that arrived during the replay, in order
+}
+
+struct Fragments {
+    buffer: web::BytesMut,
+    binary: bool,   // binary messages are ignored, like unfragmented ones
+    too_long: bool, // past MAX_WS_FRAME_SIZE; the rest is discarded
+}
+
+impl Fragments {
+    fn new(binary: bool) -> Self {
+        Fragments {
+            buffer: web::BytesMut::new(),
+            binary,
+            too_long: false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Paused {
+    held: Vec<ServerEvent>, // delivered on resume, unless more than MAX_HELD_MESSAGES arrived
+    missed: usize,
+}
+
+// Typing and presence frames waiting out AppState::transient_interval. Only the latest frame
+// per kind and user is kept, in the order they first arrived.
+#[derive(Default)]
+struct Throttle {
+    last_sent: Option<Instant>,
+    pending: Vec<ServerEvent>,
+    flush: Option<SpawnHandle>,
+}
+
+// What makes two transient frames interchangeable; None for everything else
+fn transient_key(event: &ServerEvent) -> Option<(&'static str, &str)> {
+    match event {
+        ServerEvent::Typing { username } => Some(("typing", username)),
+        ServerEvent::Presence { username, .. } => Some(("presence", username)),
+        _ => None,
+    }
+}
+
+struct Batching {
+    timer: SpawnHandle,
+    pending: Vec<ServerEvent>,
+}
+
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+const MAX_HISTORY_LIMIT: usize = 200;
+const DEFAULT_CONTEXT_WINDOW: usize = 10; // messages on each side of a context target
+const MAX_CONTEXT_WINDOW: usize = 50;
+const MAX_PINS_PER_ROOM: usize = 10;
+const MIN_BATCH_INTERVAL_MS: u64 = 10;
+const MAX_BATCH_INTERVAL_MS: u64 = 10_000;
+const MESSAGE_BURST: u32 = 10; // chat messages a session may send back to back
+const MESSAGE_REFILL_PER_SEC: f64 = 2.0;
+const MAX_MUTE_SECS: u64 = 7 * 24 * 60 * 60;
+const MAX_WATCHED_USERS: usize = 100;
+const REPLAY_CHUNK_SIZE: usize = 50;
+const MAX_HELD_MESSAGES: usize = 100; // past this a paused session only counts what it misses
+const MAX_SUBSCRIBED_TYPES: usize = 64;
+// Delivered whatever the session subscribed to, so clients can't miss being told to go away
+const ALWAYS_DELIVERED: &[&str] = &["error", "shutting_down"];
+// What a hello can ask for, with the live event types only sent to clients that claim it.
+// Sessions without a hello get all of them. Anything else, compression included, is ignored.
+const CAPABILITIES: &[(&str, &[&str])] = &[
+    ("batch", &[]), // live events arrive in batch frames, every HELLO_BATCH_INTERVAL_MS
+    ("reactions", &["reaction_added", "reaction_removed"]),
+    ("polls", &["poll"]),
+];
+const HELLO_BATCH_INTERVAL_MS: u64 = 100;
+// Room for a message with a full-size base64 attachment
+const MAX_WS_FRAME_SIZE: usize = 128 * 1024;
+// The upgrade takes a handful of short parameters; anything longer isn't worth parsing
+const MAX_WS_QUERY_LEN: usize = 2048;
+const MAX_USER_AGENT_LEN: usize = 256;
+const REPLAY_CHUNK_INTERVAL: Duration = Duration::from_millis(20);
+
+impl WebSocketSession {
+    // A session with what the upgrade request didn't choose left at its defaults
+    fn new(app_state: Arc<AppState>, room_id: Uuid, username: String, authenticated: bool, client_ip: String) -> Self {
+        WebSocketSession {
+            room_id,
+            username,
+            authenticated,
+            status_text: None,
+            color: None,
+            replay_history: true,
+            session_id: Uuid::new_v4(),
+            resume_token: Uuid::new_v4(),
+            resume_from: None,
+            last_delivered: None,
+            batching: None,
+            message_bucket: TokenBucket::new(MESSAGE_BURST, MESSAGE_REFILL_PER_SEC),
+            send_failures: Arc::new(AtomicU32::new(0)),
+            client_ip,
+            user_agent: None,
+            watching: HashSet::new(),
+            strict: app_state.strict_events,
+            replay: None,
+            connected_at: Instant::now(),
+            close_reason: None,
+            paused: None,
+            throttle: Throttle::default(),
+            subscribed: None,
+            fragments: None,
+            last_reaction: None,
+            heard_from: false,
+            withheld: HashSet::new(),
+            app_state,
+        }
+    }
+
+    // Sends an event to this session only
+    fn reply(ctx: &mut ws::WebsocketContext<Self>, event: &ServerEvent) {
+        if let Ok(json) = serde_json::to_string(&Versioned::new(event)) {
+            ctx.text(json);
+        }
+    }
+
+    // Rejects a client event with a uniform error frame
+    fn reply_error(ctx: &mut ws::WebsocketContext<Self>, code: WsErrorCode, message: &str) {
+        Self::reply(ctx, &ServerEvent::Error { code, message: message.to_string() });
+    }
+
+    // Spends one of the session's message tokens, warning the client once few are left.
+    // Without a token the client is told when to retry and false is returned.
+    fn take_message_token(&mut self, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        if let Err(retry_after) = self.message_bucket.try_take() {
+            let message = format!("Sending too fast, retry in {} ms", retry_after.as_millis());
+            Self::reply_error(ctx, WsErrorCode::RateLimited, &message);
+            return false;
+        }
+        if let Some(remaining) = self.message_bucket.running_low() {
+            Self::reply(ctx, &ServerEvent::Warning { code: WarningCode::ApproachingLimit, remaining });
+        }
+        true
+    }
+
+    fn send_chat_message(&mut self, ctx: &mut ws::WebsocketContext<Self>, draft: Draft) {
+        if !self.take_message_token(ctx) {
+            return;
+        }
+
+        // Rejections are answered to the sender only
+        if let Err(event) = self.app_state.post_message(self.room_id, &self.username, draft) {
+            Self::reply(ctx, &event);
+        }
+    }
+
+    // Reposts a message of this room in another one, as a new message by the forwarder.
+    // They have to be a member of both rooms, and the target room's rules apply as usual.
+    fn forward(&mut self, ctx: &mut ws::WebsocketContext<Self>, message_id: Uuid, to_room: Uuid) {
+        let allowed = {
+            let rooms = self.app_state.rooms.lock().unwrap();
+            let member_of = |room_id: &Uuid| {
+                rooms
+                    .get(room_id)
+                    .is_some_and(|room| room.is_member(&self.username) && !room.banned.contains(&self.username))
+            };
+            member_of(&self.room_id) && member_of(&to_room)
+        };
+        if !allowed {
+            return Self::reply_error(ctx, WsErrorCode::Forbidden, "You must be a member of both rooms to forward");
+        }
+        let Some(original) = self.app_state.messages.get(self.room_id, message_id) else {
+            return Self::reply_error(ctx, WsErrorCode::MessageNotFound, "Message not found");
+        };
+        if !self.take_message_token(ctx) {
+            return;
+        }
+
+        let forwarded_from = ForwardedFrom {
+            room_id: self.room_id,
+            message_id,
+            username: original.username,
+        };
+        let draft = Draft {
+            text: original.message,
+            format: original.format,
+            signature: None,
+            attachment: original.attachment,
+            ttl_secs: None,
+            forwarded_from: Some(forwarded_from),
+        };
+        let posted = self.app_state.post_message(to_room, &self.username, draft);
+        match posted {
+            Ok(()) => Self::reply(ctx, &ServerEvent::Forwarded { message_id, to_room }),
+            Err(event) => Self::reply(ctx, &event),
+        }
+    }
+
+    // Tombstones every message this user sent to the room and tells the room about each deletion
+    fn purge_mine(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        let store = &self.app_state.messages;
+        let purged: Vec<Uuid> = store
+            .recent(self.room_id, usize::MAX)
+            .into_iter()
+            .filter(|message| message.username == self.username && !message.deleted)
+            .filter(|message| store.delete(self.room_id, message.id))
+            .map(|message| message.id)
+            .collect();
+
+        for message_id in &purged {
+            self.app_state.broadcast(self.room_id, &ServerEvent::Deleted { message_id: *message_id });
+        }
+        Self::reply(ctx, &ServerEvent::Purged { count: purged.len() });
+    }
+
+    // Authors may edit their own messages within the room's edit window. The old signature
+    // can't cover the new text, so signed rooms need a fresh one.
+    fn edit_message(
+        &self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        message_id: Uuid,
+        mut text: String,
+        signature: Option<String>,
+    ) {
+        let room = self.app_state.rooms.lock().unwrap().get(&self.room_id).map(|room| {
+            (room.archived, room.ephemeral, room.require_signatures, room.edit_window_secs)
+        });
+        let Some((archived, ephemeral, require_signatures, edit_window_secs)) = room else {
+            return Self::reply_error(ctx, WsErrorCode::MessageNotFound, "Message not found");
+        };
+        if archived {
+            return Self::reply_error(ctx, WsErrorCode::RoomArchived, "This room is archived");
+        }
+        if ephemeral {
+            return Self::reply_error(ctx, WsErrorCode::EphemeralRoom, "Ephemeral rooms do not support editing");
+        }
+        if let Err(message) = prepare_text(&mut text, signature.is_some()) {
+            return Self::reply_error(ctx, WsErrorCode::TooLong, &message);
+        }
+        let text = match self.app_state.sanitize(text, signature.is_some()) {
+            Ok(text) => text,
+            Err(event) => return Self::reply(ctx, &event),
+        };
+        if let Err(event) = self.app_state.check_wordlist(&text) {
+            return Self::reply(ctx, &event);
+        }
+        match self.app_state.check_signature(&self.username, &text, signature.as_deref()) {
+            Ok(false) if require_signatures => return Self::reply(ctx, &unsigned_rejected()),
+            Ok(_) => {}
+            Err(event) => return Self::reply(ctx, &event),
+        }
+
+        let now = now_ms();
+        let edited = self
+            .app_state
+            .with_message(self.room_id, message_id, |message| {
+                if message.username != self.username {
+                    Err((WsErrorCode::Forbidden, "You can only edit your own messages"))
+                } else if now.saturating_sub(message.sent_at) > edit_window_secs * 1000 {
+                    Err((WsErrorCode::EditWindowExpired, "This message can no longer be edited"))
+                } else {
+                    message.message = text.clone();
+                    message.signature = signature.clone();
+                    message.edited_at = Some(now);
+                    Ok(())
+                }
+            })
+            .unwrap_or(Err((WsErrorCode::MessageNotFound, "Message not found")));
+
+        match edited {
+            Ok(()) => self.app_state.broadcast(
+                self.room_id,
+                &ServerEvent::Edited { message_id, text, edited_at: now, signature },
+            ),
+            Err((code, message)) => Self::reply_error(ctx, code, message),
+        }
+    }
+
+    fn react(&mut self, ctx: &mut ws::WebsocketContext<Self>, message_id: Uuid, emoji: String) {
+        if self.app_state.is_ephemeral(self.room_id) {
+            return Self::reply_error(ctx, WsErrorCode::EphemeralRoom, "Ephemeral rooms do not support reactions");
+        }
+        if self.app_state.is_archived(self.room_id) {
+            return Self::reply_error(ctx, WsErrorCode::RoomArchived, "This room is archived");
+        }
+        if !self.kind_allowed(ctx, MessageKind::Reaction) || self.reaction_cooling_down(ctx) {
+            return;
+        }
+
+        // Err if the user is already at the limit of different emojis on this message
+        let max = self.app_state.max_reactions_per_user;
+        let added = self.app_state.with_message(self.room_id, message_id, |message| {
+            if message.reactions.get(&emoji).is_some_and(|users| users.contains(&self.username)) {
+                return Ok(false);
+            }
+            let mine = message.reactions.values().filter(|users| users.contains(&self.username)).count();
+            if mine >= max {
+                return Err(());
+            }
+            Ok(message
+                .reactions
+                .entry(emoji.clone())
+                .or_default()
+                .insert(self.username.clone()))
+        });
+
+        match added {
+            Some(Ok(true)) => {
+                self.last_reaction = Some(Instant::now());
+                self.app_state.broadcast_to_others(
+                    self.room_id,
+                    &self.username,
+                    &ServerEvent::ReactionAdded { message_id, username: self.username.clone(), emoji },
+                )
+            }
+            Some(Ok(false)) => {}
+            Some(Err(())) => Self::reply_error(
+                ctx,
+                WsErrorCode::ReactionLimited,
+                &format!("At most {} different reactions per message", max),
+            ),
+            None => Self::reply_error(ctx, WsErrorCode::MessageNotFound, "Message not found"),
+        }
+    }
+
+    // Replies with an error unless the room's allowed_message_types include `kind`
+    fn kind_allowed(&self, ctx: &mut ws::WebsocketContext<Self>, kind: MessageKind) -> bool {
+        let allowed = {
+            let rooms = self.app_state.rooms.lock().unwrap();
+            rooms.get(&self.room_id).is_some_and(|room| room.allows(kind))
+        };
+        if !allowed {
+            Self::reply(ctx, &kind.refusal());
+        }
+        allowed
+    }
+
+    // Replies with an error if this session changed a reaction too recently
+    fn reaction_cooling_down(&self, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        let Some(left) = self
+            .last_reaction
+            .and_then(|last| self.app_state.reaction_cooldown.checked_sub(last.elapsed()))
+        else {
+            return false;
+        };
+        let message = format!("Reacting too fast, retry in {} ms", left.as_millis().max(1));
+        Self::reply_error(ctx, WsErrorCode::ReactionLimited, &message);
+        true
+    }
+
+    // Removing a reaction the user never made is a no-op
+    fn unreact(&mut self, ctx: &mut ws::WebsocketContext<Self>, message_id: Uuid, emoji: String) {
+        if self.app_state.is_ephemeral(self.room_id) {
+            return Self::reply_error(ctx, WsErrorCode::EphemeralRoom, "Ephemeral rooms do not support reactions");
+        }
+        if self.app_state.is_archived(self.room_id) {
+            return Self::reply_error(ctx, WsErrorCode::RoomArchived, "This room is archived");
+        }
+        if self.reaction_cooling_down(ctx) {
+            return;
+        }
+
+        let removed = self.app_state.with_message(self.room_id, message_id, |message| {
+            let Some(users) = message.reactions.get_mut(&emoji) else {
+                return false;
+            };
+            let removed = users.remove(&self.username);
+            if users.is_empty() {
+                message.reactions.remove(&emoji);
+            }
+            removed
+        });
+
+        match removed {
+            Some(true) => {
+                self.last_reaction = Some(Instant::now());
+                self.app_state.broadcast_to_others(
+                    self.room_id,
+                    &self.username,
+                    &ServerEvent::ReactionRemoved { message_id, username: self.username.clone(), emoji },
+                )
+            }
+            Some(false) => {}
+            None => Self::reply_error(ctx, WsErrorCode::MessageNotFound, "Message not found"),
+        }
+    }
+
+    // The room's creator or one of its moderators, or a user with the moderator or admin role
+    fn can_moderate(&self) -> bool {
+        let room_moderator = {
+            let rooms = self.app_state.rooms.lock().unwrap();
+            rooms.get(&self.room_id).is_some_and(|room| room.can_moderate(&self.username))
+        };
+        if room_moderator {
+            return true;
+        }
+        let users = self.app_state.users.lock().unwrap();
+        users
+            .get(&self.username)
+            .is_some_and(|user| user.roles.contains(&Role::Moderator) || user.roles.contains(&Role::Admin))
+    }
+
+    // Only the room's creator may change the topic
+    fn set_topic(&self, ctx: &mut ws::WebsocketContext<Self>, mut text: String) {
+        limits::trim(&mut text);
+        {
+            let mut rooms = self.app_state.rooms.lock().unwrap();
+            let Some(room) = rooms.get_mut(&self.room_id) else {
+                return;
+            };
+            if room.creator != self.username {
+                return Self::reply_error(ctx, WsErrorCode::Forbidden, "Only the room creator can set the topic");
+            }
+            if let Err(message) = check_len("topic", &text, MAX_TOPIC_LEN) {
+                return Self::reply_error(ctx, WsErrorCode::TooLong, &message);
+            }
+            room.topic = text.clone();
+        }
+
+        self.app_state.broadcast(self.room_id, &ServerEvent::Topic { text });
+    }
+
+    // Changes several settings at once and shows the room the result. Creator only, except that
+    // moderators may change allowed_message_types on its own.
+    fn update_settings(&self, ctx: &mut ws::WebsocketContext<Self>, mut update: SettingsUpdate) {
+        update.topic.iter_mut().chain(update.welcome_message.iter_mut()).for_each(limits::trim);
+        let topic = update.topic.clone();
+        let may_moderate = !update.needs_creator() && self.can_moderate();
+        let settings = {
+            let mut rooms = self.app_state.rooms.lock().unwrap();
+            let Some(room) = rooms.get_mut(&self.room_id) else {
+                return;
+            };
+            if room.creator != self.username && !may_moderate {
+                return Self::reply_error(ctx, WsErrorCode::Forbidden, "Only the room creator can change settings");
+            }
+            if let Err(message) = update.validate(room, self.app_state.max_history) {
+                return Self::reply_error(ctx, WsErrorCode::InvalidEvent, &message);
+            }
+            update.apply(room);
+            room.settings()
+        };
+
+        // Clients that only follow topic frames still learn about the new topic
+        if let Some(text) = topic {
+            self.app_state.broadcast(self.room_id, &ServerEvent::Topic { text });
+        }
+        self.app_state.broadcast(self.room_id, &ServerEvent::Settings(settings));
+    }
+
+    fn set_meta(&mut self, ctx: &mut ws::WebsocketContext<Self>, color: Option<String>) {
+        if let Some(color) = &color {
+            let hex = color.strip_prefix('#').unwrap_or_default();
+            if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Self::reply_error(ctx, WsErrorCode::InvalidEvent, "color must look like #rrggbb");
+            }
+        }
+
+        self.color = color.clone();
+        self.app_state.broadcast(
+            self.room_id,
+            &ServerEvent::Meta {
+                username: self.username.clone(),
+                color,
+            },
+        );
+    }
+
+    fn set_status(&mut self, ctx: &mut ws::WebsocketContext<Self>, mut text: String) {
+        limits::trim(&mut text);
+        if let Err(message) = check_len("text", &text, MAX_STATUS_LEN) {
+            return Self::reply_error(ctx, WsErrorCode::TooLong, &message);
+        }
+
+        self.status_text = Some(text.clone());
+        self.app_state.broadcast_to_others(
+            self.room_id,
+            &self.username,
+            &ServerEvent::Presence {
+                username: self.username.clone(),
+                status: None,
+                status_text: Some(text),
+            },
+        );
+    }
+
+    // Explicit history fetch, answered to the requesting session only. Without `after_seq` it is
+    // the latest `limit` messages; with it, the first `limit` messages whose seq is greater.
+    fn send_history(&self, ctx: &mut ws::WebsocketContext<Self>, limit: Option<usize>, after_seq: Option<u64>) {
+        let limit = limit.unwrap_or(DEFAULT_HISTORY_LIMIT).min(MAX_HISTORY_LIMIT);
+        let messages = match after_seq {
+            Some(after_seq) => self
+                .app_state
+                .messages
+                .recent(self.room_id, usize::MAX)
+                .into_iter()
+                .filter(|message| message.seq > after_seq)
+                .take(limit)
+                .collect(),
+            None => self.app_state.messages.recent(self.room_id, limit),
+        };
+        Self::reply(ctx, &ServerEvent::History { messages, more: false });
+    }
+
+    // Replays history on connect. Large backlogs go out REPLAY_CHUNK_SIZE messages at a time,
+    // one chunk per REPLAY_CHUNK_INTERVAL, so a reconnect storm doesn't pile whole histories
+    // into socket buffers at once. Live events are held back until the replay is done.
+    fn start_replay(&mut self, ctx: &mut ws::WebsocketContext<Self>, messages: Vec<ChatMessage>) {
+        if messages.len() <= REPLAY_CHUNK_SIZE {
+            return Self::reply(ctx, &ServerEvent::History { messages, more: false });
+        }
+        self.replay = Some(Replay {
+            remaining: messages.into(),
+            deferred: Vec::new(),
+        });
+        self.send_replay_chunk(ctx);
+    }
+
+    fn send_replay_chunk(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let Some(replay) = &mut self.replay else {
+            return;
+        };
+        let count = replay.remaining.len().min(REPLAY_CHUNK_SIZE);
+        let messages: Vec<ChatMessage> = replay.remaining.drain(..count).collect();
+        let more = !replay.remaining.is_empty();
+        Self::reply(ctx, &ServerEvent::History { messages, more });
+
+        if more {
+            ctx.run_later(REPLAY_CHUNK_INTERVAL, |session, ctx| session.send_replay_chunk(ctx));
+        } else if let Some(replay) = self.replay.take() {
+            for event in replay.deferred {
+                self.deliver(ctx, event);
+            }
+        }
+    }
+
+    // Hands a live event on, unless it has to wait for the history replay to finish
+    fn dispatch(&mut self, ctx: &mut ws::WebsocketContext<Self>, event: ServerEvent) {
+        match &mut self.replay {
+            Some(replay) => replay.deferred.push(event),
+            None => self.deliver(ctx, event),
+        }
+    }
+
+    // Sends a typing/presence frame now if the last one went out at least `interval` ago;
+    // otherwise it replaces any pending frame for the same user and waits for the next slot
+    fn throttle_transient(&mut self, ctx: &mut ws::WebsocketContext<Self>, event: ServerEvent, interval: Duration) {
+        let now = Instant::now();
+        let due = self.throttle.last_sent.is_none_or(|last| now - last >= interval);
+        if due && self.throttle.flush.is_none() {
+            self.throttle.last_sent = Some(now);
+            return self.dispatch(ctx, event);
+        }
+
+        let key = transient_key(&event);
+        match self.throttle.pending.iter_mut().find(|pending| transient_key(pending) == key) {
+            Some(pending) => *pending = event,
+            None => self.throttle.pending.push(event),
+        }
+        if self.throttle.flush.is_none() {
+            let wait = self.throttle.last_sent.map_or(Duration::ZERO, |last| interval.saturating_sub(now - last));
+            self.throttle.flush = Some(ctx.run_later(wait, move |session, ctx| session.flush_transient(ctx, interval)));
+        }
+    }
+
+    // Sends the oldest pending transient frame and schedules the next one
+    fn flush_transient(&mut self, ctx: &mut ws::WebsocketContext<Self>, interval: Duration) {
+        self.throttle.flush = None;
+        if self.throttle.pending.is_empty() {
+            return;
+        }
+        let event = self.throttle.pending.remove(0);
+        self.throttle.last_sent = Some(Instant::now());
+        self.dispatch(ctx, event);
+        if !self.throttle.pending.is_empty() {
+            self.throttle.flush = Some(ctx.run_later(interval, move |session, ctx| session.flush_transient(ctx, interval)));
+        }
+    }
+
+    // Sends a broadcast event now, or queues it for the next batch frame
+    fn deliver(&mut self, ctx: &mut ws::WebsocketContext<Self>, event: ServerEvent) {
+        match &mut self.batching {
+            Some(batching) => batching.pending.push(event),
+            None => Self::reply(ctx, &event),
+        }
+    }
+
+    // Posts one of the room's canned replies as an ordinary message from this user
+    fn send_template(&mut self, ctx: &mut ws::WebsocketContext<Self>, name: &str) {
+        if !self.can_moderate() {
+            return Self::reply_error(ctx, WsErrorCode::Forbidden, "Only moderators can send templates");
+        }
+        let text = {
+            let rooms = self.app_state.rooms.lock().unwrap();
+            rooms.get(&self.room_id).and_then(|room| room.templates.get(name).cloned())
+        };
+        match text {
+            Some(text) => self.send_chat_message(ctx, Draft::plain(text)),
+            None => Self::reply_error(ctx, WsErrorCode::TemplateNotFound, "Template not found"),
+        }
+    }
+
+    fn start_poll(&mut self, ctx: &mut ws::WebsocketContext<Self>, question: String, options: Vec<String>) {
+        if !self.can_moderate() {
+            return Self::reply_error(ctx, WsErrorCode::Forbidden, "Only moderators can start polls");
+        }
+        if !self.kind_allowed(ctx, MessageKind::Poll) {
+            return;
+        }
+        let question = question.trim().to_string();
+        let options: Vec<String> = options.iter().map(|option| option.trim().to_string()).collect();
+        if let Err(message) = validate_poll(&question, &options) {
+            return Self::reply_error(ctx, WsErrorCode::InvalidEvent, &message);
+        }
+
+        let started = {
+            let mut rooms = self.app_state.rooms.lock().unwrap();
+            match rooms.get_mut(&self.room_id) {
+                Some(room) if room.polls.len() >= self.app_state.max_active_polls => {
+                    Err((WsErrorCode::PollLimit, "Close an open poll before starting another"))
+                }
+                Some(room) => {
+                    let poll = Poll {
+                        id: Uuid::new_v4(),
+                        question,
+                        options,
+                        created_by: self.username.clone(),
+                        votes: HashMap::new(),
+                    };
+                    let results = poll.results(false);
+                    room.polls.push(poll);
+                    Ok(results)
+                }
+                None => Err((WsErrorCode::RoomNotFound, "Room not found")),
+            }
+        };
+        match started {
+            Ok(results) => self.app_state.broadcast(self.room_id, &ServerEvent::Poll(results)),
+            Err((code, message)) => Self::reply_error(ctx, code, message),
+        }
+    }
+
+    // Members only. Voting again replaces the earlier vote; repeating the same vote changes nothing
+    fn vote(&self, ctx: &mut ws::WebsocketContext<Self>, poll_id: Uuid, option: usize) {
+        let voted = {
+            let mut rooms = self.app_state.rooms.lock().unwrap();
+            let Some(room) = rooms.get_mut(&self.room_id) else {
+                return;
+            };
+            let member = room.is_member(&self.username);
+            match room.polls.iter_mut().find(|poll| poll.id == poll_id) {
+                None => Err((WsErrorCode::PollNotFound, "Poll not found")),
+                Some(_) if !member => Err((WsErrorCode::Forbidden, "Only members can vote")),
+                Some(poll) if option >= poll.options.len() => Err((WsErrorCode::InvalidEvent, "The poll has no such option")),
+                Some(poll) => {
+                    let changed = poll.votes.insert(self.username.clone(), option) != Some(option);
+                    Ok(changed.then(|| poll.results(false)))
+                }
+            }
+        };
+        match voted {
+            Ok(Some(results)) => self.app_state.broadcast(self.room_id, &ServerEvent::Poll(results)),
+            Ok(None) => {}
+            Err((code, message)) => Self::reply_error(ctx, code, message),
+        }
+    }
+
+    fn close_poll(&mut self, ctx: &mut ws::WebsocketContext<Self>, poll_id: Uuid) {
+        if !self.can_moderate() {
+            return Self::reply_error(ctx, WsErrorCode::Forbidden, "Only moderators can close polls");
+        }
+        let closed = {
+            let mut rooms = self.app_state.rooms.lock().unwrap();
+            rooms.get_mut(&self.room_id).and_then(|room| {
+                let index = room.polls.iter().position(|poll| poll.id == poll_id)?;
+                Some(room.polls.remove(index).results(true))
+            })
+        };
+        match closed {
+            Some(results) => self.app_state.broadcast(self.room_id, &ServerEvent::Poll(results)),
+            None => Self::reply_error(ctx, WsErrorCode::PollNotFound, "Poll not found"),
+        }
+    }
+
+    // The text of a complete client message, if `msg` completes one
+    fn incoming_text(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        msg: Result<ws::Message, ws::ProtocolError>,
+    ) -> Option<web::Bytes> {
+        match msg {
+            Ok(ws::Message::Text(text)) => Some(text.into_bytes()),
+            Ok(ws::Message::Continuation(item)) => self.reassemble(ctx, item),
+            _ => None,
+        }
+    }
+
+    // Collects a fragmented message. It is limited to MAX_WS_FRAME_SIZE as a whole, the same
+    // as a single frame.
+    fn reassemble(&mut self, ctx: &mut ws::WebsocketContext<Self>, item: Item) -> Option<web::Bytes> {
+        let (data, last) = match item {
+            Item::FirstText(data) => {
+                self.fragments = Some(Fragments::new(false));
+                (data, false)
+            }
+            Item::FirstBinary(data) => {
+                self.fragments = Some(Fragments::new(true));
+                (data, false)
+            }
+            Item::Continue(data) => (data, false),
+            Item::Last(data) => (data, true),
+        };
+        let Some(fragments) = &mut self.fragments else {
+            Self::reply_error(ctx, WsErrorCode::InvalidEvent, "Continuation frame without a first frame");
+            return None;
+        };
+        if !fragments.too_long {
+            if fragments.buffer.len() + data.len() > MAX_WS_FRAME_SIZE {
+                fragments.too_long = true;
+                fragments.buffer.clear();
+                let message = format!("Messages are limited to {} bytes", MAX_WS_FRAME_SIZE);
+                Self::reply_error(ctx, WsErrorCode::TooLong, &message);
+            } else {
+                fragments.buffer.extend_from_slice(&data);
+            }
+        }
+        if !last {
+            return None;
+        }
+        let fragments = self.fragments.take()?;
+        (!fragments.binary && !fragments.too_long).then(|| fragments.buffer.freeze())
+    }
+
+    // Limits live events to the given types, named as in their "type" field. Replies to the
+    // session's own requests are not filtered.
+    fn subscribe(&mut self, ctx: &mut ws::WebsocketContext<Self>, events: Option<Vec<String>>) {
+        if events.as_ref().is_some_and(|events| events.len() > MAX_SUBSCRIBED_TYPES) {
+            return Self::reply_error(
+                ctx,
+                WsErrorCode::InvalidEvent,
+                &format!("At most {} event types can be subscribed to", MAX_SUBSCRIBED_TYPES),
+            );
+        }
+        self.subscribed = events.map(|events| events.into_iter().collect());
+        let events = self.subscribed.as_ref().map(|events| {
+            let mut events: Vec<String> = events.iter().cloned().collect();
+            events.sort();
+            events
+        });
+        Self::reply(ctx, &ServerEvent::Subscribed { events });
+    }
+
+    // Whether a live event passes the session's subscription and hello. The type is read back
+    // from the event's JSON, so only sessions that narrowed what they get pay for it.
+    fn wants(&self, event: &ServerEvent) -> bool {
+        if self.subscribed.is_none() && self.withheld.is_empty() {
+            return true;
+        }
+        let value = serde_json::to_value(event).unwrap_or_default();
+        let kind = value.get("type").and_then(serde_json::Value::as_str).unwrap_or_default();
+        if self.withheld.contains(kind) {
+            return false;
+        }
+        ALWAYS_DELIVERED.contains(&kind) || self.subscribed.as_ref().is_none_or(|subscribed| subscribed.contains(kind))
+    }
+
+    // Progressive enhancement: the client lists what it understands, the server turns on the
+    // CAPABILITIES it shares and stops sending event types the client didn't claim
+    fn hello(&mut self, ctx: &mut ws::WebsocketContext<Self>, supports: Vec<String>, first_frame: bool) {
+        if !first_frame {
+            return Self::reply_error(ctx, WsErrorCode::InvalidEvent, "hello must be the first frame");
+        }
+        let mut enabled = Vec::new();
+        for &(capability, events) in CAPABILITIES {
+            if supports.iter().any(|supported| supported == capability) {
+                enabled.push(capability.to_string());
+            } else {
+                self.withheld.extend(events);
+            }
+        }
+        if enabled.iter().any(|capability| capability == "batch") {
+            self.set_batching(ctx, Some(HELLO_BATCH_INTERVAL_MS));
+        }
+        Self::reply(ctx, &ServerEvent::HelloAck { enabled });
+    }
+
+    // Away mode: chat messages stop being delivered, everything else still is
+    fn pause(&mut self) {
+        self.paused.get_or_insert_with(Paused::default);
+    }
+
+    // Delivers the messages held while paused, then how many there were. If too many arrived
+    // only the count is sent, and the client catches up through history.
+    fn resume(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let Some(paused) = self.paused.take() else {
+            return Self::reply(ctx, &ServerEvent::Missed { count: 0 });
+        };
+        // Through the live path, so the count stays behind the messages even when batching
+        let missed = ServerEvent::Missed { count: paused.missed };
+        for event in paused.held.into_iter().chain([missed]) {
+            <Self as Handler<ServerEvent>>::handle(self, event, ctx);
+        }
+    }
+
+    // Remembers the last message this session was sent; saved as the resume point in `stopped`
+    fn note_delivered(&mut self, message_id: Option<Uuid>) {
+        self.last_delivered = message_id;
+    }
+
+    fn set_batching(&mut self, ctx: &mut ws::WebsocketContext<Self>, interval_ms: Option<u64>) {
+        let interval_ms = interval_ms.unwrap_or(0);
+        if interval_ms != 0 && !(MIN_BATCH_INTERVAL_MS..=MAX_BATCH_INTERVAL_MS).contains(&interval_ms) {
+            return Self::reply_error(
+                ctx,
+                WsErrorCode::InvalidEvent,
+                &format!(
+                    "interval_ms must be between {} and {}",
+                    MIN_BATCH_INTERVAL_MS, MAX_BATCH_INTERVAL_MS
+                ),
+            );
+        }
+
+        // Whatever was queued under the old setting goes out before switching
+        self.flush_batch(ctx);
+        if let Some(batching) = self.batching.take() {
+            ctx.cancel_future(batching.timer);
+        }
+
+        if interval_ms > 0 {
+            let timer = ctx.run_interval(Duration::from_millis(interval_ms), |act, ctx| {
+                act.flush_batch(ctx);
+            });
+            self.batching = Some(Batching { timer, pending: Vec::new() });
+        }
+    }
+
+    fn flush_batch(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Some(batching) = &mut self.batching {
+            if !batching.pending.is_empty() {
+                let events = std::mem::take(&mut batching.pending);
+                Self::reply(ctx, &ServerEvent::Batch { events });
+            }
+        }
+    }
+
+    // Delivers a DM to every session of the recipient and acknowledges delivery to the sender
+    fn send_direct_message(&self, ctx: &mut ws::WebsocketContext<Self>, to: String, mut text: String) {
+        // Only accounts can receive DMs; anything else would be kept in history nobody can read
+        if !self.app_state.users.lock().unwrap().contains_key(&to) {
+            return Self::reply_error(ctx, WsErrorCode::UserNotFound, "User not found");
+        }
+        if let Err(message) = prepare_text(&mut text, false) {
+            return Self::reply_error(ctx, WsErrorCode::TooLong, &message);
+        }
+        let text = match self.app_state.sanitize(text, false) {
+            Ok(text) => text,
+            Err(event) => return Self::reply(ctx, &event),
+        };
+        if let Err(event) = self.app_state.check_wordlist(&text) {
+            return Self::reply(ctx, &event);
+        }
+        let id = Uuid::new_v4();
+        self.app_state.direct_messages.lock().unwrap().insert(
+            id,
+            DirectMessageRoute {
+                from: self.username.clone(),
+                to: to.clone(),
+            },
+        );
+
+        {
+            let mut dm_history = self.app_state.dm_history.lock().unwrap();
+            let history = dm_history.entry(dm_conversation(&self.username, &to)).or_default();
+            history.push_back(DirectMessage {
+                id,
+                from: self.username.clone(),
+                to: to.clone(),
+                text: text.clone(),
+                sent_at: now_ms(),
+            });
+            if history.len() > MAX_DM_HISTORY {
+                history.pop_front();
+            }
+        }
+
+        let event = ServerEvent::Dm {
+            id,
+            from: self.username.clone(),
+            to: to.clone(),
+            text,
+        };
+        if self.app_state.send_to_user(&to, &event) > 0 {
+            self.app_state
+                .send_to_user(&self.username, &ServerEvent::Delivered { message_id: id });
+        }
+    }
+
+    // Read receipt: forwarded to the sender for a DM, recorded as the read marker for a room message
+    fn mark_seen(&self, ctx: &mut ws::WebsocketContext<Self>, message_id: Uuid) {
+        let sender = {
+            let direct_messages = self.app_state.direct_messages.lock().unwrap();
+            direct_messages
+                .get(&message_id)
+                .filter(|route| route.to == self.username)
+                .map(|route| route.from.clone())
+        };
+
+        if let Some(sender) = sender {
+            self.app_state.send_to_user(
+                &sender,
+                &ServerEvent::Seen { message_id, by: self.username.clone() },
+            );
+            return;
+        }
+
+        // Seeing a room message moves the read marker used for unread counts
+        match self.app_state.messages.get(self.room_id, message_id) {
+            Some(message) => self.advance_read_marker(message.seq),
+            None => Self::reply_error(ctx, WsErrorCode::MessageNotFound, "Message not found"),
+        }
+    }
+
+    // Moves this user's read marker on the client's request, letting the others know if the room
+    // shares read cursors
+    fn advance_read_marker(&self, seq: u64) {
+        if !self.app_state.mark_read(self.room_id, &self.username, seq) {
+            return;
+        }
+        let shared = self
+            .app_state
+            .rooms
+            .lock()
+            .unwrap()
+            .get(&self.room_id)
+            .is_some_and(|room| room.share_read_cursors);
+        if shared {
+            let event = ServerEvent::Read {
+                username: self.username.clone(),
+                up_to_seq: seq,
+            };
+            self.app_state.broadcast_to_others(self.room_id, &self.username, &event);
+        }
+    }
+
+    // Marks everything up to `up_to_seq` read at once. Sequence numbers past the room's latest
+    // message are capped, so the marker can't run ahead of messages still to come.
+    fn mark_read_up_to(&self, ctx: &mut ws::WebsocketContext<Self>, up_to_seq: u64) {
+        let last_seq = self.app_state.rooms.lock().unwrap().get(&self.room_id).map(|room| room.last_seq);
+        let Some(last_seq) = last_seq else {
+            return;
+        };
+        self.advance_read_marker(up_to_seq.min(last_seq));
+        let event = ServerEvent::MarkedRead {
+            read_seq: self.app_state.read_marker(self.room_id, &self.username),
+            unread: self.app_state.unread_count(self.room_id, &self.username),
+        };
+        Self::reply(ctx, &event);
+    }
+
+    // Like GET /list_rooms, but also includes private rooms this user was invited to
+    fn list_rooms(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        let rooms: Vec<Room> = {
+            let rooms = self.app_state.rooms.lock().unwrap();
+            rooms
+                .values()
+                .filter(|room| room.visible_to(&self.username) && !room.archived)
+                .cloned()
+                .collect()
+        };
+        Self::reply(ctx, &ServerEvent::Rooms { rooms });
+    }
+
+    // Pins or unpins a stored message (moderators only); a room holds at most MAX_PINS_PER_ROOM pins
+    fn set_pinned(&self, ctx: &mut ws::WebsocketContext<Self>, message_id: Uuid, pinned: bool) {
+        if !self.can_moderate() {
+            return Self::reply_error(ctx, WsErrorCode::Forbidden, "Only moderators can pin messages");
+        }
+
+        let pin_count = self
+            .app_state
+            .messages
+            .recent(self.room_id, usize::MAX)
+            .iter()
+            .filter(|message| message.pinned)
+            .count();
+        let changed = self
+            .app_state
+            .with_message(self.room_id, message_id, |message| {
+                if pinned && !message.pinned && pin_count >= MAX_PINS_PER_ROOM {
+                    Err((WsErrorCode::PinLimit, "This room already has the maximum number of pins"))
+                } else {
+                    Ok(std::mem::replace(&mut message.pinned, pinned) != pinned)
+                }
+            })
+            .unwrap_or(Err((WsErrorCode::MessageNotFound, "Message not found")));
+
+        match changed {
+            Ok(true) if pinned => self.app_state.broadcast(self.room_id, &ServerEvent::Pinned { message_id }),
+            Ok(true) => self.app_state.broadcast(self.room_id, &ServerEvent::Unpinned { message_id }),
+            Ok(false) => {}
+            Err((code, message)) => Self::reply_error(ctx, code, message),
+        }
+    }
+
+    // Deep-link support: one stored message of this room, sent to the requester only
+    fn get_message(&self, ctx: &mut ws::WebsocketContext<Self>, message_id: Uuid) {
+        match self.app_state.messages.get(self.room_id, message_id) {
+            Some(message) => Self::reply(ctx, &ServerEvent::MessageDetail { message }),
+            None => Self::reply_error(ctx, WsErrorCode::MessageNotFound, "Message not found"),
+        }
+    }
+
+    // A message with up to `before` older and `after` newer ones around it, for jumping to it
+    fn send_context(
+        &self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        message_id: Uuid,
+        before: Option<usize>,
+        after: Option<usize>,
+    ) {
+        let before = before.unwrap_or(DEFAULT_CONTEXT_WINDOW).min(MAX_CONTEXT_WINDOW);
+        let after = after.unwrap_or(DEFAULT_CONTEXT_WINDOW).min(MAX_CONTEXT_WINDOW);
+        let history = self.app_state.messages.recent(self.room_id, usize::MAX);
+        let Some(index) = history.iter().position(|message| message.id == message_id) else {
+            return Self::reply_error(ctx, WsErrorCode::MessageNotFound, "Message not found");
+        };
+        let end = (index + after + 1).min(history.len());
+        let messages = history[index.saturating_sub(before)..end].to_vec();
+        Self::reply(ctx, &ServerEvent::Context { message_id, messages });
+    }
+
+    fn reaction_summary(&self, ctx: &mut ws::WebsocketContext<Self>, message_id: Uuid) {
+        let Some(message) = self.app_state.messages.get(self.room_id, message_id) else {
+            return Self::reply_error(ctx, WsErrorCode::MessageNotFound, "Message not found");
+        };
+        let summary = message
+            .reactions
+            .iter()
+            .map(|(emoji, users)| (emoji.clone(), users.len()))
+            .collect();
+        let mut mine: Vec<String> = message
+            .reactions
+            .iter()
+            .filter(|(_, users)| users.contains(&self.username))
+            .map(|(emoji, _)| emoji.clone())
+            .collect();
+        mine.sort();
+        Self::reply(ctx, &ServerEvent::Reactions { message_id, summary, mine });
+    }
+
+    fn rate_status(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let (remaining, reset_in) = self.message_bucket.status();
+        let event = ServerEvent::RateStatus {
+            remaining,
+            reset_in_ms: reset_in.as_millis() as u64,
+        };
+        Self::reply(ctx, &event);
+    }
+
+    // Server clock for skew correction; lighter than a ping/pong round
+    fn time(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        let now = SystemTime::now();
+        let event = ServerEvent::Time {
+            now: humantime::format_rfc3339_millis(now).to_string(),
+            epoch_ms: now.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+        };
+        Self::reply(ctx, &event);
+    }
+
+    // Moderators only: `duration_secs` mutes the user for that long, None lifts the mute.
+    // Muted users' messages are dropped before they are stored or broadcast.
+    fn set_muted(&self, ctx: &mut ws::WebsocketContext<Self>, username: String, duration_secs: Option<u64>) {
+        if let Some(secs) = duration_secs {
+            if secs == 0 || secs > MAX_MUTE_SECS {
+                let message = format!("duration_secs must be between 1 and {}", MAX_MUTE_SECS);
+                return Self::reply_error(ctx, WsErrorCode::InvalidEvent, &message);
+            }
+        }
+
+        if !self.can_moderate() {
+            return Self::reply_error(ctx, WsErrorCode::Forbidden, "Only moderators can mute users");
+        }
+
+        {
+            let mut rooms = self.app_state.rooms.lock().unwrap();
+            let Some(room) = rooms.get_mut(&self.room_id) else {
+                return;
+            };
+            match duration_secs {
+                Some(_) if username == room.creator => {
+                    return Self::reply_error(ctx, WsErrorCode::Forbidden, "The room creator can't be muted");
+                }
+                Some(secs) => {
+                    room.muted.insert(username.clone(), Instant::now() + Duration::from_secs(secs));
+                }
+                None => {
+                    room.muted.remove(&username);
+                }
+            }
+        }
+
+        let event = ServerEvent::MuteUpdated {
+            username,
+            muted: duration_secs.is_some(),
+        };
+        Self::reply(ctx, &event);
+    }
+
+    // Refreshes this user's typing indicator and tells the room
+    fn typing(&self) {
+        {
+            let mut rooms = self.app_state.rooms.lock().unwrap();
+            let Some(room) = rooms.get_mut(&self.room_id) else {
+                return;
+            };
+            room.typing.insert(self.username.clone(), Instant::now());
+        }
+
+        let event = ServerEvent::Typing {
+            username: self.username.clone(),
+        };
+        self.app_state.broadcast_to_others(self.room_id, &self.username, &event);
+    }
+
+    // For clients that joined mid-typing and missed the typing events
+    fn who_typing(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        let usernames = {
+            let mut rooms = self.app_state.rooms.lock().unwrap();
+            rooms.get_mut(&self.room_id).map(Room::typing_users).unwrap_or_default()
+        };
+        Self::reply(ctx, &ServerEvent::WhoTyping { usernames });
+    }
+
+    fn clear_history(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        if !self.can_moderate() {
+            return Self::reply_error(ctx, WsErrorCode::Forbidden, "Only moderators can clear the history");
+        }
+        self.app_state.clear_history(self.room_id);
+    }
+
+    // Replaces the set of users this session watches; those already online are reported right away
+    fn watch(&mut self, ctx: &mut ws::WebsocketContext<Self>, usernames: Vec<String>) {
+        let usernames: HashSet<String> = usernames.into_iter().collect();
+        if usernames.len() > MAX_WATCHED_USERS {
+            let message = format!("At most {} users can be watched", MAX_WATCHED_USERS);
+            return Self::reply_error(ctx, WsErrorCode::TooLong, &message);
+        }
+
+        self.set_watched(usernames, &ctx.address());
+        for username in &self.watching {
+            if self.app_state.is_online(username) {
+                let event = ServerEvent::Presence {
+                    username: username.clone(),
+                    status: Some(PresenceStatus::Online),
+                    status_text: None,
+                };
+                Self::reply(ctx, &event);
+            }
+        }
+    }
+
+    fn set_watched(&mut self, usernames: HashSet<String>, addr: &Addr<Self>) {
+        let mut watchers = self.app_state.watchers.lock().unwrap();
+        for username in self.watching.difference(&usernames) {
+            if let Some(sessions) = watchers.get_mut(username) {
+                sessions.retain(|session| session != addr);
+                if sessions.is_empty() {
+                    watchers.remove(username);
+                }
+            }
+        }
+        for username in usernames.difference(&self.watching) {
+            watchers.entry(username.clone()).or_default().push(addr.clone());
+        }
+        self.watching = usernames;
+    }
+
+    // Gives up membership of the room, tells the room and closes this socket
+    fn leave(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Some(room) = self.app_state.rooms.lock().unwrap().get_mut(&self.room_id) {
+            room.users.remove(&self.username);
+        }
+
+        let event = ServerEvent::Left {
+            username: self.username.clone(),
+        };
+        self.app_state.broadcast(self.room_id, &event);
+        self.close_reason = Some("Left the room".to_string());
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Normal,
+            description: self.close_reason.clone(),
+        }));
+        ctx.stop();
+    }
+}
+
+impl Actor for WebSocketSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if !self.authenticated && self.app_state.guest_name_taken(self.room_id, &self.username) {
+            Self::reply_error(ctx, WsErrorCode::NameTaken, "This name is already in use in the room");
+            self.close_reason = Some("Name already in use".to_string());
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: self.close_reason.clone(),
+            }));
+            return ctx.stop();
+        }
+        Self::reply(
+            ctx,
+            &ServerEvent::Session {
+                session_id: self.session_id,
+                room_id: self.room_id,
+                username: self.username.clone(),
+            },
+        );
+        log::info!(
+            "ws_connect session_id={} room_id={} username={} ip={}",
+            self.session_id,
+            self.room_id,
+            self.username,
+            if self.app_state.record_client_ips { &self.client_ip } else { "-" }
+        );
+
+        // Bounded so that Connection::send can notice a session that doesn't keep up
+        ctx.set_mailbox_capacity(SESSION_MAILBOX_CAPACITY);
+        self.app_state.register_session(
+            &self.username,
+            UserSession {
+                addr: ctx.address(),
+                session_id: self.session_id,
+                room_id: self.room_id,
+                connected_at: now_ms(),
+                client_ip: self.app_state.record_client_ips.then(|| self.client_ip.clone()),
+                user_agent: self.user_agent.clone(),
+            },
+        );
+
+        let room = {
+            let mut rooms = self.app_state.rooms.lock().unwrap();
+            rooms.get_mut(&self.room_id).map(|room| {
+                if let Some(cooldown) = self.app_state.new_member_cooldown {
+                    room.note_arrival(&self.username, cooldown);
+                }
+                room.clone()
+            })
+        };
+        if let Some(room) = room {
+            let welcome = (!room.welcome_message.is_empty()).then(|| room.welcome_message.clone());
+            let connected = ServerEvent::Connected {
+                room: Box::new(room),
+                resume_token: self.resume_token,
+                session_id: self.session_id,
+            };
+            Self::reply(ctx, &connected);
+            // Only for this session, ahead of the history and any live events
+            if let Some(text) = welcome {
+                Self::reply(ctx, &ServerEvent::Welcome { text });
+            }
+        }
+
+        // A valid resume token replays only what was missed; otherwise the usual replay applies
+        let resumed = self
+            .resume_from
+            .take()
+            .and_then(|token| self.app_state.take_resume_point(token, self.room_id, &self.username));
+        if let Some(point) = resumed {
+            let messages = self.app_state.history_since(self.room_id, point.last_message_id);
+            self.start_replay(ctx, messages);
+        } else if self.replay_history {
+            let messages = self.app_state.messages.recent(self.room_id, DEFAULT_HISTORY_LIMIT);
+            self.start_replay(ctx, messages);
+        }
+        self.note_delivered(self.app_state.latest_message_id(self.room_id));
+
+        let mut connections = self.app_state.connections.lock().unwrap();
+
+        // Only the user's first session in the room brings them online
+        let was_online = connections
+            .get(&self.room_id)
+            .is_some_and(|users| users.iter().any(|user| user.username == self.username));
+        if !was_online {
+            let event = ServerEvent::Presence {
+                username: self.username.clone(),
+                status: Some(PresenceStatus::Online),
+                status_text: None,
+            };
+            for user in self.app_state.recipients(&connections, self.room_id) {
+                user.send(&event);
+            }
+        }
+
+        connections.entry(self.room_id).or_default().push(Connection {
+            username: self.username.clone(),
+            addr: ctx.address(),
+            send_failures: self.send_failures.clone(),
+        });
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        let duration = self.connected_at.elapsed();
+        log::info!(
+            "ws_disconnect session_id={} room_id={} username={} duration_ms={} reason={:?}",
+            self.session_id,
+            self.room_id,
+            self.username,
+            duration.as_millis(),
+            self.close_reason.as_deref().unwrap_or("client closed")
+        );
+        self.app_state.metrics.session_duration.observe(duration);
+        self.app_state.release_ws_slot(&self.client_ip);
+        self.set_watched(HashSet::new(), &ctx.address());
+        self.app_state.unregister_session(&self.username, &ctx.address());
+        self.app_state
+            .save_resume_point(self.resume_token, self.room_id, &self.username, self.last_delivered);
+        if let Some(room) = self.app_state.rooms.lock().unwrap().get_mut(&self.room_id) {
+            room.last_session_end_ms = Some(now_ms());
+        }
+
+        let mut connections = self.app_state.connections.lock().unwrap();
+        let Some(users) = connections.get_mut(&self.room_id) else {
+            return;
+        };
+        users.retain(|user| user.addr != ctx.address());
+
+        // Closing another tab keeps the user online until their last session leaves
+        let still_online = users.iter().any(|user| user.username == self.username);
+        let recipients = self.app_state.recipients(&connections, self.room_id);
+        if !still_online {
+            let event = ServerEvent::Presence {
+                username: self.username.clone(),
+                status: Some(PresenceStatus::Offline),
+                status_text: None,
+            };
+            for user in &recipients {
+                user.send(&event);
+            }
+        } else if self.status_text.is_some() {
+            // The status belonged to this session only
+            let event = ServerEvent::Presence {
+                username: self.username.clone(),
+                status: None,
+                status_text: Some(String::new()),
+            };
+            for user in recipients.iter().filter(|user| user.username != self.username) {
+                user.send(&event);
+            }
+        }
+        if still_online && self.color.is_some() {
+            let event = ServerEvent::Meta {
+                username: self.username.clone(),
+                color: None,
+            };
+            for user in &recipients {
+                user.send(&event);
+            }
+        }
+    }
+}
+
+impl Handler<Disconnect> for WebSocketSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Self::Context) {
+        self.close_reason = Some(msg.reason.clone());
+        ctx.close(Some(ws::CloseReason {
+            code: msg.code,
+            description: Some(msg.reason),
+        }));
+        ctx.stop();
+    }
+}
+
+impl Handler<ServerEvent> for WebSocketSession {
+    type Result = ();
+
+    fn handle(&mut self, event: ServerEvent, ctx: &mut Self::Context) {
+        // Events queued behind a full mailbox are stale; the client has to reconnect and resume
+        if self.send_failures.load(Ordering::Relaxed) >= MAX_SEND_FAILURES {
+            let disconnect = Disconnect {
+                code: ws::CloseCode::Policy,
+                reason: TOO_SLOW_REASON.to_string(),
+            };
+            return <Self as Handler<Disconnect>>::handle(self, disconnect, ctx);
+        }
+        if !self.wants(&event) {
+            return;
+        }
+        if let (Some(paused), ServerEvent::Message(_)) = (&mut self.paused, &event) {
+            paused.missed += 1;
+            if paused.missed <= MAX_HELD_MESSAGES {
+                paused.held.push(event);
+            } else {
+                paused.held.clear();
+            }
+            return;
+        }
+        if let Some(interval) = self.app_state.transient_interval {
+            if transient_key(&event).is_some() {
+                return self.throttle_transient(ctx, event, interval);
+            }
+        }
+        if let ServerEvent::Message(message) = &event {
+            self.note_delivered(Some(message.id));
+        }
+        self.dispatch(ctx, event);
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        if let Some(text) = self.incoming_text(ctx, msg) {
+            let first_frame = !std::mem::replace(&mut self.heard_from, true);
+            if let Ok(text_string) = String::from_utf8(text.to_vec()) {
+                if let Ok(VersionProbe { v: Some(v) }) = serde_json::from_str(&text_string) {
+                    if v.as_u64() != Some(PROTOCOL_VERSION) {
+                        let message = format!("Protocol version {} is not supported, use {}", v, PROTOCOL_VERSION);
+                        return Self::reply_error(ctx, WsErrorCode::UnsupportedVersion, &message);
+                    }
+                }
+                match serde_json::from_str::<ClientEvent>(&text_string) {
+                    Ok(ClientEvent::Message(draft)) => self.send_chat_message(ctx, draft),
+                    Ok(ClientEvent::PurgeMine) => self.purge_mine(ctx),
+                    Ok(ClientEvent::React { message_id, emoji }) => self.react(ctx, message_id, emoji),
+                    Ok(ClientEvent::Unreact { message_id, emoji }) => self.unreact(ctx, message_id, emoji),
+                    Ok(ClientEvent::SetTopic { text }) => self.set_topic(ctx, text),
+                    Ok(ClientEvent::Status { text }) => self.set_status(ctx, text),
+                    Ok(ClientEvent::SetMeta { color }) => self.set_meta(ctx, color),
+                    Ok(ClientEvent::History { limit, after_seq }) => self.send_history(ctx, limit, after_seq),
+                    Ok(ClientEvent::SetBatching { interval_ms }) => self.set_batching(ctx, interval_ms),
+                    Ok(ClientEvent::Dm { to, text }) => self.send_direct_message(ctx, to, text),
+                    Ok(ClientEvent::Seen { message_id }) => self.mark_seen(ctx, message_id),
+                    Ok(ClientEvent::MarkRead { up_to_seq }) => self.mark_read_up_to(ctx, up_to_seq),
+                    Ok(ClientEvent::Pause) => self.pause(),
+                    Ok(ClientEvent::Resume) => self.resume(ctx),
+                    Ok(ClientEvent::Subscribe { events }) => self.subscribe(ctx, events),
+                    Ok(ClientEvent::Template { name }) => self.send_template(ctx, &name),
+                    Ok(ClientEvent::Hello { supports }) => self.hello(ctx, supports, first_frame),
+                    Ok(ClientEvent::Poll { question, options }) => self.start_poll(ctx, question, options),
+                    Ok(ClientEvent::Vote { poll_id, option }) => self.vote(ctx, poll_id, option),
+                    Ok(ClientEvent::ClosePoll { poll_id }) => self.close_poll(ctx, poll_id),
+                    Ok(ClientEvent::ListRooms) => self.list_rooms(ctx),
+                    Ok(ClientEvent::Pin { message_id }) => self.set_pinned(ctx, message_id, true),
+                    Ok(ClientEvent::Unpin { message_id }) => self.set_pinned(ctx, message_id, false),
+                    Ok(ClientEvent::GetMessage { message_id }) => self.get_message(ctx, message_id),
+                    Ok(ClientEvent::Context { message_id, before, after }) => {
+                        self.send_context(ctx, message_id, before, after)
+                    }
+                    Ok(ClientEvent::Reactions { message_id }) => self.reaction_summary(ctx, message_id),
+                    Ok(ClientEvent::RateStatus) => self.rate_status(ctx),
+                    Ok(ClientEvent::Time) => self.time(ctx),
+                    Ok(ClientEvent::GetSettings) => {
+                        let settings = self.app_state.rooms.lock().unwrap().get(&self.room_id).map(Room::settings);
+                        if let Some(settings) = settings {
+                            Self::reply(ctx, &ServerEvent::Settings(settings));
+                        }
+                    }
+                    Ok(ClientEvent::UpdateSettings(update)) => self.update_settings(ctx, update),
+                    Ok(ClientEvent::Forward { message_id, to_room }) => self.forward(ctx, message_id, to_room),
+                    Ok(ClientEvent::RoomSummary { room_id }) => match self.app_state.room_summary(room_id, &self.username) {
+                        Some(summary) => Self::reply(ctx, &ServerEvent::RoomSummary(summary)),
+                        None => Self::reply_error(ctx, WsErrorCode::RoomNotFound, "Room not found"),
+                    },
+                    Ok(ClientEvent::Edit { message_id, text, signature }) => {
+                        self.edit_message(ctx, message_id, text, signature)
+                    }
+                    Ok(ClientEvent::Mute { username, duration_secs }) => {
+                        self.set_muted(ctx, username, Some(duration_secs))
+                    }
+                    Ok(ClientEvent::Unmute { username }) => self.set_muted(ctx, username, None),
+                    Ok(ClientEvent::Typing) => self.typing(),
+                    Ok(ClientEvent::WhoTyping) => self.who_typing(ctx),
+                    Ok(ClientEvent::ClearHistory) => self.clear_history(ctx),
+                    Ok(ClientEvent::Watch { usernames }) => self.watch(ctx, usernames),
+                    Ok(ClientEvent::Leave) => self.leave(ctx),
+                    Ok(ClientEvent::RoomInfo) => {
+                        let room = self.app_state.rooms.lock().unwrap().get(&self.room_id).cloned();
+                        if let Some(room) = room {
+                            Self::reply(ctx, &ServerEvent::RoomInfo { room: Box::new(room) });
+                        }
+                    }
+                    Ok(ClientEvent::Sync) => {
+                        if let Some(state) = self.app_state.sync_state(self.room_id, &self.username) {
+                            Self::reply(ctx, &ServerEvent::Sync(state));
+                        }
+                    }
+                    Ok(ClientEvent::Unread) => {
+                        let counts = self.app_state.unread_counts(&self.username);
+                        Self::reply(ctx, &ServerEvent::Unread { counts });
+                    }
+                    // Unknown event types and plain text are sent as chat messages verbatim,
+                    // except that strict sessions reject unknown types
+                    Ok(ClientEvent::Unknown) if self.strict => {
+                        Self::reply_error(ctx, WsErrorCode::UnknownEvent, "Unknown event type")
+                    }
+                    Ok(ClientEvent::Unknown) => self.send_chat_message(ctx, Draft::plain(text_string)),
+                    Err(err) => match serde_json::from_str::<serde_json::Value>(&text_string) {
+                        Ok(serde_json::Value::Object(_)) => {
+                            Self::reply_error(ctx, WsErrorCode::InvalidEvent, &err.to_string())
+                        }
+                        Err(_) if text_string.trim_start().starts_with('{') => {
+                            Self::reply_error(ctx, WsErrorCode::BadJson, "Malformed JSON event")
+                        }
+                        _ => self.send_chat_message(ctx, Draft::plain(text_string)),
+                    },
+                }
+            } else {
+                Self::reply_error(ctx, WsErrorCode::InvalidUtf8, "Invalid UTF-8 data received.");
+            }
+        }
+    }
+}
+
+// Suggested wait for a client whose address has all its WebSocket slots in use; a slot frees up
+// whenever one of its sessions ends, so there is no exact time to give
+const WS_SLOT_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+// WebSocket handler
+async fn websocket_handler(
+    req: HttpRequest,
+    stream: web::Payload,
+    data: web::Data<Arc<AppState>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    // Upgrades bypass CORS, so the allowlist is enforced here before the handshake
+    let origin = req.headers().get(header::ORIGIN).and_then(|value| value.to_str().ok());
+    if !data.origin_allowed(origin) {
+        log::warn!("Rejected WebSocket upgrade from origin {:?}", origin);
+        return Err(ApiError::forbidden("Origin not allowed").into());
+    }
+
+    let query_string = req.query_string();
+    if query_string.len() > MAX_WS_QUERY_LEN {
+        return Err(ApiError::bad_request("Query string too long").into());
+    }
+    let query_params: HashMap<String, String> = serde_urlencoded::from_str(query_string)
+        .map_err(|_| ApiError::bad_request("Invalid query string"))?;
+
+    let room_id = query_params
+        .get("roomId")
+        .and_then(|id| Uuid::parse_str(id).ok())
+        .ok_or_else(|| ApiError::bad_request("Missing or invalid roomId"))?;
+
+    // Browsers can't set headers on an upgrade, so the token may also come as ?token=. With a
+    // token the name is the account's; only guests pick one, and not one that has an account.
+    let token = auth::bearer_token(&req).or(query_params.get("token").map(String::as_str));
+    let (username, authenticated) = match token {
+        Some(token) => match data.jwt_keys.validate(token) {
+            Ok(claims) => (claims.sub, true),
+            Err(_) => return Err(ApiError::unauthorized("Invalid or expired token").into()),
+        },
+        None => {
+            let username = query_params
+                .get("username")
+                .cloned()
+                .unwrap_or_else(|| "guest".to_string());
+            if data.users.lock().unwrap().contains_key(&username) {
+                return Err(ApiError::unauthorized("This name belongs to an account, log in to use it").into());
+            }
+            // Accounts that predate a blocklist entry keep working; guests can't pick a blocked name
+            if data.username_blocklist.contains(&username.to_lowercase()) {
+                return Err(ApiError::bad_request("This username is not allowed").into());
+            }
+            (username, false)
+        }
+    };
+
+    if let Some(room) = data.rooms.lock().unwrap().get(&room_id) {
+        if room.banned.contains(&username) {
+            return Err(ApiError::forbidden("You are banned from this room").into());
+        }
+        if !room.visible_to(&username) {
+            return Err(ApiError::forbidden("This room is private").into());
+        }
+        if room.archived {
+            return Err(ApiError::forbidden("This room is archived").into());
+        }
+    }
+
+    let replay_history = query_params
+        .get("history")
+        .is_none_or(|value| value != "false");
+
+    let resume_from = query_params
+        .get("resume")
+        .and_then(|token| Uuid::parse_str(token).ok());
+
+    let strict = match query_params.get("strict").map(String::as_str) {
+        Some("true") => true,
+        Some("false") => false,
+        _ => data.strict_events,
+    };
+
+    if data.draining.load(Ordering::Relaxed) {
+        return Err(ApiError::service_unavailable("Server is shutting down").into());
+    }
+
+    let client_ip = data.client_key(&req);
+    // Unlike the slot below this caps how often, not how many: clients reconnecting in a tight
+    // loop are told to back off. Accounts are limited too, so rotating addresses doesn't get
+    // around it; a guest's name proves nothing, and limiting it would let anyone lock it out.
+    let reconnect = data
+        .reconnect_limiter
+        .check_request(&req, &format!("ip:{}", client_ip))
+        .and_then(|()| {
+            if authenticated {
+                data.reconnect_limiter.check_request(&req, &format!("user:{}", username))
+            } else {
+                Ok(())
+            }
+        });
+    if let Err(retry_after) = reconnect {
+        log::warn!("Reconnect rate limited for {} from {}", username, client_ip);
+        return Ok(too_many_requests(retry_after));
+    }
+    match data.acquire_ws_slot(&client_ip) {
+        Ok(()) => {}
+        Err(SlotRefused::PerAddress) => {
+            log::warn!("Too many WebSocket connections from {}", client_ip);
+            return Ok(too_many_requests(WS_SLOT_RETRY_AFTER));
+        }
+        Err(SlotRefused::ServerFull) => {
+            log::warn!("Refused a WebSocket connection, the server is at max_connections");
+            return Err(ApiError::service_unavailable("The server is at its connection limit").into());
+        }
+    }
+
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|agent| agent.chars().take(MAX_USER_AGENT_LEN).collect());
+
+    // Once the session runs, `stopped` gives the slot back; a failed handshake has to do it here
+    let session = WebSocketSession {
+        replay_history,
+        resume_from,
+        user_agent,
+        strict,
+        ..WebSocketSession::new(data.get_ref().clone(), room_id, username, authenticated, client_ip.clone())
+    };
+    let (addr, response) = ws::WsResponseBuilder::new(session, &req, stream)
+        .frame_size(MAX_WS_FRAME_SIZE)
+        .start_with_addr()
+        .inspect_err(|_| data.release_ws_slot(&client_ip))?;
+    // Complements the mailbox check in Connection::send: that one catches a session that can't
+    // keep up with events, this one a client that doesn't read what the session wrote
+    let limit = data.ws_send_buffer;
+    Ok(response.map_body(|_, body| {
+        outbound::bounded(body, limit, move || {
+            log::warn!("Closing slow WebSocket client: more than {} bytes unsent", limit);
+            addr.do_send(Disconnect {
+                code: ws::CloseCode::Policy,
+                reason: TOO_SLOW_REASON.to_string(),
+            });
+        })
+    }))
+}
+
+async fn get_chat_history(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    visible_room(&data.rooms.lock().unwrap(), &room_id, &user)?;
+    Ok(HttpResponse::Ok().json(data.messages.recent(*room_id, usize::MAX)))
+}
+
+// The addresses a proxy says the request came through, nearest hop last. `Forwarded` wins
+// over `X-Forwarded-For` when both are present.
+fn forwarded_chain(http_req: &HttpRequest) -> Vec<IpAddr> {
+    let headers = http_req.headers();
+    if let Some(forwarded) = headers.get(header::FORWARDED).and_then(|value| value.to_str().ok()) {
+        return forwarded
+            .split(',')
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("for").then(|| parse_forwarded_node(value))?
+                })
+            })
+            .collect();
+    }
+    headers
+        .get_all("X-Forwarded-For")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect()
+}
+
+// A `Forwarded` node is an IP, possibly quoted, bracketed (IPv6) and/or with a port
+fn parse_forwarded_node(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Some(rest) = value.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    value.parse().ok().or_else(|| value.rsplit_once(':')?.0.parse().ok())
+}
+
+// Usernames are 3-32 characters of ASCII letters, digits, '_', '-' or '.'
+fn validate_username(username: &str) -> Result<(), ApiError> {
+    let len = username.chars().count();
+    if !(MIN_USERNAME_LEN..=MAX_USERNAME_LEN).contains(&len) {
+        return Err(ApiError::bad_request(format!(
+            "Username must be {}-{} characters long",
+            MIN_USERNAME_LEN, MAX_USERNAME_LEN
+        )));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err(ApiError::bad_request(
+            "Username may only contain letters, digits, '_', '-' and '.'",
+        ));
+    }
+    if username.eq_ignore_ascii_case(SYSTEM_USER) {
+        return Err(ApiError::bad_request("This username is reserved"));
+    }
+    Ok(())
+}
+
+// REST API Handlers
+async fn register(
+    http_req: HttpRequest,
+    data: web::Data<Arc<AppState>>,
+    mut req: web::Json<RegisterRequest>,
+) -> HttpResponse {
+    limits::trim(&mut req.username);
+    log::info!("Incoming register request for {}", req.username);
+
+    if let Err(retry_after) = data.register_limiter.check_request(&http_req, &data.client_key(&http_req)) {
+        log::warn!("Register rate limited for {}", data.client_key(&http_req));
+        return too_many_requests(retry_after);
+    }
+
+    if let Err(err) = data.check_username(&req.username) {
+        return err.error_response();
+    }
+    let public_key = match req.public_key.as_deref().map(auth::parse_public_key).transpose() {
+        Ok(public_key) => public_key,
+        Err(message) => return ApiError::bad_request(message).error_response(),
+    };
+
+    // Hashing is deliberately slow, so it runs off the async workers and before taking the lock
+    let password = req.password.clone();
+    let password_hash = match web::block(move || auth::hash_password(&password)).await {
+        Ok(Ok(hash)) => hash,
+        err => {
+            log::error!("Failed to hash password: {:?}", err.map(|_| ()));
+            return ApiError::internal("Failed to register user").error_response();
+        }
+    };
+
+    let mut users = data.users.lock().unwrap_or_else(|e| {
+        log::error!("Failed to lock users: {:?}", e);
+        panic!("Mutex poisoned");
+    });
+
+    if users.contains_key(&req.username) {
+        log::warn!("User already exists: {}", req.username);
+        return ApiError::conflict("User already exists").error_response();
+    }
+
+    users.insert(
+        req.username.clone(),
+        User {
+            password_hash,
+            roles: HashSet::from([Role::User]),
+            created_at: now_ms(),
+            public_key,
+        },
+    );
+    drop(users);
+    log::info!("User registered successfully: {}", req.username);
+
+    // The default room may have been deleted since startup; then there is nothing to join
+    if let Some(room_id) = data.default_room {
+        if let Some(room) = data.rooms.lock().unwrap().get_mut(&room_id) {
+            room.add_member(&req.username);
+        }
+    }
+
+    HttpResponse::Created().body("User registered successfully")
+}
+
+// Rate limited per client so it can't be used to enumerate accounts cheaply
+async fn username_available(
+    http_req: HttpRequest,
+    data: web::Data<Arc<AppState>>,
+    mut query: web::Query<AvailabilityQuery>,
+) -> HttpResponse {
+    limits::trim(&mut query.username);
+    if let Err(retry_after) = data.availability_limiter.check_request(&http_req, &data.client_key(&http_req)) {
+        return too_many_requests(retry_after);
+    }
+
+    if let Err(err) = data.check_username(&query.username) {
+        return err.error_response();
+    }
+
+    let available = !data.users.lock().unwrap().contains_key(&query.username);
+    HttpResponse::Ok().json(serde_json::json!({ "available": available }))
+}
+
+// Public part of a user record; never includes the password hash
+#[derive(Serialize)]
+struct UserProfile {
+    username: String,
+    created_at: u64,
+    roles: Vec<Role>,
+    online: bool,
+}
+
+async fn get_user(data: web::Data<Arc<AppState>>, username: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let mut profile = {
+        let users = data.users.lock().unwrap();
+        let user = users
+            .get(username.as_str())
+            .ok_or_else(|| ApiError::not_found("User not found"))?;
+        let mut roles: Vec<Role> = user.roles.iter().copied().collect();
+        roles.sort();
+        UserProfile {
+            username: username.into_inner(),
+            created_at: user.created_at,
+            roles,
+            online: false,
+        }
+    };
+    profile.online = data.is_online(&profile.username);
+    Ok(HttpResponse::Ok().json(profile))
+}
+
+// The caller as seen by the server: who the token is for and the rooms they created or joined
+#[derive(Serialize)]
+struct MeResponse {
+    username: String,
+    roles: Vec<Role>,
+    rooms: Vec<Room>,
+}
+
+async fn me(data: web::Data<Arc<AppState>>, user: AuthUser) -> Result<HttpResponse, ApiError> {
+    // A valid token can outlive its account
+    if !data.users.lock().unwrap().contains_key(&user.username) {
+        return Err(ApiError::unauthorized("Unknown user"));
+    }
+    let mut roles: Vec<Role> = user.roles.iter().copied().collect();
+    roles.sort();
+
+    let mut rooms: Vec<Room> = data
+        .rooms
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|room| room.is_member(&user.username))
+        .cloned()
+        .collect();
+    rooms.sort_by_key(|room| room.created_at);
+
+    Ok(HttpResponse::Ok().json(MeResponse {
+        username: user.username,
+        roles,
+        rooms,
+    }))
+}
+
+// The caller's live WebSocket sessions, oldest first
+async fn my_sessions(data: web::Data<Arc<AppState>>, user: AuthUser) -> HttpResponse {
+    let user_sessions = data.user_sessions.lock().unwrap();
+    let sessions: Vec<SessionInfo> = user_sessions
+        .get(&user.username)
+        .into_iter()
+        .flatten()
+        .map(UserSession::info)
+        .collect();
+    HttpResponse::Ok().json(sessions)
+}
+
+async fn end_my_session(
+    data: web::Data<Arc<AppState>>,
+    user: AuthUser,
+    session_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    if !data.disconnect_session(&user.username, *session_id, "Session ended by its user") {
+        return Err(ApiError::not_found("Session not found"));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Serialize)]
+struct MessageRoom {
+    message_id: Uuid,
+    room_id: Uuid,
+}
+
+// Finds the room of a message known only by id, e.g. from a forward or a link. Rooms the
+// caller can't see get the same 404 as unknown ids, so their messages can't be probed for.
+async fn message_room(
+    data: web::Data<Arc<AppState>>,
+    user: AuthUser,
+    message_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let not_found = || ApiError::not_found("Message not found");
+    let room_id = data.messages.room_of(*message_id).ok_or_else(not_found)?;
+    let visible = data
+        .rooms
+        .lock()
+        .unwrap()
+        .get(&room_id)
+        .is_some_and(|room| room.visible_to(&user.username));
+    if !visible || data.messages.get(room_id, *message_id).is_none() {
+        return Err(not_found());
+    }
+    Ok(HttpResponse::Ok().json(MessageRoom {
+        message_id: *message_id,
+        room_id,
+    }))
+}
+
+#[derive(Deserialize)]
+struct DmHistoryQuery {
+    with: String,
+    limit: Option<usize>,
+}
+
+// The caller's conversation with one other user, newest first
+async fn my_dms(
+    data: web::Data<Arc<AppState>>,
+    user: AuthUser,
+    query: web::Query<DmHistoryQuery>,
+) -> HttpResponse {
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT).min(MAX_HISTORY_LIMIT);
+    let dm_history = data.dm_history.lock().unwrap();
+    let messages: Vec<DirectMessage> = dm_history
+        .get(&dm_conversation(&user.username, &query.with))
+        .map(|history| history.iter().rev().take(limit).cloned().collect())
+        .unwrap_or_default();
+    HttpResponse::Ok().json(messages)
+}
+
+async fn login(
+    http_req: HttpRequest,
+    data: web::Data<Arc<AppState>>,
+    mut req: web::Json<LoginRequest>,
+) -> HttpResponse {
+    limits::trim(&mut req.username);
+    // Failures count per username and client address, so guessing from one address can't lock
+    // the owner out everywhere else
+    let lockout_key = format!("{}@{}", req.username, data.client_key(&http_req));
+    // Locked accounts are refused before the password is even looked at
+    if let Err(retry_after) = data.login_lockout.check(&lockout_key) {
+        log::warn!("Login for {} refused, account locked", req.username);
+        return too_many_requests(retry_after);
+    }
+
+    let password_hash = data.users.lock().unwrap().get(&req.username).map(|user| user.password_hash.clone());
+    let verified = match password_hash {
+        Some(password_hash) => {
+            let password = req.password.clone();
+            web::block(move || auth::verify_password(&password, &password_hash))
+                .await
+                .unwrap_or(false)
+        }
+        None => false,
+    };
+    // Unknown usernames count too, so lockouts don't reveal which accounts exist
+    if !verified {
+        data.login_lockout.record_failure(&lockout_key);
+        return ApiError::unauthorized("Invalid username or password").error_response();
+    }
+    data.login_lockout.reset(&lockout_key);
+
+    match data.jwt_keys.issue(&req.username) {
+        Ok(token) => HttpResponse::Ok().json(serde_json::json!({
+            "message": "Login successful",
+            "token": token,
+        })),
+        Err(err) => {
+            log::error!("Failed to issue token: {:?}", err);
+            ApiError::internal("Failed to issue token").error_response()
+        }
+    }
+}
+
+const MAX_ROOMS_PER_CREATOR: usize = 10;
+
+// Everything a new room must satisfy; shared by real and dry-run creation
+fn validate_new_room(
+    rooms: &HashMap<Uuid, Room>,
+    creator: &str,
+    req: &CreateRoomRequest,
+    max_history: usize,
+) -> Result<(), ApiError> {
+    let name = req.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::bad_request("Room name must not be empty"));
+    }
+    check_len("name", name, MAX_ROOM_NAME_LEN).map_err(ApiError::bad_request)?;
+    // Rooms the creator can't see don't count, so a conflict doesn't reveal a private room's name
+    if rooms.values().any(|room| room.visible_to(creator) && room.name.eq_ignore_ascii_case(name)) {
+        return Err(ApiError::conflict("A room with this name already exists"));
+    }
+    if let Some(history_size) = req.history_size {
+        if !(1..=max_history).contains(&history_size) {
+            return Err(ApiError::bad_request(format!(
+                "history_size must be between 1 and {}",
+                max_history
+            )));
+        }
+    }
+    let created = rooms.values().filter(|room| room.creator == creator).count();
+    if created >= MAX_ROOMS_PER_CREATOR {
+        return Err(ApiError::forbidden(format!(
+            "A user can create at most {} rooms",
+            MAX_ROOMS_PER_CREATOR
+        )));
+    }
+    Ok(())
+}
+
+// Fixed forever: changing it would move every slug to a different room id
+const ROOM_SLUG_NAMESPACE: Uuid = Uuid::from_u128(0x6f1c_2a4e_93b7_5d10_8e2f_4c6a_b1d9_0e37);
+const MAX_SLUG_LEN: usize = 64;
+
+// Lowercase letters, digits and inner hyphens, like `team-42`
+fn validate_slug(slug: &str) -> Result<(), ApiError> {
+    let valid = !slug.is_empty()
+        && slug.len() <= MAX_SLUG_LEN
+        && slug.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !slug.starts_with('-')
+        && !slug.ends_with('-');
+    if !valid {
+        return Err(ApiError::bad_request(format!(
+            "slug must be 1 to {} lowercase letters, digits or inner hyphens",
+            MAX_SLUG_LEN
+        )));
+    }
+    Ok(())
+}
+
+// The same slug always gives the same id (UUIDv5), on every server
+fn slug_room_id(slug: &str) -> Uuid {
+    Uuid::new_v5(&ROOM_SLUG_NAMESPACE, slug.as_bytes())
+}
+
+// A fresh id that no existing room uses, so an insert can never overwrite a room.
+// `generate` is Uuid::new_v4 outside of tests.
+fn unused_room_id(rooms: &HashMap<Uuid, Room>, mut generate: impl FnMut() -> Uuid) -> Uuid {
+    loop {
+        let id = generate();
+        if !rooms.contains_key(&id) {
+            return id;
+        }
+    }
+}
+
+// The caller becomes the creator
+async fn create_room(
+    http_req: HttpRequest,
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    req: web::Json<CreateRoomRequest>,
+    query: web::Query<CreateRoomQuery>,
+) -> HttpResponse {
+    // Dry runs don't create anything, so they don't spend the creation budget either
+    if !query.dry_run {
+        if let Err(retry_after) = data.create_room_limiter.check_request(&http_req, &data.client_key(&http_req)) {
+            return too_many_requests(retry_after);
+        }
+    }
+
+    let slug_id = match &req.slug {
+        Some(slug) => match validate_slug(slug) {
+            Ok(()) => Some(slug_room_id(slug)),
+            Err(err) => return err.error_response(),
+        },
+        None => None,
+    };
+
+    let mut rooms = data.rooms.lock().unwrap();
+    // Creating by slug is idempotent: a room that already has the id is returned as it is,
+    // unless the caller can't see it
+    if let Some(id) = slug_id.filter(|id| rooms.contains_key(id)) {
+        return match visible_room(&rooms, &id, &user) {
+            Ok(room) => HttpResponse::Ok().json(room),
+            Err(err) => err.error_response(),
+        };
+    }
+    if data.max_rooms.is_some_and(|max| rooms.len() >= max) {
+        return ApiError::service_unavailable("The server is at its room limit").error_response();
+    }
+    if let Err(err) = validate_new_room(&rooms, &user.username, &req, data.max_history) {
+        return err.error_response();
+    }
+    if query.dry_run {
+        return HttpResponse::Ok().json(serde_json::json!({ "valid": true }));
+    }
+
+    let mut room = Room {
+        id: slug_id.unwrap_or_else(|| unused_room_id(&rooms, Uuid::new_v4)),
+        name: req.name.trim().to_string(),
+        creator: user.username.clone(),
+        users: HashMap::new(),
+        ephemeral: req.ephemeral,
+        topic: String::new(),
+        last_seq: 0,
+        banned: HashSet::new(),
+        history_size: req.history_size.unwrap_or(data.max_history),
+        private: req.private,
+        suppress_duplicates: req.suppress_duplicates,
+        last_messages: HashMap::new(),
+        persistent: req.persistent,
+        last_activity_ms: now_ms(),
+        webhook_url: None,
+        muted: HashMap::new(),
+        typing: HashMap::new(),
+        last_session_end_ms: None,
+        templates: HashMap::new(),
+        arrivals: HashMap::new(),
+        polls: Vec::new(),
+        created_at: now_ms(),
+        require_signatures: req.require_signatures,
+        edit_window_secs: req.edit_window_secs.unwrap_or(data.edit_window_secs),
+        welcome_message: String::new(),
+        archived: false,
+        moderators: HashSet::new(),
+        last_message_at: None,
+        share_read_cursors: false,
+        message_ttl_secs: 0,
+        allowed_message_types: all_message_kinds(),
+    };
+    if req.join {
+        room.add_member(&user.username);
+    }
+    rooms.insert(room.id, room.clone());
+    HttpResponse::Created()
+        .insert_header((header::LOCATION, format!("/rooms/{}", room.id)))
+        .json(room)
+}
+
+#[derive(Deserialize)]
+struct BatchRoomsRequest {
+    ids: Vec<String>,
+}
+
+const MAX_BATCH_ROOM_IDS: usize = 100;
+
+// Several rooms in one call: id -> room, unknown ids and rooms the caller can't see are left out
+async fn batch_rooms(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    req: web::Json<BatchRoomsRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if req.ids.len() > MAX_BATCH_ROOM_IDS {
+        return Err(ApiError::bad_request(format!(
+            "At most {} ids can be fetched at once",
+            MAX_BATCH_ROOM_IDS
+        )));
+    }
+    let ids = req
+        .ids
+        .iter()
+        .map(|id| Uuid::parse_str(id).map_err(|_| ApiError::bad_request(format!("Invalid room id: {:?}", id))))
+        .collect::<Result<Vec<Uuid>, ApiError>>()?;
+
+    let rooms = data.rooms.lock().unwrap();
+    let found: HashMap<Uuid, &Room> = ids
+        .iter()
+        .filter_map(|id| visible_room(&rooms, id, &user).ok().map(|room| (*id, room)))
+        .collect();
+    Ok(HttpResponse::Ok().json(found))
+}
+
+#[derive(Deserialize)]
+struct BatchPresenceRequest {
+    usernames: Vec<String>,
+}
+
+const MAX_BATCH_PRESENCE: usize = 200;
+
+// Presence of many users in one call. Anonymous users who are connected count as online;
+// registered ones who aren't connected are offline.
+async fn batch_presence(
+    data: web::Data<Arc<AppState>>,
+    req: web::Json<BatchPresenceRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if req.usernames.len() > MAX_BATCH_PRESENCE {
+        return Err(ApiError::bad_request(format!(
+            "At most {} usernames can be looked up at once",
+            MAX_BATCH_PRESENCE
+        )));
+    }
+
+    let online: HashSet<&String> = {
+        let user_sessions = data.user_sessions.lock().unwrap();
+        req.usernames.iter().filter(|name| user_sessions.contains_key(*name)).collect()
+    };
+    let users = data.users.lock().unwrap();
+    let statuses: HashMap<&String, PresenceStatus> = req
+        .usernames
+        .iter()
+        .map(|name| {
+            let status = if online.contains(name) {
+                PresenceStatus::Online
+            } else if users.contains_key(name) {
+                PresenceStatus::Offline
+            } else {
+                PresenceStatus::Unknown
+            };
+            (name, status)
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(statuses))
+}
+
+async fn get_room(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let rooms = data.rooms.lock().unwrap();
+    Ok(HttpResponse::Ok().json(visible_room(&rooms, &room_id, &user)?))
+}
+
+const DEFAULT_MEMBERS_PAGE: usize = 50;
+const MAX_MEMBERS_PAGE: usize = 200;
+
+async fn list_members(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+    query: web::Query<MembersQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let rooms = data.rooms.lock().unwrap();
+    let room = visible_room(&rooms, &room_id, &user)?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_MEMBERS_PAGE).min(MAX_MEMBERS_PAGE);
+    let offset = query.offset.unwrap_or(0);
+
+    // Sorted so that consecutive pages are stable
+    let mut members: Vec<&String> = room.users.keys().collect();
+    members.sort();
+
+    Ok(HttpResponse::Ok().json(MembersPage {
+        total: members.len(),
+        limit,
+        offset,
+        members: members.into_iter().skip(offset).take(limit).cloned().collect(),
+    }))
+}
+
+// Every member with their role and whether they're connected, for room sidebars. The creator
+// and moderators are listed even if they never joined as members.
+async fn list_members_full(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let online: HashSet<String> = data
+        .connections
+        .lock()
+        .unwrap()
+        .get(&room_id)
+        .map(|users| users.iter().map(|user| user.username.clone()).collect())
+        .unwrap_or_default();
+
+    let rooms = data.rooms.lock().unwrap();
+    let room = visible_room(&rooms, &room_id, &user)?;
+    let usernames: HashSet<&String> = room
+        .users
+        .keys()
+        .chain(&room.moderators)
+        .chain([&room.creator])
+        .collect();
+    let mut members: Vec<MemberEntry> = usernames
+        .into_iter()
+        .map(|username| MemberEntry {
+            role: if *username == room.creator {
+                MemberRole::Creator
+            } else if room.moderators.contains(username) {
+                MemberRole::Moderator
+            } else {
+                MemberRole::Member
+            },
+            online: online.contains(username),
+            username: username.clone(),
+        })
+        .collect();
+    members.sort_by(|a, b| a.role.cmp(&b.role).then_with(|| a.username.cmp(&b.username)));
+    Ok(HttpResponse::Ok().json(members))
+}
+
+// Creator-only export. Rows are snapshotted under the lock and then streamed in chunks.
+async fn export_members_csv(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let mut members: Vec<(String, u64)> = {
+        let mut rooms = data.rooms.lock().unwrap();
+        let room = creator_room(&mut rooms, &room_id, &user.username)?;
+        room.users.iter().map(|(username, joined_at)| (username.clone(), *joined_at)).collect()
+    };
+    members.sort();
+
+    // Usernames are validated on registration, so they never need CSV quoting
+    let mut chunks = vec!["username,joined_at\n".to_string()];
+    chunks.extend(members.chunks(CSV_ROWS_PER_CHUNK).map(|rows| {
+        rows.iter()
+            .map(|(username, joined_at)| format!("{},{}\n", username, joined_at))
+            .collect::<String>()
+    }));
+    let body = futures_util::stream::iter(
+        chunks
+            .into_iter()
+            .map(|chunk| Ok::<_, actix_web::Error>(web::Bytes::from(chunk))),
+    );
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"members-{}.csv\"", room_id),
+        ))
+        .streaming(body))
+}
+
+const CSV_ROWS_PER_CHUNK: usize = 500;
+
+async fn list_pinned(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    visible_room(&data.rooms.lock().unwrap(), &room_id, &user)?;
+
+    let pinned: Vec<ChatMessage> = data
+        .messages
+        .recent(*room_id, usize::MAX)
+        .into_iter()
+        .filter(|message| message.pinned)
+        .collect();
+    Ok(HttpResponse::Ok().json(pinned))
+}
+
+const DEFAULT_LEADERBOARD: usize = 10;
+const MAX_LEADERBOARD: usize = 100;
+
+// Top senders among the room's stored history; deleted messages don't count
+async fn room_leaderboard(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+    query: web::Query<LeaderboardQuery>,
+) -> Result<HttpResponse, ApiError> {
+    visible_room(&data.rooms.lock().unwrap(), &room_id, &user)?;
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for message in data.messages.recent(*room_id, usize::MAX) {
+        if !message.deleted {
+            *counts.entry(message.username).or_default() += 1;
+        }
+    }
+    let mut leaderboard: Vec<LeaderboardEntry> = counts
+        .into_iter()
+        .map(|(username, messages)| LeaderboardEntry { username, messages })
+        .collect();
+    leaderboard.sort_by(|a, b| b.messages.cmp(&a.messages).then_with(|| a.username.cmp(&b.username)));
+    leaderboard.truncate(query.limit.unwrap_or(DEFAULT_LEADERBOARD).min(MAX_LEADERBOARD));
+    Ok(HttpResponse::Ok().json(leaderboard))
+}
+
+// Most used emoji among the room's stored history, counting each user's reaction once
+async fn top_reactions(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+    query: web::Query<LeaderboardQuery>,
+) -> Result<HttpResponse, ApiError> {
+    visible_room(&data.rooms.lock().unwrap(), &room_id, &user)?;
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for message in data.messages.recent(*room_id, usize::MAX) {
+        if !message.deleted {
+            for (emoji, users) in message.reactions {
+                *counts.entry(emoji).or_default() += users.len();
+            }
+        }
+    }
+    let mut top: Vec<ReactionCount> = counts
+        .into_iter()
+        .map(|(emoji, count)| ReactionCount { emoji, count })
+        .collect();
+    top.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.emoji.cmp(&b.emoji)));
+    top.truncate(query.limit.unwrap_or(DEFAULT_LEADERBOARD).min(MAX_LEADERBOARD));
+    Ok(HttpResponse::Ok().json(top))
+}
+
+// Looks up a room the caller must have created: 404 if it doesn't exist, 403 if it isn't theirs
+fn creator_room<'a>(
+    rooms: &'a mut HashMap<Uuid, Room>,
+    room_id: &Uuid,
+    username: &str,
+) -> Result<&'a mut Room, ApiError> {
+    let room = rooms
+        .get_mut(room_id)
+        .filter(|room| room.visible_to(username))
+        .ok_or_else(|| ApiError::not_found("Room not found"))?;
+    if room.creator != username {
+        return Err(ApiError::forbidden("Only the room creator can do this"));
+    }
+    Ok(room)
+}
+
+// Looks up a room the caller may see. Private rooms they aren't a member of get the same 404
+// as unknown ids, so that their existence can't be probed for.
+fn visible_room<'a>(rooms: &'a HashMap<Uuid, Room>, room_id: &Uuid, user: &AuthUser) -> Result<&'a Room, ApiError> {
+    rooms
+        .get(room_id)
+        .filter(|room| room.visible_to(&user.username))
+        .ok_or_else(|| ApiError::not_found("Room not found"))
+}
+
+// Looks up a room the caller may moderate: its creator and moderators, or any global
+// moderator or admin. A private room the caller can't see is a 404, as in `visible_room`.
+fn moderated_room<'a>(
+    rooms: &'a mut HashMap<Uuid, Room>,
+    room_id: &Uuid,
+    user: &AuthUser,
+) -> Result<&'a mut Room, ApiError> {
+    let room = rooms
+        .get_mut(room_id)
+        .filter(|room| user.is_moderator() || room.visible_to(&user.username))
+        .ok_or_else(|| ApiError::not_found("Room not found"))?;
+    if !room.can_moderate(&user.username) && !user.is_moderator() {
+        return Err(ApiError::forbidden("Only the room creator or a moderator can do this"));
+    }
+    Ok(room)
+}
+
+#[derive(Deserialize, Serialize)]
+struct Template {
+    name: String,
+    text: String,
+}
+
+const MAX_TEMPLATES_PER_ROOM: usize = 50;
+
+// Creates or replaces a canned reply; moderators send it with a "template" event
+async fn set_template(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+    req: web::Json<Template>,
+) -> Result<HttpResponse, ApiError> {
+    let mut template = req.into_inner();
+    limits::trim(&mut template.name);
+    if template.name.is_empty() || template.name.contains(char::is_whitespace) {
+        return Err(ApiError::bad_request("Template names must be non-empty and without spaces"));
+    }
+    check_len("name", &template.name, MAX_TEMPLATE_NAME_LEN).map_err(ApiError::bad_request)?;
+    prepare_text(&mut template.text, false).map_err(ApiError::bad_request)?;
+    if template.text.is_empty() {
+        return Err(ApiError::bad_request("Template text must not be empty"));
+    }
+
+    let mut rooms = data.rooms.lock().unwrap();
+    let room = moderated_room(&mut rooms, &room_id, &user)?;
+    let replaced = room.templates.contains_key(&template.name);
+    if !replaced && room.templates.len() >= MAX_TEMPLATES_PER_ROOM {
+        return Err(ApiError::conflict(format!(
+            "A room can have at most {} templates",
+            MAX_TEMPLATES_PER_ROOM
+        )));
+    }
+    room.templates.insert(template.name.clone(), template.text.clone());
+    let mut response = if replaced { HttpResponse::Ok() } else { HttpResponse::Created() };
+    Ok(response.json(template))
+}
+
+async fn list_templates(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let mut rooms = data.rooms.lock().unwrap();
+    let room = moderated_room(&mut rooms, &room_id, &user)?;
+    let mut templates: Vec<Template> = room
+        .templates
+        .iter()
+        .map(|(name, text)| Template {
+            name: name.clone(),
+            text: text.clone(),
+        })
+        .collect();
+    templates.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(HttpResponse::Ok().json(templates))
+}
+
+async fn delete_template(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    path: web::Path<(Uuid, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let (room_id, name) = path.into_inner();
+    let mut rooms = data.rooms.lock().unwrap();
+    let room = moderated_room(&mut rooms, &room_id, &user)?;
+    if room.templates.remove(&name).is_none() {
+        return Err(ApiError::not_found("Template not found"));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+struct ModeratorRequest {
+    username: String,
+}
+
+// Creator only: lets a registered user moderate the room
+async fn add_moderator(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+    req: web::Json<ModeratorRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if !data.users.lock().unwrap().contains_key(&req.username) {
+        return Err(ApiError::not_found("User not found"));
+    }
+    let mut rooms = data.rooms.lock().unwrap();
+    let room = creator_room(&mut rooms, &room_id, &user.username)?;
+    if req.username == room.creator {
+        return Err(ApiError::bad_request("The room creator already moderates the room"));
+    }
+    room.moderators.insert(req.username.clone());
+    log::info!("{} made {} a moderator of room {}", user.username, req.username, room_id);
+    let mut moderators: Vec<&String> = room.moderators.iter().collect();
+    moderators.sort();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "moderators": moderators })))
+}
+
+async fn remove_moderator(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    path: web::Path<(Uuid, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let (room_id, username) = path.into_inner();
+    let mut rooms = data.rooms.lock().unwrap();
+    let room = creator_room(&mut rooms, &room_id, &user.username)?;
+    if !room.moderators.remove(&username) {
+        return Err(ApiError::not_found("Not a moderator of this room"));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Removes a member and closes their sessions; unlike a ban they may come back
+async fn kick_user(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+    req: web::Json<BanRequest>,
+) -> Result<HttpResponse, ApiError> {
+    {
+        let mut rooms = data.rooms.lock().unwrap();
+        let room = moderated_room(&mut rooms, &room_id, &user)?;
+        if req.username == room.creator {
+            return Err(ApiError::bad_request("The room creator can't be kicked"));
+        }
+        check_outranks(room, &user, &req.username)?;
+        room.users.remove(&req.username);
+    }
+
+    let closed = data.disconnect_in_room(*room_id, &req.username, "Kicked from the room");
+    log::info!("{} kicked {} from room {}", user.username, req.username, room_id);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "username": req.username, "sessions_closed": closed })))
+}
+
+// A room moderator can't kick or ban another one; that takes the creator (who appointed them
+// and can also remove them) or a global moderator or admin
+fn check_outranks(room: &Room, user: &AuthUser, target: &str) -> Result<(), ApiError> {
+    if room.moderators.contains(target) && room.creator != user.username && !user.is_moderator() {
+        return Err(ApiError::forbidden("Only the room creator can do this to a moderator"));
+    }
+    Ok(())
+}
+
+// Bans a user from the room: they lose membership, are disconnected and can't reconnect
+async fn ban_user(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+    req: web::Json<BanRequest>,
+) -> Result<HttpResponse, ApiError> {
+    {
+        let mut rooms = data.rooms.lock().unwrap();
+        let room = moderated_room(&mut rooms, &room_id, &user)?;
+        if req.username == room.creator {
+            return Err(ApiError::bad_request("The room creator can't be banned"));
+        }
+        check_outranks(room, &user, &req.username)?;
+        room.users.remove(&req.username);
+        room.moderators.remove(&req.username);
+        room.banned.insert(req.username.clone());
+    }
+
+    data.disconnect_in_room(*room_id, &req.username, "Banned from the room");
+    log::info!("{} banned {} from room {}", user.username, req.username, room_id);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "username": req.username, "banned": true })))
+}
+
+async fn unban_user(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+    req: web::Json<BanRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let mut rooms = data.rooms.lock().unwrap();
+    let room = moderated_room(&mut rooms, &room_id, &user)?;
+    room.banned.remove(&req.username);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "username": req.username, "banned": false })))
+}
+
+async fn clear_room_history(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    {
+        let mut rooms = data.rooms.lock().unwrap();
+        moderated_room(&mut rooms, &room_id, &user)?;
+    }
+
+    data.clear_history(*room_id);
+    log::info!("{} cleared the history of room {}", user.username, room_id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn report_message(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+    req: web::Json<ReportRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let reason = req.reason.trim();
+    if reason.is_empty() {
+        return Err(ApiError::bad_request("A reason is required"));
+    }
+    check_len("reason", reason, MAX_REPORT_REASON_LEN).map_err(ApiError::bad_request)?;
+    {
+        let rooms = data.rooms.lock().unwrap();
+        let room = visible_room(&rooms, &room_id, &user)?;
+        if !room.is_member(&user.username) {
+            return Err(ApiError::forbidden("Only room members can report messages"));
+        }
+    }
+    if data.messages.get(*room_id, req.message_id).is_none() {
+        return Err(ApiError::not_found("Message not found"));
+    }
+
+    let report = Report {
+        id: Uuid::new_v4(),
+        message_id: req.message_id,
+        reporter: user.username,
+        reason: reason.to_string(),
+        created_at: now_ms(),
+    };
+    log::info!("{} reported message {} in room {}", report.reporter, report.message_id, room_id);
+    data.reports.lock().unwrap().entry(*room_id).or_default().push(report.clone());
+    Ok(HttpResponse::Created().json(report))
+}
+
+async fn list_reports(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    {
+        let mut rooms = data.rooms.lock().unwrap();
+        moderated_room(&mut rooms, &room_id, &user)?;
+    }
+    let reports = data.reports.lock().unwrap().get(&room_id).cloned().unwrap_or_default();
+    Ok(HttpResponse::Ok().json(reports))
+}
+
+async fn set_webhook(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+    req: web::Json<WebhookRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let url = webhook::validate_url(&req.url).map_err(ApiError::bad_request)?;
+    webhook::resolve_externally(&url).await.map_err(ApiError::bad_request)?;
+
+    let mut rooms = data.rooms.lock().unwrap();
+    let room = creator_room(&mut rooms, &room_id, &user.username)?;
+    room.webhook_url = Some(url.clone());
+    data.webhooks.reset(*room_id);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "url": url.as_str() })))
+}
+
+// The creator's view of the webhook: its URL and whether deliveries are paused
+async fn get_webhook(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let mut rooms = data.rooms.lock().unwrap();
+    let room = creator_room(&mut rooms, &room_id, &user.username)?;
+    let url = room.webhook_url.as_ref().map(Url::as_str);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "url": url,
+        "breaker": data.webhooks.status(*room_id),
+    })))
+}
+
+// Creator only. Connected sessions stay open but can no longer post; they learn about it
+// from the settings frame.
+async fn set_archived(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: Uuid,
+    archived: bool,
+) -> Result<HttpResponse, ApiError> {
+    let settings = {
+        let mut rooms = data.rooms.lock().unwrap();
+        let room = creator_room(&mut rooms, &room_id, &user.username)?;
+        room.archived = archived;
+        room.settings()
+    };
+    log::info!("{} set archived={} on room {}", user.username, archived, room_id);
+    data.broadcast(room_id, &ServerEvent::Settings(settings));
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "room_id": room_id, "archived": archived })))
+}
+
+async fn archive_room(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    set_archived(user, data, room_id.into_inner(), true).await
+}
+
+async fn unarchive_room(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    set_archived(user, data, room_id.into_inner(), false).await
+}
+
+async fn delete_webhook(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let mut rooms = data.rooms.lock().unwrap();
+    let room = creator_room(&mut rooms, &room_id, &user.username)?;
+    room.webhook_url = None;
+    data.webhooks.reset(*room_id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn list_banned(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let mut rooms = data.rooms.lock().unwrap();
+    let room = moderated_room(&mut rooms, &room_id, &user)?;
+    let mut banned: Vec<&String> = room.banned.iter().collect();
+    banned.sort();
+    Ok(HttpResponse::Ok().json(banned))
+}
+
+const MAX_SCHEDULE_AHEAD_MS: u64 = 30 * 24 * 60 * 60 * 1000;
+
+async fn schedule_message(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+    http_req: HttpRequest,
+    mut req: web::Json<ScheduleRequest>,
+) -> Result<HttpResponse, ApiError> {
+    // Scheduling counts against the same per-user budget a live session has, so it can't be
+    // used to queue up a flood
+    if let Err(retry_after) = data.schedule_limiter.check_request(&http_req, &user.username) {
+        return Ok(too_many_requests(retry_after));
+    }
+    let signed = req.signature.is_some();
+    prepare_text(&mut req.text, signed).map_err(ApiError::bad_request)?;
+    if let Some(word) = data.wordlist.find_in(&req.text) {
+        return Err(ApiError::bad_request(format!("The message contains a blocked word: {}", word)));
+    }
+    let now = now_ms();
+    if req.send_at <= now {
+        return Err(ApiError::bad_request("send_at must be in the future"));
+    }
+    if req.send_at - now > MAX_SCHEDULE_AHEAD_MS {
+        return Err(ApiError::bad_request("send_at is too far in the future"));
+    }
+    visible_room(&data.rooms.lock().unwrap(), &room_id, &user)?;
+
+    let id = Uuid::new_v4();
+    let req = req.into_inner();
+    data.scheduled.lock().unwrap().insert(
+        id,
+        ScheduledMessage {
+            room_id: *room_id,
+            username: user.username,
+            text: req.text,
+            format: req.format,
+            signature: req.signature,
+        },
+    );
+
+    let state = data.get_ref().clone();
+    let delay = Duration::from_millis(req.send_at - now);
+    actix_web::rt::spawn(async move {
+        actix_web::rt::time::sleep(delay).await;
+        // Gone if it was cancelled in the meantime
+        let Some(message) = state.scheduled.lock().unwrap().remove(&id) else {
+            return;
+        };
+        let draft = Draft {
+            text: message.text,
+            format: message.format,
+            signature: message.signature,
+            attachment: None,
+            ttl_secs: None,
+            forwarded_from: None,
+        };
+        if let Err(event) = state.post_message(message.room_id, &message.username, draft) {
+            log::info!(
+                "Scheduled message {} by {} was rejected: {}",
+                id,
+                message.username,
+                serde_json::to_string(&event).unwrap_or_default()
+            );
+        }
+    });
+
+    Ok(HttpResponse::Created().json(serde_json::json!({ "id": id, "send_at": req.send_at })))
 }
 
-#[derive(Default)]
-struct AppState {
-    users: Mutex<HashMap<String, String>>,               // username -> password
-    rooms: Mutex<HashMap<Uuid, Room>>,                  // room_id -> Room
-    connections: Mutex<HashMap<Uuid, Vec<Addr<WebSocketSession>>>>, // room_id -> WebSocket connections
+// Only whoever scheduled the message can cancel it
+async fn cancel_scheduled(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    path: web::Path<(Uuid, Uuid)>,
+) -> Result<HttpResponse, ApiError> {
+    let (room_id, id) = path.into_inner();
+    let mut scheduled = data.scheduled.lock().unwrap();
+    match scheduled.get(&id) {
+        Some(message) if message.room_id == room_id => {
+            if message.username != user.username {
+                return Err(ApiError::forbidden("Only the author can cancel a scheduled message"));
+            }
+            scheduled.remove(&id);
+            Ok(HttpResponse::NoContent().finish())
+        }
+        _ => Err(ApiError::not_found("Scheduled message not found")),
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct RegisterRequest {
-    username: String,
-    password: String,
+const DEFAULT_INVITE_TTL_SECS: u64 = 24 * 60 * 60;
+const MAX_INVITE_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+async fn create_invite(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+    req: web::Json<CreateInviteRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let ttl_secs = req.ttl_secs.unwrap_or(DEFAULT_INVITE_TTL_SECS);
+    if ttl_secs == 0 || ttl_secs > MAX_INVITE_TTL_SECS {
+        return Err(ApiError::bad_request(format!(
+            "ttl_secs must be between 1 and {}",
+            MAX_INVITE_TTL_SECS
+        )));
+    }
+
+    {
+        let mut rooms = data.rooms.lock().unwrap();
+        creator_room(&mut rooms, &room_id, &user.username)?;
+    }
+
+    let now = now_ms();
+    let token = Uuid::new_v4();
+    let expires_at = now + ttl_secs * 1000;
+    let mut invites = data.invites.lock().unwrap();
+    // Expired invites are dropped here so the map doesn't grow without bound
+    invites.retain(|_, invite| invite.expires_at > now);
+    invites.insert(
+        token,
+        Invite {
+            room_id: *room_id,
+            single_use: req.single_use,
+            expires_at,
+        },
+    );
+    Ok(HttpResponse::Created().json(InviteCreated {
+        invite_token: token,
+        expires_at,
+    }))
 }
 
-#[derive(Deserialize)]
-struct LoginRequest {
-    username: String,
-    password: String,
+// Adds the authenticated user to the room an invite points to; single-use invites are consumed
+async fn join_room(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    query: web::Query<JoinQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let mut invites = data.invites.lock().unwrap();
+    let invite = invites
+        .get(&query.invite)
+        .ok_or_else(|| ApiError::not_found("Invite not found or already used"))?;
+    if invite.expires_at <= now_ms() {
+        invites.remove(&query.invite);
+        return Err(ApiError::new(StatusCode::GONE, "invite_expired", "Invite has expired"));
+    }
+
+    let mut rooms = data.rooms.lock().unwrap();
+    let room = rooms
+        .get_mut(&invite.room_id)
+        .ok_or_else(|| ApiError::not_found("Room not found"))?;
+    if room.banned.contains(&user.username) {
+        return Err(ApiError::forbidden("You are banned from this room"));
+    }
+    room.add_member(&user.username);
+    // Only consumed once it has actually let someone in
+    if invite.single_use {
+        invites.remove(&query.invite);
+    }
+    Ok(HttpResponse::Ok().json(room.clone()))
 }
 
-#[derive(Deserialize)]
-struct CreateRoomRequest {
-    name: String,
-    creator: String,
+#[derive(Serialize)]
+struct AddUserResponse {
+    #[serde(flatten)]
+    room: Room,
+    added: bool, // false if they already were a member
 }
 
-#[derive(Deserialize)]
-struct AddUserRequest {
-    room_id: Uuid,
-    username: String,
+// Creator or moderator only; anyone else joins through an invite. Only registered users can
+// become members, guests just connect under a name. When several checks fail, the first of
+// these is reported: unknown room (404), caller can't moderate it (403), unknown user (404),
+// banned from the room (403).
+async fn add_user(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    req: web::Json<AddUserRequest>,
+) -> Result<HttpResponse, ApiError> {
+    moderated_room(&mut data.rooms.lock().unwrap(), &req.room_id, &user)?;
+    if !data.users.lock().unwrap().contains_key(&req.username) {
+        return Err(ApiError::not_found("User not found"));
+    }
+
+    // users and rooms are never held together, so the room may have gone in between
+    let mut rooms = data.rooms.lock().unwrap();
+    let room = moderated_room(&mut rooms, &req.room_id, &user)?;
+    if room.banned.contains(&req.username) {
+        return Err(ApiError::forbidden("User is banned from this room"));
+    }
+    let added = room.add_member(&req.username);
+    Ok(HttpResponse::Ok().json(AddUserResponse { room: room.clone(), added }))
 }
 
-#[derive(Deserialize, Message, Clone, Serialize)]
-#[rtype(result = "()")]
-struct ChatMessage {
+#[derive(Serialize)]
+struct RoomIdle {
     room_id: Uuid,
+    idle_secs: u64,                    // since creation or the latest message, as room GC counts it
+    message_idle_secs: Option<u64>,    // None if nothing was ever posted
+    connection_idle_secs: Option<u64>, // 0 while someone is connected; None if nobody ever was
+    connections: usize,
+}
+
+// How long the room has gone without activity, for tuning ROOM_GC_TTL_SECS
+async fn room_idle(
+    user: AuthUser,
+    data: web::Data<Arc<AppState>>,
+    room_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let connections = data.connections.lock().unwrap().get(&room_id).map_or(0, Vec::len);
+    let rooms = data.rooms.lock().unwrap();
+    let room = visible_room(&rooms, &room_id, &user)?;
+
+    let now = now_ms();
+    let secs_since = |at: u64| now.saturating_sub(at) / 1000;
+    let connection_idle_secs = if connections > 0 {
+        Some(0)
+    } else {
+        room.last_session_end_ms.map(secs_since)
+    };
+    Ok(HttpResponse::Ok().json(RoomIdle {
+        room_id: room.id,
+        idle_secs: secs_since(room.last_activity_ms),
+        message_idle_secs: room.last_message_at.map(secs_since),
+        connection_idle_secs,
+        connections,
+    }))
+}
+
+const DEFAULT_ACTIVE_ROOMS: usize = 20;
+const MAX_ACTIVE_ROOMS: usize = 100;
+
+// Public rooms that have had messages, most recent first
+async fn active_rooms(data: web::Data<Arc<AppState>>, query: web::Query<ActiveRoomsQuery>) -> HttpResponse {
+    let limit = query.limit.unwrap_or(DEFAULT_ACTIVE_ROOMS).min(MAX_ACTIVE_ROOMS);
+    let rooms = data.rooms.lock().unwrap();
+    let mut active: Vec<&Room> = rooms
+        .values()
+        .filter(|room| !room.private && !room.archived && room.last_message_at.is_some())
+        .collect();
+    active.sort_by(|a, b| b.last_message_at.cmp(&a.last_message_at).then_with(|| a.name.cmp(&b.name)));
+    active.truncate(limit);
+    HttpResponse::Ok().json(active)
+}
+
+async fn list_rooms(data: web::Data<Arc<AppState>>, query: web::Query<ListRoomsQuery>) -> HttpResponse {
+    let rooms = data.rooms.lock().unwrap();
+    let room_list: Vec<_> = rooms
+        .values()
+        .filter(|room| !room.private && (query.include_archived || !room.archived))
+        .cloned()
+        .collect();
+    HttpResponse::Ok().json(room_list)
+}
+
+#[derive(Deserialize)]
+struct GrantRoleRequest {
     username: String,
-    message: String,
+    role: Role,
 }
 
-// WebSocket Session
-struct WebSocketSession {
-    room_id: Uuid,
+async fn grant_role(
+    _admin: AdminAuth,
+    data: web::Data<Arc<AppState>>,
+    req: web::Json<GrantRoleRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let mut users = data.users.lock().unwrap();
+    let user = users
+        .get_mut(&req.username)
+        .ok_or_else(|| ApiError::not_found("User not found"))?;
+    user.roles.insert(req.role);
+    log::info!("Granted the {} role to {}", req.role.as_str(), req.username);
+
+    let mut roles: Vec<Role> = user.roles.iter().copied().collect();
+    roles.sort();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "username": req.username, "roles": roles })))
+}
+
+// Bumped whenever a change to the export format would make older exports import wrongly
+const EXPORT_VERSION: u32 = 1;
+
+// A backup of everything but live state: connections, read markers, invites, DMs and
+// scheduled messages are left out
+#[derive(Serialize, Deserialize)]
+struct StateExport {
+    version: u32,
+    exported_at: u64, // epoch ms
+    users: Vec<UserExport>,
+    rooms: Vec<RoomExport>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    messages: Option<HashMap<Uuid, Vec<ChatMessage>>>, // room_id -> history, oldest first
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserExport {
     username: String,
-    app_state: Arc<AppState>,
+    password_hash: String, // argon2 PHC string; passwords themselves are never stored
+    roles: HashSet<Role>,
+    created_at: u64,
+    public_key: Option<String>, // base64, as registered
 }
 
-impl Actor for WebSocketSession {
-    type Context = ws::WebsocketContext<Self>;
+// The room as the API shows it, plus the parts it keeps to itself
+#[derive(Serialize, Deserialize)]
+struct RoomExport {
+    #[serde(flatten)]
+    room: Room,
+    members: HashMap<String, u64>,
+    banned: HashSet<String>,
+    webhook_url: Option<String>,
+    #[serde(default)]
+    templates: HashMap<String, String>,
+}
 
-    fn started(&mut self, ctx: &mut Self::Context) {
-        let mut connections = self.app_state.connections.lock().unwrap();
-        connections
-            .entry(self.room_id)
-            .or_insert_with(Vec::new)
-            .push(ctx.address());
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    messages: bool, // include every room's stored history
+}
+
+async fn export_state(
+    _admin: AdminAuth,
+    data: web::Data<Arc<AppState>>,
+    query: web::Query<ExportQuery>,
+) -> HttpResponse {
+    let users = data
+        .users
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(username, user)| UserExport {
+            username: username.clone(),
+            password_hash: user.password_hash.clone(),
+            roles: user.roles.clone(),
+            created_at: user.created_at,
+            public_key: user.public_key.map(|key| BASE64.encode(key.as_bytes())),
+        })
+        .collect();
+    let rooms: Vec<RoomExport> = data
+        .rooms
+        .lock()
+        .unwrap()
+        .values()
+        .map(|room| RoomExport {
+            room: room.clone(),
+            members: room.users.clone(),
+            banned: room.banned.clone(),
+            webhook_url: room.webhook_url.as_ref().map(Url::to_string),
+            templates: room.templates.clone(),
+        })
+        .collect();
+    let messages = query.messages.then(|| {
+        rooms
+            .iter()
+            .map(|export| (export.room.id, data.messages.recent(export.room.id, usize::MAX)))
+            .collect()
+    });
+
+    HttpResponse::Ok().json(StateExport {
+        version: EXPORT_VERSION,
+        exported_at: now_ms(),
+        users,
+        rooms,
+        messages,
+    })
+}
+
+// Replaces all users and rooms with the export's, dropping reports, read markers, invites and
+// scheduled messages. Only allowed while nobody is connected, since live sessions would be left
+// in rooms that may no longer exist. Without exported messages the history of every room starts empty.
+async fn import_state(
+    _admin: AdminAuth,
+    data: web::Data<Arc<AppState>>,
+    req: web::Json<StateExport>,
+) -> Result<HttpResponse, ApiError> {
+    let export = req.into_inner();
+    if export.version != EXPORT_VERSION {
+        return Err(ApiError::bad_request(format!(
+            "Unsupported export version {}, this server reads version {}",
+            export.version, EXPORT_VERSION
+        )));
     }
 
-    fn stopped(&mut self, ctx: &mut Self::Context) {
-        let mut connections = self.app_state.connections.lock().unwrap();
-        if let Some(users) = connections.get_mut(&self.room_id) {
-            users.retain(|addr| addr != &ctx.address());
-        }
+    let mut users = HashMap::new();
+    for user in export.users {
+        let public_key = user
+            .public_key
+            .as_deref()
+            .map(auth::parse_public_key)
+            .transpose()
+            .map_err(|err| ApiError::bad_request(format!("User {}: {}", user.username, err)))?;
+        let account = User {
+            password_hash: user.password_hash,
+            roles: user.roles,
+            created_at: user.created_at,
+            public_key,
+        };
+        users.insert(user.username, account);
+    }
+    let mut rooms = HashMap::new();
+    for export in export.rooms {
+        let mut room = export.room;
+        room.users = export.members;
+        room.banned = export.banned;
+        room.templates = export.templates;
+        room.webhook_url = export
+            .webhook_url
+            .as_deref()
+            .map(webhook::validate_url)
+            .transpose()
+            .map_err(|err| ApiError::bad_request(format!("Room {}: {}", room.id, err)))?;
+        room.last_activity_ms = now_ms();
+        rooms.insert(room.id, room);
     }
-}
 
-impl Handler<ChatMessage> for WebSocketSession {
-    type Result = ();
+    let (user_count, room_count) = (users.len(), rooms.len());
+    let replaced: Vec<Uuid> = {
+        // Taken before the rooms, like join_room does
+        let mut invites = data.invites.lock().unwrap();
+        let connections = data.connections.lock().unwrap();
+        if connections.values().any(|users| !users.is_empty()) {
+            return Err(ApiError::conflict("Disconnect all WebSocket sessions before importing"));
+        }
+        let mut current = data.rooms.lock().unwrap();
+        let replaced = current.keys().copied().collect();
+        *current = rooms;
+        // Everything else keyed by the old rooms and users goes with them; clearing `scheduled`
+        // also cancels the pending sends
+        data.reports.lock().unwrap().clear();
+        data.read_markers.lock().unwrap().clear();
+        invites.clear();
+        data.expiries.lock().unwrap().clear();
+        data.scheduled.lock().unwrap().clear();
+        replaced
+    };
+    *data.users.lock().unwrap() = users;
 
-    fn handle(&mut self, msg: ChatMessage, ctx: &mut Self::Context) {
-        if msg.room_id == self.room_id {
-            ctx.text(msg.message);
+    for room_id in replaced {
+        data.messages.clear(room_id);
+    }
+    let mut message_count = 0;
+    let capacities: HashMap<Uuid, usize> = data
+        .rooms
+        .lock()
+        .unwrap()
+        .values()
+        .map(|room| (room.id, room.history_size))
+        .collect();
+    for (room_id, history) in export.messages.unwrap_or_default() {
+        let Some(&capacity) = capacities.get(&room_id) else {
+            continue; // history of a room the export doesn't contain
+        };
+        data.messages.clear(room_id);
+        for message in history.into_iter().filter(|message| message.room_id == room_id) {
+            if let Some(expires_at) = message.expires_at {
+                data.schedule_expiry(expires_at, room_id, message.id);
+            }
+            data.messages.store(message, capacity);
+            message_count += 1;
         }
     }
+    log::info!(
+        "Imported {} users, {} rooms and {} messages",
+        user_count,
+        room_count,
+        message_count
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "users": user_count,
+        "rooms": room_count,
+        "messages": message_count,
+    })))
 }
 
-impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketSession {
-    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        if let Ok(ws::Message::Text(text)) = msg {
-            if let Ok(text_string) = String::from_utf8(text.as_bytes().to_vec()) {
-                let connections = self.app_state.connections.lock().unwrap();
-                if let Some(users) = connections.get(&self.room_id) {
-                    let chat_message = ChatMessage {
-                        room_id: self.room_id,
-                        username: self.username.clone(),
-                        message: text_string.clone(),
-                    };
-
-                    // Broadcast the message to all users in the room
-                    for user in users {
-                        user.do_send(chat_message.clone());
-                    }
+#[derive(Deserialize)]
+struct DebugBroadcastRequest {
+    room_id: Uuid,
+    event_json: serde_json::Value, // an object with a string "type", sent as is
+}
 
-                    // Add the message to the room's history
-                    let mut rooms = self.app_state.rooms.lock().unwrap();
-                    if let Some(room) = rooms.get_mut(&self.room_id) {
-                        room.history.push(chat_message);
-                    }
-                }
-            } else {
-                ctx.text("Invalid UTF-8 data received.");
-            }
-        }
+// Test hook for client developers: delivers any event, including ones the server would
+// rarely or never produce, to everyone connected to the room. Only routed when
+// DEBUG_ENDPOINTS is set, so it is a plain 404 otherwise.
+async fn debug_broadcast(
+    _admin: AdminAuth,
+    data: web::Data<Arc<AppState>>,
+    req: web::Json<DebugBroadcastRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let req = req.into_inner();
+    if !data.rooms.lock().unwrap().contains_key(&req.room_id) {
+        return Err(ApiError::not_found("Room not found"));
+    }
+    let serde_json::Value::Object(event) = req.event_json else {
+        return Err(ApiError::bad_request("event_json must be an object"));
+    };
+    if !event.get("type").is_some_and(serde_json::Value::is_string) {
+        return Err(ApiError::bad_request("event_json must have a string \"type\""));
     }
+    if event.contains_key("v") {
+        return Err(ApiError::bad_request("event_json must not set \"v\", the server adds it"));
+    }
+
+    log::warn!("Debug broadcast of a {} event to room {}", event["type"], req.room_id);
+    data.broadcast(req.room_id, &ServerEvent::Debug(event));
+    Ok(HttpResponse::Accepted().finish())
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum MotdLevel {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
 
+// Message of the day; empty text means no banner
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct Motd {
+    text: String,
+    #[serde(default)]
+    level: MotdLevel,
+}
 
-// WebSocket handler
-async fn websocket_handler(
-    req: HttpRequest,
-    stream: web::Payload,
+async fn get_motd(data: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(data.motd.lock().unwrap().clone())
+}
+
+async fn set_motd(
+    _admin: AdminAuth,
     data: web::Data<Arc<AppState>>,
-) -> Result<HttpResponse, actix_web::Error> {
-    let query_string = req.query_string();
-    let query_params: HashMap<String, String> = serde_urlencoded::from_str(query_string)
-        .map_err(|_| actix_web::error::ErrorBadRequest("Invalid query string"))?;
+    req: web::Json<Motd>,
+) -> Result<HttpResponse, ApiError> {
+    let mut motd = req.into_inner();
+    limits::trim(&mut motd.text);
+    check_len("text", &motd.text, MAX_MOTD_LEN).map_err(ApiError::bad_request)?;
+    log::info!("MOTD set: {:?}", motd.text);
+    *data.motd.lock().unwrap() = motd.clone();
+    Ok(HttpResponse::Ok().json(motd))
+}
 
-    let room_id = query_params
-        .get("roomId")
-        .and_then(|id| Uuid::parse_str(id).ok())
-        .ok_or_else(|| actix_web::error::ErrorBadRequest("Missing or invalid roomId"))?;
+async fn reload_wordlist(_admin: AdminAuth, data: web::Data<Arc<AppState>>) -> Result<HttpResponse, ApiError> {
+    if !data.wordlist.is_configured() {
+        return Err(ApiError::not_found("WORDLIST_PATH is not set"));
+    }
+    let words = data.wordlist.reload().map_err(ApiError::internal)?;
+    log::info!("Reloaded the word list, {} words", words);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "words": words })))
+}
 
-    let username = query_params
-        .get("username")
-        .cloned()
-        .unwrap_or_else(|| "guest".to_string());
+#[derive(Deserialize)]
+struct RotateKeysRequest {
+    secret: Option<String>, // a new key is generated if left out
+}
 
-    ws::start(
-        WebSocketSession {
-            room_id,
-            username,
-            app_state: data.get_ref().clone(),
-        },
-        &req,
-        stream,
-    )
+// Installs a new JWT signing key. Tokens signed with the old one stay valid for
+// KEY_ROTATION_GRACE_SECS; the new key itself is never echoed back.
+async fn rotate_keys(
+    _admin: AdminAuth,
+    data: web::Data<Arc<AppState>>,
+    req: Option<web::Json<RotateKeysRequest>>,
+) -> Result<HttpResponse, ApiError> {
+    let secret = req.and_then(|req| req.into_inner().secret);
+    if let Some(secret) = &secret {
+        if secret.len() < config::MIN_JWT_SECRET_LEN {
+            return Err(ApiError::bad_request(format!(
+                "secret must be at least {} bytes",
+                config::MIN_JWT_SECRET_LEN
+            )));
+        }
+    }
+    let retired_keys = data.jwt_keys.rotate(secret);
+    log::info!("Rotated the JWT signing key, {} retired keys still accepted", retired_keys);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "retired_keys": retired_keys,
+        "grace_secs": data.jwt_keys.grace().as_secs(),
+    })))
 }
 
-async fn get_chat_history(
+#[derive(Deserialize)]
+struct AdminDisconnectRequest {
+    username: String,
+    reason: Option<String>, // close reason shown to the client
+}
+
+// Incident response: closes all of the user's WebSocket sessions, across every room
+async fn admin_disconnect(
+    _admin: AdminAuth,
     data: web::Data<Arc<AppState>>,
-    room_id: web::Path<Uuid>,
+    req: web::Json<AdminDisconnectRequest>,
 ) -> HttpResponse {
-    let rooms = data.rooms.lock().unwrap();
-    if let Some(room) = rooms.get(&room_id) {
-        HttpResponse::Ok().json(&room.history)
-    } else {
-        HttpResponse::NotFound().body("Room not found")
-    }
+    let reason = req.reason.as_deref().unwrap_or("Disconnected by an administrator");
+    let closed = data.disconnect_everywhere(&req.username, reason);
+    log::info!("Admin disconnected {} sessions of {}", closed, req.username);
+    HttpResponse::Ok().json(serde_json::json!({ "closed": closed }))
 }
 
-// REST API Handlers
-async fn register(data: web::Data<Arc<AppState>>, req: web::Json<RegisterRequest>) -> HttpResponse {
-    log::info!("Incoming register request: {:?}", req);
+// Every open WebSocket session, oldest first, for abuse investigation
+async fn admin_connections(_admin: AdminAuth, data: web::Data<Arc<AppState>>) -> HttpResponse {
+    let mut connections: Vec<AdminConnection> = data
+        .user_sessions
+        .lock()
+        .unwrap()
+        .iter()
+        .flat_map(|(username, sessions)| {
+            sessions.iter().map(|session| AdminConnection {
+                username: username.clone(),
+                session: session.info(),
+            })
+        })
+        .collect();
+    connections.sort_by_key(|connection| connection.session.connected_at);
+    HttpResponse::Ok().json(connections)
+}
 
-    let mut users = data.users.lock().unwrap_or_else(|e| {
-        log::error!("Failed to lock users: {:?}", e);
-        panic!("Mutex poisoned");
-    });
+#[derive(Serialize)]
+struct RoomMessageCount {
+    room_id: Uuid,
+    name: String,
+    count: usize,
+}
 
-    if users.contains_key(&req.username) {
-        log::warn!("User already exists: {}", req.username);
-        return HttpResponse::Conflict().body("User already exists");
-    }
+async fn admin_metrics(_admin: AdminAuth, data: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "ws_sessions_open": data.open_sessions().len(),
+        "ws_session_duration": data.metrics.session_duration.snapshot(),
+    }))
+}
 
-    users.insert(req.username.clone(), req.password.clone());
-    log::info!("User registered successfully: {}", req.username);
+// Stored messages per room, busiest first
+async fn message_counts(_admin: AdminAuth, data: web::Data<Arc<AppState>>) -> HttpResponse {
+    let rooms = data.rooms.lock().unwrap();
+
+    let mut counts: Vec<RoomMessageCount> = rooms
+        .values()
+        .map(|room| RoomMessageCount {
+            room_id: room.id,
+            name: room.name.clone(),
+            count: data.messages.count(room.id),
+        })
+        .collect();
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
 
-    HttpResponse::Ok().body("User registered successfully")
+    HttpResponse::Ok().json(counts)
 }
 
-async fn login(data: web::Data<Arc<AppState>>, req: web::Json<LoginRequest>) -> HttpResponse {
-    let users = data.users.lock().unwrap();
-    if let Some(password) = users.get(&req.username) {
-        if password == &req.password {
-            return HttpResponse::Ok().body("Login successful");
-        }
-    }
-    HttpResponse::Unauthorized().body("Invalid username or password")
+// Capabilities clients can feature-detect through /version
+const FEATURES: &[&str] = &[
+    "reactions",
+    "presence",
+    "status_text",
+    "topics",
+    "ephemeral_rooms",
+    "history_on_demand",
+    "resume",
+    "batching",
+    "message_seq",
+];
+
+// Current usage against the configured limits, for clients that show server load.
+// A null maximum means there is no limit.
+async fn capacity(data: web::Data<Arc<AppState>>) -> HttpResponse {
+    let rooms = data.rooms.lock().unwrap().len();
+    HttpResponse::Ok().json(serde_json::json!({
+        "rooms": rooms,
+        "max_rooms": data.max_rooms,
+        "connections": data.ws_connection_count(),
+        "max_connections": data.max_connections,
+    }))
 }
 
-async fn create_room(data: web::Data<Arc<AppState>>, req: web::Json<CreateRoomRequest>) -> HttpResponse {
-    let mut rooms = data.rooms.lock().unwrap();
-    let room = Room {
-        id: Uuid::new_v4(),
-        name: req.name.clone(),
-        creator: req.creator.clone(),
-        users: HashSet::new(),
-        history: Vec::new()
-    };
-    rooms.insert(room.id, room.clone());
-    HttpResponse::Ok().json(room)
+async fn uptime(data: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "started_at": data.started_at,
+        "uptime_secs": data.started.elapsed().as_secs(),
+    }))
 }
 
-async fn add_user(data: web::Data<Arc<AppState>>, req: web::Json<AddUserRequest>) -> HttpResponse {
-    let mut rooms = data.rooms.lock().unwrap();
-    if let Some(room) = rooms.get_mut(&req.room_id) {
-        room.users.insert(req.username.clone());
-        return HttpResponse::Ok().json(room.clone());
-    }
-    HttpResponse::NotFound().body("Room not found")
+async fn version() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "features": FEATURES,
+    }))
 }
 
-async fn list_rooms(data: web::Data<Arc<AppState>>) -> HttpResponse {
-    let rooms = data.rooms.lock().unwrap();
-    let room_list: Vec<_> = rooms.values().cloned().collect();
-    HttpResponse::Ok().json(room_list)
+fn cors(allowed_origins: Option<&[String]>) -> Cors {
+    let cors = Cors::default()
+        .allow_any_header()
+        .allow_any_method()
+        .expose_headers([rate_limit::WARNING_HEADER]);
+    match allowed_origins {
+        Some(origins) => origins.iter().fold(cors, |cors, origin| cors.allowed_origin(origin)),
+        None => cors.allow_any_origin(),
+    }
 }
 
+const ROOM_GC_INTERVAL: Duration = Duration::from_secs(60);
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// What workers get after the drain to finish in-flight HTTP requests
+const HTTP_SHUTDOWN_TIMEOUT_SECS: u64 = 5;
 
-use env_logger;
-use log::info;
+// Resolves on the first SIGTERM or Ctrl-C
+async fn termination_signal() {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                let sigterm = Box::pin(async move { sigterm.recv().await });
+                let ctrl_c = Box::pin(actix_web::rt::signal::ctrl_c());
+                futures_util::future::select(sigterm, ctrl_c).await;
+                return;
+            }
+            Err(err) => log::error!("Could not listen for SIGTERM: {}", err),
+        }
+    }
+    let _ = actix_web::rt::signal::ctrl_c().await;
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "debug");
     env_logger::init();
 
-    let app_state = Arc::new(AppState::default());
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(err) => {
+            log::error!("Invalid configuration: {}", err);
+            eprintln!("Refusing to start: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let app_state = Arc::new(AppState::new(&config));
+
+    let shutdown_state = app_state.clone();
+
+    // Idle room garbage collection, and resume points past their window
+    let gc_state = app_state.clone();
+    let gc_ttl = config.room_gc_ttl;
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(ROOM_GC_INTERVAL.min(gc_ttl));
+        loop {
+            interval.tick().await;
+            gc_state.sweep_resume_points();
+            let removed = gc_state.collect_idle_rooms(gc_ttl);
+            if !removed.is_empty() {
+                log::info!("Garbage collected {} idle rooms", removed.len());
+            }
+        }
+    });
+
+    // Disappearing messages
+    let expiry_state = app_state.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(EXPIRY_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            expiry_state.expire_messages();
+        }
+    });
+    let json_limit = config.json_limit;
+    let allowed_origins = config.allowed_origins.clone();
+    let debug_endpoints = config.debug_endpoints;
+    if debug_endpoints {
+        log::warn!("DEBUG_ENDPOINTS is set, /admin/debug/* is enabled");
+    }
 
-    HttpServer::new(move || {
+    let mut server = HttpServer::new(move || {
         App::new()
-            .wrap(
-                Cors::default()
-                    .allow_any_origin()
-                    .allow_any_header()
-                    .allow_any_method(),
-            )
+            .wrap_fn(|req, srv| {
+                let response = srv.call(req);
+                async move {
+                    let mut response = response.await?;
+                    rate_limit::add_warning_header(&mut response);
+                    Ok(response)
+                }
+            })
+            .wrap(cors(allowed_origins.as_deref()))
             .app_data(web::Data::new(app_state.clone()))
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(json_limit)
+                    .error_handler(json_error_handler),
+            )
+            .app_data(web::PathConfig::default().error_handler(path_error_handler))
             .route("/register", web::post().to(register))
             .route("/login", web::post().to(login))
+            .route("/me", web::get().to(me))
+            .route("/me/dms", web::get().to(my_dms))
+            .route("/me/sessions", web::get().to(my_sessions))
+            .route("/messages/{message_id}/room", web::get().to(message_room))
+            .route("/me/sessions/{session_id}", web::delete().to(end_my_session))
+            .route("/users/available", web::get().to(username_available))
+            .route("/users/{username}", web::get().to(get_user))
             .route("/create_room", web::post().to(create_room))
             .route("/add_user", web::post().to(add_user))
             .route("/list_rooms", web::get().to(list_rooms))
+            .route("/rooms/batch", web::post().to(batch_rooms))
+            .route("/presence/batch", web::post().to(batch_presence))
+            .route("/rooms/active", web::get().to(active_rooms))
+            .route("/rooms/{room_id}", web::get().to(get_room))
+            .route("/rooms/{room_id}/members", web::get().to(list_members))
+            .route("/rooms/{room_id}/members/full", web::get().to(list_members_full))
+            .route("/rooms/{room_id}/members.csv", web::get().to(export_members_csv))
+            .route("/rooms/{room_id}/ban", web::post().to(ban_user))
+            .route("/rooms/{room_id}/unban", web::post().to(unban_user))
+            .route("/rooms/{room_id}/kick", web::post().to(kick_user))
+            .route("/rooms/{room_id}/moderators", web::post().to(add_moderator))
+            .route("/rooms/{room_id}/moderators/{username}", web::delete().to(remove_moderator))
+            .route("/rooms/{room_id}/templates", web::get().to(list_templates))
+            .route("/rooms/{room_id}/templates", web::post().to(set_template))
+            .route("/rooms/{room_id}/templates/{name}", web::delete().to(delete_template))
+            .route("/rooms/{room_id}/banned", web::get().to(list_banned))
+            .route("/rooms/{room_id}/pinned", web::get().to(list_pinned))
+            .route("/rooms/{room_id}/leaderboard", web::get().to(room_leaderboard))
+            .route("/rooms/{room_id}/reactions/top", web::get().to(top_reactions))
+            .route("/rooms/{room_id}/idle", web::get().to(room_idle))
+            .route("/rooms/{room_id}/clear", web::post().to(clear_room_history))
+            .route("/rooms/{room_id}/report", web::post().to(report_message))
+            .route("/rooms/{room_id}/reports", web::get().to(list_reports))
+            .route("/rooms/{room_id}/schedule", web::post().to(schedule_message))
+            .route("/rooms/{room_id}/schedule/{id}", web::delete().to(cancel_scheduled))
+            .route("/rooms/{room_id}/webhook", web::get().to(get_webhook))
+            .route("/rooms/{room_id}/webhook", web::post().to(set_webhook))
+            .route("/rooms/{room_id}/webhook", web::delete().to(delete_webhook))
+            .route("/rooms/{room_id}/invite", web::post().to(create_invite))
+            .route("/rooms/{room_id}/archive", web::post().to(archive_room))
+            .route("/rooms/{room_id}/unarchive", web::post().to(unarchive_room))
+            .route("/join", web::post().to(join_room))
             .route("/get_chat_history/{room_id}", web::get().to(get_chat_history))
             .route("/ws/", web::get().to(websocket_handler))
-    })
-        .bind("127.0.0.1:8080")?
-        .run()
-        .await
+            .route("/version", web::get().to(version))
+            .route("/capacity", web::get().to(capacity))
+            .route("/uptime", web::get().to(uptime))
+            .route("/motd", web::get().to(get_motd))
+            .route("/admin/message_counts", web::get().to(message_counts))
+            .route("/admin/metrics", web::get().to(admin_metrics))
+            .route("/admin/roles", web::post().to(grant_role))
+            .route("/admin/disconnect", web::post().to(admin_disconnect))
+            .route("/admin/connections", web::get().to(admin_connections))
+            .route("/admin/rotate_keys", web::post().to(rotate_keys))
+            .route("/admin/reload_wordlist", web::post().to(reload_wordlist))
+            .route("/admin/motd", web::post().to(set_motd))
+            .route("/admin/export", web::get().to(export_state))
+            .route("/admin/import", web::post().to(import_state))
+            .configure(|cfg| {
+                if debug_endpoints {
+                    cfg.route("/admin/debug/broadcast", web::post().to(debug_broadcast));
+                }
+            })
+            .default_service(web::to(route_not_found))
+    });
+
+    if let Some(workers) = config.workers {
+        server = server.workers(workers);
+    }
+
+    // actix's own signal handling would stop the workers right away; instead the drain runs
+    // first and stops the server once the WebSocket clients are gone
+    let server = server
+        .bind(("127.0.0.1", config.port))?
+        .disable_signals()
+        .shutdown_timeout(HTTP_SHUTDOWN_TIMEOUT_SECS)
+        .run();
+    let handle = server.handle();
+    let shutdown_grace = config.shutdown_grace;
+    actix_web::rt::spawn(async move {
+        termination_signal().await;
+        log::info!("Shutdown requested, draining for up to {:?}", shutdown_grace);
+        handle.pause().await;
+        shutdown_state.drain(shutdown_grace).await;
+        handle.stop(true).await;
+    });
+    server.await
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    fn test_state() -> AppState {
+        AppState::new(&Config::for_tests())
+    }
+
+    // Adds a public room created by alice, idle since the epoch, adjusted by `edit`
+    fn add_room(state: &AppState, edit: impl FnOnce(&mut Room)) -> Uuid {
+        let config = Config {
+            default_room: Some(Uuid::new_v4()),
+            ..Config::for_tests()
+        };
+        let mut room = seed_rooms(&config).into_values().next().unwrap();
+        room.creator = "alice".to_string();
+        room.persistent = false;
+        room.last_activity_ms = 0;
+        edit(&mut room);
+        let id = room.id;
+        state.rooms.lock().unwrap().insert(id, room);
+        id
+    }
+
+    #[actix_web::test]
+    async fn idle_room_gc_removes_only_stale_empty_rooms() {
+        let state = test_state();
+        let stale = add_room(&state, |_| {});
+        let persistent = add_room(&state, |room| room.persistent = true);
+        let with_members = add_room(&state, |room| {
+            room.users.insert("bob".to_string(), 0);
+        });
+        let recent = add_room(&state, |room| room.last_activity_ms = now_ms());
+
+        assert_eq!(state.collect_idle_rooms(Duration::from_secs(60)), [stale]);
+        let rooms = state.rooms.lock().unwrap();
+        assert!(!rooms.contains_key(&stale));
+        for id in [persistent, with_members, recent] {
+            assert!(rooms.contains_key(&id));
+        }
+    }
+
+    #[actix_web::test]
+    async fn unused_room_id_skips_ids_already_taken() {
+        let state = test_state();
+        let taken = add_room(&state, |_| {});
+        let fresh = Uuid::new_v4();
+        let mut candidates = [taken, taken, fresh].into_iter();
+        let id = unused_room_id(&state.rooms.lock().unwrap(), || candidates.next().unwrap());
+        assert_eq!(id, fresh);
+        assert_eq!(candidates.next(), None);
+    }
+
+    #[actix_web::test]
+    async fn seed_admin_is_created_with_the_admin_role() {
+        let config = Config {
+            seed_admin: Some(config::SeedAdmin {
+                username: "root".to_string(),
+                password: "correct horse battery".to_string(),
+            }),
+            ..Config::for_tests()
+        };
+        let state = AppState::new(&config);
+        let users = state.users.lock().unwrap();
+        let admin = users.get("root").expect("the seed admin was not created");
+        assert!(admin.roles.contains(&Role::Admin));
+        assert!(auth::verify_password("correct horse battery", &admin.password_hash));
+    }
+
+    #[actix_web::test]
+    async fn full_mailbox_closes_the_session_as_too_slow() {
+        let state = Arc::new(test_state());
+        let room_id = add_room(&state, |_| {});
+        let session = WebSocketSession::new(state.clone(), room_id, "bob".to_string(), true, "127.0.0.1".to_string());
+        let send_failures = session.send_failures.clone();
+        // The session only runs while its output is polled, so until then nothing leaves the mailbox
+        let (addr, output) = ws::WebsocketContext::create_with_addr(session, futures_util::stream::pending());
+        let connection = Connection {
+            username: "bob".to_string(),
+            addr,
+            send_failures: send_failures.clone(),
+        };
+        let event = ServerEvent::Typing { username: "alice".to_string() };
+        while send_failures.load(Ordering::Relaxed) < MAX_SEND_FAILURES {
+            connection.send(&event);
+        }
+
+        let frames = actix_web::rt::time::timeout(Duration::from_secs(5), output.collect::<Vec<_>>())
+            .await
+            .expect("the session did not close");
+        let written: Vec<u8> = frames.into_iter().flat_map(|frame| frame.unwrap().to_vec()).collect();
+        let reason = TOO_SLOW_REASON.as_bytes();
+        assert!(written.windows(reason.len()).any(|window| window == reason));
+    }
+
+    // The JSON of every complete text frame in what the server wrote
+    fn text_frames(written: &[u8]) -> Vec<serde_json::Value> {
+        let mut frames = Vec::new();
+        let mut rest = written;
+        while rest.len() >= 2 {
+            let (len, header) = match rest[1] & 0x7f {
+                126 if rest.len() >= 4 => (u16::from_be_bytes([rest[2], rest[3]]) as usize, 4),
+                127 if rest.len() >= 10 => (u64::from_be_bytes(rest[2..10].try_into().unwrap()) as usize, 10),
+                126 | 127 => break,
+                len => (len as usize, 2),
+            };
+            if rest.len() < header + len {
+                break;
+            }
+            if rest[0] & 0x0f == 1 {
+                frames.push(serde_json::from_slice(&rest[header..header + len]).unwrap());
+            }
+            rest = &rest[header + len..];
+        }
+        frames
+    }
+
+    #[actix_web::test]
+    async fn large_replay_goes_out_in_paced_chunks() {
+        let state = Arc::new(test_state());
+        let room_id = add_room(&state, |_| {});
+        for seq in 1..=120 {
+            let message = serde_json::from_value(serde_json::json!({
+                "id": Uuid::new_v4(),
+                "room_id": room_id,
+                "seq": seq,
+                "username": "alice",
+                "message": format!("message {}", seq),
+            }))
+            .unwrap();
+            state.messages.store(message, MAX_HISTORY_LIMIT);
+        }
+        // Resuming from before the first message replays all of them
+        let token = Uuid::new_v4();
+        state.save_resume_point(token, room_id, "bob", None);
+        let session = WebSocketSession {
+            resume_from: Some(token),
+            ..WebSocketSession::new(state.clone(), room_id, "bob".to_string(), true, "127.0.0.1".to_string())
+        };
+        let (_addr, mut output) = ws::WebsocketContext::create_with_addr(session, futures_util::stream::pending());
+
+        let started = Instant::now();
+        let mut written = Vec::new();
+        let mut chunks: Vec<(Duration, serde_json::Value)> = Vec::new();
+        while chunks.last().is_none_or(|(_, chunk)| chunk["more"] == true) {
+            let bytes = actix_web::rt::time::timeout(Duration::from_secs(5), output.next())
+                .await
+                .expect("the replay stalled")
+                .unwrap()
+                .unwrap();
+            written.extend_from_slice(&bytes);
+            let history = text_frames(&written).into_iter().filter(|frame| frame["type"] == "history");
+            chunks.extend(history.skip(chunks.len()).map(|chunk| (started.elapsed(), chunk)));
+        }
+
+        let sizes: Vec<usize> = chunks.iter().map(|(_, chunk)| chunk["messages"].as_array().unwrap().len()).collect();
+        assert_eq!(sizes, [REPLAY_CHUNK_SIZE, REPLAY_CHUNK_SIZE, 20]);
+        let seqs: Vec<u64> = chunks
+            .iter()
+            .flat_map(|(_, chunk)| chunk["messages"].as_array().unwrap().clone())
+            .map(|message| message["seq"].as_u64().unwrap())
+            .collect();
+        assert_eq!(seqs, (1..=120).collect::<Vec<u64>>());
+        // Each chunk after the first waits its turn
+        assert!(chunks[2].0 - chunks[0].0 >= REPLAY_CHUNK_INTERVAL * 2);
+    }
+
+    #[actix_web::test]
+    async fn control_characters_are_stripped_or_rejected() {
+        let rejected = |result: Result<String, Box<ServerEvent>>| match result.map_err(|event| *event) {
+            Err(ServerEvent::Error { code, .. }) => matches!(code, WsErrorCode::InvalidCharacters),
+            _ => false,
+        };
+        let text = "line one\nline\u{0}\u{7} two\t\u{1b}[31mred";
+
+        let strip = test_state();
+        assert_eq!(strip.sanitize(text.to_string(), false).ok().as_deref(), Some("line one\nline two\t[31mred"));
+        assert_eq!(strip.sanitize("plain\r\n".to_string(), false).ok().as_deref(), Some("plain\r\n"));
+        // Stripping would break the signature
+        assert!(rejected(strip.sanitize(text.to_string(), true)));
+
+        let reject = AppState::new(&Config {
+            sanitize_mode: config::SanitizeMode::Reject,
+            ..Config::for_tests()
+        });
+        assert!(rejected(reject.sanitize(text.to_string(), false)));
+        assert_eq!(reject.sanitize("plain\r\n".to_string(), false).ok().as_deref(), Some("plain\r\n"));
+    }
+
+    #[actix_web::test]
+    async fn forwarding_headers_count_only_from_trusted_proxies() {
+        let state = AppState::new(&Config {
+            trusted_proxies: vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+            ..Config::for_tests()
+        });
+        let key = |peer: &str, headers: &[(&str, &str)]| {
+            let mut req = actix_web::test::TestRequest::default().peer_addr(peer.parse().unwrap());
+            for &header in headers {
+                req = req.append_header(header);
+            }
+            state.client_key(&req.to_http_request())
+        };
+
+        // Anyone else can claim any address, so their headers are ignored
+        assert_eq!(key("203.0.113.9:5000", &[("X-Forwarded-For", "198.51.100.1")]), "203.0.113.9");
+        assert_eq!(key("10.0.0.1:5000", &[]), "10.0.0.1");
+        assert_eq!(key("10.0.0.1:5000", &[("X-Forwarded-For", "198.51.100.1")]), "198.51.100.1");
+        // The nearest address that isn't another of our proxies is the client; what comes before
+        // it was added by the client itself
+        let chain = [("X-Forwarded-For", "192.0.2.66, 198.51.100.1, 10.0.0.2")];
+        assert_eq!(key("10.0.0.1:5000", &chain), "198.51.100.1");
+        let forwarded = [("Forwarded", r#"for=198.51.100.1;proto=https, for="[2001:db8::1]:4711""#)];
+        assert_eq!(key("10.0.0.1:5000", &forwarded), "2001:db8::1");
+    }
+
+    // Pinned, since a different id for the same slug would orphan rooms created by slug
+    #[test]
+    fn slug_ids_are_stable() {
+        assert_eq!(slug_room_id("team-chat").to_string(), "41bd3cd3-a606-5426-affe-27ac6fe1f64a");
+        assert_ne!(slug_room_id("team-chat"), slug_room_id("team-chat-2"));
+    }
+
+    #[actix_web::test]
+    async fn cooldown_counts_from_joining_and_spares_moderators() {
+        let state = test_state();
+        let cooldown = Duration::from_secs(10);
+        let id = add_room(&state, |room| {
+            room.users.insert("settled".to_string(), now_ms() - 60_000);
+            room.users.insert("newcomer".to_string(), now_ms() - 2_500);
+        });
+        let mut rooms = state.rooms.lock().unwrap();
+        let room = rooms.get_mut(&id).unwrap();
+
+        assert_eq!(room.cooldown_left("settled", cooldown), None);
+        assert_eq!(room.cooldown_left("newcomer", cooldown), Some(8));
+        assert_eq!(room.cooldown_left("alice", cooldown), None);
+        assert_eq!(room.cooldown_left("guest", cooldown), None);
+        room.note_arrival("guest", cooldown);
+        assert_eq!(room.cooldown_left("guest", cooldown), Some(10));
+    }
+
+    #[test]
+    fn poll_results_count_each_voters_latest_pick() {
+        let mut poll = Poll {
+            id: Uuid::new_v4(),
+            question: "Lunch?".to_string(),
+            options: vec!["Pizza".to_string(), "Sushi".to_string(), "Salad".to_string()],
+            created_by: "alice".to_string(),
+            votes: HashMap::new(),
+        };
+        assert_eq!(poll.results(false).tallies, [0, 0, 0]);
+        for (username, option) in [("bob", 0), ("carol", 2), ("dave", 2), ("bob", 2)] {
+            poll.votes.insert(username.to_string(), option);
+        }
+        let results = poll.results(true);
+        assert_eq!(results.tallies, [0, 0, 3]);
+        assert!(results.closed);
+    }
+}
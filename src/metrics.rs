@@ -0,0 +1,100 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Upper bounds of the WebSocket session duration buckets, in seconds
+const SESSION_DURATION_BUCKETS: &[f64] = &[1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0, 4.0 * 3600.0, 24.0 * 3600.0];
+
+// Process-wide metrics, reported by /admin/metrics; reset on restart
+pub struct Metrics {
+    pub session_duration: Histogram, // how long WebSocket sessions stayed connected
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            session_duration: Histogram::new(SESSION_DURATION_BUCKETS),
+        }
+    }
+}
+
+// Fixed-bucket histogram of durations, updated without locking
+pub struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<AtomicU64>, // one per bound, plus one for everything above the last
+    sum_ms: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct HistogramSnapshot {
+    buckets: Vec<Bucket>,
+    count: u64,
+    sum_secs: f64,
+}
+
+// Cumulative like Prometheus buckets: `count` observations took at most `le` seconds
+#[derive(Serialize)]
+struct Bucket {
+    le: Option<f64>, // None for the catch-all bucket
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let index = self.bounds.iter().position(|&bound| secs <= bound).unwrap_or(self.bounds.len());
+        self.counts[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(value.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut total = 0;
+        let buckets = self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(index, count)| {
+                total += count.load(Ordering::Relaxed);
+                Bucket {
+                    le: self.bounds.get(index).copied(),
+                    count: total,
+                }
+            })
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            count: total,
+            sum_secs: self.sum_ms.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new(&[1.0, 60.0]);
+        for secs in [0.5, 1.0, 30.0, 7200.0] {
+            histogram.observe(Duration::from_secs_f64(secs));
+        }
+        let snapshot = serde_json::to_value(histogram.snapshot()).unwrap();
+        assert_eq!(
+            snapshot,
+            serde_json::json!({
+                "buckets": [{ "le": 1.0, "count": 2 }, { "le": 60.0, "count": 3 }, { "le": null, "count": 4 }],
+                "count": 4,
+                "sum_secs": 7231.5,
+            })
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::web::Bytes;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+// Encoded frames a WebSocket session has produced that the connection hasn't written yet
+#[derive(Default)]
+struct Backlog {
+    chunks: VecDeque<Bytes>,
+    bytes: usize,
+    overflowed: bool,
+    finished: bool, // the session's stream has ended
+    waker: Option<Waker>,
+}
+
+// Wraps a WebSocket response body so the bytes waiting for the client can be measured.
+//
+// actix queues a session's outgoing frames without bound and only encodes them as fast as the
+// client reads, so a stalled client is invisible to the session. Here the session's body is
+// drained into a backlog as soon as it produces anything; once the backlog exceeds `limit`
+// bytes it is dropped and `on_overflow` is called. It should close the session, whose close
+// frame is then the next thing the client gets.
+pub fn bounded(body: BoxBody, limit: usize, on_overflow: impl FnOnce() + 'static) -> BoxBody {
+    let backlog = Rc::new(RefCell::new(Backlog::default()));
+    actix_web::rt::spawn(pump(body, limit, backlog.clone(), on_overflow));
+    BoxBody::new(BacklogBody { backlog })
+}
+
+async fn pump(mut body: BoxBody, limit: usize, backlog: Rc<RefCell<Backlog>>, on_overflow: impl FnOnce()) {
+    let mut on_overflow = Some(on_overflow);
+    while let Some(Ok(chunk)) = poll_fn(|cx| Pin::new(&mut body).poll_next(cx)).await {
+        let overflowed = {
+            let mut backlog = backlog.borrow_mut();
+            backlog.bytes += chunk.len();
+            backlog.chunks.push_back(chunk);
+            if backlog.bytes > limit && !backlog.overflowed {
+                backlog.overflowed = true;
+                backlog.chunks.clear();
+                backlog.bytes = 0;
+            }
+            if let Some(waker) = backlog.waker.take() {
+                waker.wake();
+            }
+            backlog.overflowed
+        };
+        if overflowed {
+            if let Some(on_overflow) = on_overflow.take() {
+                on_overflow();
+            }
+        }
+    }
+
+    let mut backlog = backlog.borrow_mut();
+    backlog.finished = true;
+    if let Some(waker) = backlog.waker.take() {
+        waker.wake();
+    }
+}
+
+struct BacklogBody {
+    backlog: Rc<RefCell<Backlog>>,
+}
+
+impl MessageBody for BacklogBody {
+    type Error = Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let mut backlog = self.backlog.borrow_mut();
+        if let Some(chunk) = backlog.chunks.pop_front() {
+            backlog.bytes = backlog.bytes.saturating_sub(chunk.len());
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+        if backlog.finished {
+            return Poll::Ready(None);
+        }
+        backlog.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::{to_bytes, BodyStream};
+    use std::cell::Cell;
+
+    // A session body that has already produced `chunks`, run through `bounded` with a client
+    // that reads nothing until they've all been produced
+    async fn unread(chunks: &[&'static [u8]], limit: usize) -> (Bytes, u32) {
+        let chunks = chunks.iter().map(|&chunk| Ok::<_, Infallible>(Bytes::from_static(chunk)));
+        let body = BoxBody::new(BodyStream::new(futures_util::stream::iter(chunks.collect::<Vec<_>>())));
+        let overflows = Rc::new(Cell::new(0));
+        let counter = overflows.clone();
+        let body = bounded(body, limit, move || counter.set(counter.get() + 1));
+        actix_web::rt::task::yield_now().await;
+        let written = to_bytes(body).await.unwrap();
+        (written, overflows.get())
+    }
+
+    #[actix_web::test]
+    async fn a_backlog_within_the_limit_is_passed_on_in_order() {
+        let (written, overflows) = unread(&[b"one,", b"two,", b"three"], 64).await;
+        assert_eq!(written, "one,two,three");
+        assert_eq!(overflows, 0);
+    }
+
+    #[actix_web::test]
+    async fn crossing_the_limit_drops_the_backlog_and_reports_it_once() {
+        let (written, overflows) = unread(&[&[b'a'; 600], &[b'b'; 600], b"close"], 1000).await;
+        // The close frame the session writes once told to stop is all the client gets
+        assert_eq!(written, "close");
+        assert_eq!(overflows, 1);
+    }
+}
@@ -0,0 +1,339 @@
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Clients are warned once no more than this share of a bucket's tokens is left
+const SOFT_LIMIT_SHARE: f64 = 0.2;
+
+// How often a limiter drops the buckets of keys that have gone quiet
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Response header set by `add_warning_header`, e.g. "approaching_limit; remaining=1"
+pub const WARNING_HEADER: &str = "x-ratelimit-warning";
+
+// Token bucket: holds up to `capacity` tokens, refilled continuously at `refill_per_sec`
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, refill_per_sec: f64) -> Self {
+        TokenBucket {
+            capacity: capacity as f64,
+            refill_per_sec,
+            tokens: capacity as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    // Takes one token, or returns how long until the next one becomes available
+    pub fn try_take(&mut self) -> Result<(), Duration> {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - self.tokens;
+            Err(Duration::from_secs_f64(missing / self.refill_per_sec))
+        }
+    }
+
+    // Whole tokens left, if they are down to the last SOFT_LIMIT_SHARE of the bucket: the cue to
+    // tell a client to slow down before it is refused. Call right after `try_take`.
+    pub fn running_low(&self) -> Option<u32> {
+        let remaining = self.tokens.floor();
+        (remaining <= (self.capacity * SOFT_LIMIT_SHARE).floor()).then_some(remaining as u32)
+    }
+
+    // Refilled to capacity, i.e. no different from a new bucket
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+
+    // Whole tokens left and how long until the bucket is full again
+    pub fn status(&mut self) -> (u32, Duration) {
+        self.refill();
+        let reset_in = (self.capacity - self.tokens) / self.refill_per_sec;
+        (self.tokens.floor() as u32, Duration::from_secs_f64(reset_in))
+    }
+}
+
+// One token bucket per key (client IP, username, ...). Keys come and go, so buckets that have
+// refilled completely are dropped every SWEEP_INTERVAL; the key gets an identical one back.
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: u32,
+    refill_per_sec: f64,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    by_key: HashMap<String, TokenBucket>,
+    swept_at: Instant,
+}
+
+impl Buckets {
+    fn sweep(&mut self) {
+        self.by_key.retain(|_, bucket| !bucket.is_full());
+        self.swept_at = Instant::now();
+    }
+}
+
+impl RateLimiter {
+    pub fn new(capacity: u32, refill_per_sec: f64) -> Self {
+        RateLimiter {
+            capacity,
+            refill_per_sec,
+            buckets: Mutex::new(Buckets {
+                by_key: HashMap::new(),
+                swept_at: Instant::now(),
+            }),
+        }
+    }
+
+    // Takes a token for `key`. A request that leaves the key running low is marked, so that
+    // `add_warning_header` can warn the client in the response.
+    pub fn check_request(&self, req: &HttpRequest, key: &str) -> Result<(), Duration> {
+        if let Some(remaining) = self.take(key)? {
+            let mut extensions = req.extensions_mut();
+            let lowest = extensions.get::<LimitWarning>().map_or(remaining, |warning| warning.0.min(remaining));
+            extensions.insert(LimitWarning(lowest));
+        }
+        Ok(())
+    }
+
+    fn take(&self, key: &str) -> Result<Option<u32>, Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.swept_at.elapsed() >= SWEEP_INTERVAL {
+            buckets.sweep();
+        }
+        let bucket = buckets
+            .by_key
+            .entry(key.to_string())
+            .or_insert_with(|| TokenBucket::new(self.capacity, self.refill_per_sec));
+        bucket.try_take()?;
+        Ok(bucket.running_low())
+    }
+}
+
+// Tokens left after the request, recorded by `RateLimiter::check_request`
+#[derive(Clone, Copy)]
+struct LimitWarning(u32);
+
+// Response side of `check_request`, run by a middleware around every route. With several
+// limiters on one request the lowest count wins, as that one runs out first.
+pub fn add_warning_header<B>(response: &mut ServiceResponse<B>) {
+    let warning = response.request().extensions().get::<LimitWarning>().copied();
+    if let Some(LimitWarning(remaining)) = warning {
+        let value = format!("approaching_limit; remaining={}", remaining);
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response.headers_mut().insert(HeaderName::from_static(WARNING_HEADER), value);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RateLimitedBody {
+    code: &'static str,
+    retry_after: u64,
+}
+
+// Whole seconds the client should wait, rounded up so retrying on time never hits the limit again
+pub fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs_f64().ceil().max(1.0) as u64
+}
+
+// Shared 429 response for every limiter: Retry-After header plus a matching JSON body
+pub fn too_many_requests(retry_after: Duration) -> HttpResponse {
+    let secs = retry_after_secs(retry_after);
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, secs.to_string()))
+        .json(RateLimitedBody {
+            code: "rate_limited",
+            retry_after: secs,
+        })
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    first_at: Instant,
+    locked_until: Option<Instant>,
+}
+
+// Consecutive failures per key (e.g. login attempts per username): `max_failures` within `window`
+// lock the key out for `lockout`. A success resets the key; old entries simply expire.
+#[derive(Debug)]
+pub struct LockoutTracker {
+    max_failures: u32,
+    window: Duration,
+    lockout: Duration,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl LockoutTracker {
+    pub fn new(max_failures: u32, window: Duration, lockout: Duration) -> Self {
+        LockoutTracker {
+            max_failures,
+            window,
+            lockout,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Err with the remaining lockout if the key is currently locked
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        let failures = self.failures.lock().unwrap();
+        let now = Instant::now();
+        match failures.get(key).and_then(|entry| entry.locked_until) {
+            Some(until) if until > now => Err(until - now),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn record_failure(&self, key: &str) {
+        let mut failures = self.failures.lock().unwrap();
+        let now = Instant::now();
+        failures.retain(|_, entry| {
+            now.duration_since(entry.first_at) < self.window || entry.locked_until.is_some_and(|until| until > now)
+        });
+
+        let entry = failures.entry(key.to_string()).or_insert(Failures {
+            count: 0,
+            first_at: now,
+            locked_until: None,
+        });
+        // A finished lockout starts a fresh count
+        if entry.locked_until.is_some_and(|until| until <= now) {
+            *entry = Failures {
+                count: 0,
+                first_at: now,
+                locked_until: None,
+            };
+        }
+        entry.count += 1;
+        if entry.count >= self.max_failures {
+            entry.locked_until = Some(now + self.lockout);
+        }
+    }
+
+    pub fn reset(&self, key: &str) {
+        self.failures.lock().unwrap().remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn retry_after_header_matches_the_body() {
+        for wait in [Duration::from_millis(1), Duration::from_millis(1500), Duration::from_secs(42)] {
+            let response = too_many_requests(wait);
+            assert_eq!(response.status(), actix_web::http::StatusCode::TOO_MANY_REQUESTS);
+            let header: u64 = response.headers().get(header::RETRY_AFTER).unwrap().to_str().unwrap().parse().unwrap();
+            let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["code"], "rate_limited");
+            assert_eq!(body["retry_after"], header);
+            assert_eq!(header, retry_after_secs(wait));
+        }
+    }
+
+    #[test]
+    fn retry_after_rounds_up() {
+        assert_eq!(retry_after_secs(Duration::ZERO), 1);
+        assert_eq!(retry_after_secs(Duration::from_millis(1001)), 2);
+        assert_eq!(retry_after_secs(Duration::from_secs(3)), 3);
+    }
+
+    #[test]
+    fn bucket_refuses_when_empty_and_refills() {
+        let mut bucket = TokenBucket::new(2, 1000.0);
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_ok());
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(bucket.try_take().is_ok());
+
+        let mut slow = TokenBucket::new(1, 0.5);
+        assert!(slow.try_take().is_ok());
+        let wait = slow.try_take().unwrap_err();
+        assert!(wait > Duration::from_secs(1) && wait <= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn running_low_only_near_the_end() {
+        let mut bucket = TokenBucket::new(10, 0.001);
+        bucket.try_take().unwrap();
+        assert_eq!(bucket.running_low(), None);
+        for _ in 0..7 {
+            bucket.try_take().unwrap();
+        }
+        assert_eq!(bucket.running_low(), Some(2));
+    }
+
+    #[actix_web::test]
+    async fn the_limiter_closest_to_running_out_sets_the_warning() {
+        let (nearly_out, running_low) = (RateLimiter::new(10, 0.001), RateLimiter::new(10, 0.001));
+        for _ in 0..8 {
+            nearly_out.take("client").unwrap();
+        }
+        for _ in 0..7 {
+            running_low.take("client").unwrap();
+        }
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        let warning = || req.extensions().get::<LimitWarning>().map(|warning| warning.0);
+        RateLimiter::new(10, 0.001).check_request(&req, "client").unwrap();
+        assert_eq!(warning(), None);
+        nearly_out.check_request(&req, "client").unwrap();
+        running_low.check_request(&req, "client").unwrap();
+        assert_eq!(warning(), Some(1));
+    }
+
+    #[test]
+    fn sweep_drops_only_full_buckets() {
+        let limiter = RateLimiter::new(1, 1000.0);
+        let strict = RateLimiter::new(1, 0.001);
+        for key in ["a", "b", "c"] {
+            limiter.take(key).unwrap();
+            strict.take(key).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(5));
+
+        limiter.buckets.lock().unwrap().sweep();
+        strict.buckets.lock().unwrap().sweep();
+        assert!(limiter.buckets.lock().unwrap().by_key.is_empty());
+        // Still empty buckets are kept, or dropping them would hand out fresh tokens
+        assert_eq!(strict.buckets.lock().unwrap().by_key.len(), 3);
+        assert!(strict.take("a").is_err());
+    }
+
+    #[test]
+    fn lockout_after_max_failures_and_reset() {
+        let tracker = LockoutTracker::new(2, Duration::from_secs(60), Duration::from_secs(60));
+        tracker.record_failure("alice");
+        assert!(tracker.check("alice").is_ok());
+        tracker.record_failure("alice");
+        assert!(tracker.check("alice").is_err());
+        assert!(tracker.check("bob").is_ok());
+        tracker.reset("alice");
+        assert!(tracker.check("alice").is_ok());
+    }
+}
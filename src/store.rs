@@ -0,0 +1,221 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::config::StoreBackend;
+use crate::ChatMessage;
+
+// Where chat history lives; the broadcast path only talks to this trait.
+// Lookups and edits ignore deleted (tombstoned) messages.
+pub trait MessageStore: Send + Sync {
+    // Appends a message, dropping the room's oldest ones beyond `capacity`
+    fn store(&self, message: ChatMessage, capacity: usize);
+    // The newest `limit` messages of the room, oldest first; tombstones included
+    fn recent(&self, room_id: Uuid, limit: usize) -> Vec<ChatMessage>;
+    fn get(&self, room_id: Uuid, message_id: Uuid) -> Option<ChatMessage>;
+    // The room whose history holds the message, tombstones included
+    fn room_of(&self, message_id: Uuid) -> Option<Uuid>;
+    // Runs `f` on the message in place; false if there is no such message
+    fn edit(&self, room_id: Uuid, message_id: Uuid, f: &mut dyn FnMut(&mut ChatMessage)) -> bool;
+    // Tombstones the message: it stays in history, stripped of its text, reactions, pin,
+    // attachment, signature and forward origin
+    fn delete(&self, room_id: Uuid, message_id: Uuid) -> bool;
+    fn count(&self, room_id: Uuid) -> usize;
+    // Drops the message from history altogether, unlike `delete`; false if there is no such message
+    fn remove(&self, room_id: Uuid, message_id: Uuid) -> bool;
+    // Drops the room's whole history
+    fn clear(&self, room_id: Uuid);
+}
+
+pub fn from_backend(backend: StoreBackend) -> Box<dyn MessageStore> {
+    match backend {
+        StoreBackend::Memory => Box::new(InMemoryStore::default()),
+    }
+}
+
+// Per-room ring buffers; everything is lost on restart
+#[derive(Default)]
+pub struct InMemoryStore {
+    rooms: Mutex<HashMap<Uuid, VecDeque<ChatMessage>>>, // room_id -> message history
+    locations: Mutex<HashMap<Uuid, Uuid>>,               // message_id -> room_id; locked after `rooms`
+}
+
+impl MessageStore for InMemoryStore {
+    fn store(&self, message: ChatMessage, capacity: usize) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let mut locations = self.locations.lock().unwrap();
+        locations.insert(message.id, message.room_id);
+        let history = rooms.entry(message.room_id).or_default();
+        history.push_back(message);
+        while history.len() > capacity {
+            if let Some(evicted) = history.pop_front() {
+                locations.remove(&evicted.id);
+            }
+        }
+    }
+
+    fn recent(&self, room_id: Uuid, limit: usize) -> Vec<ChatMessage> {
+        let rooms = self.rooms.lock().unwrap();
+        match rooms.get(&room_id) {
+            Some(history) => history.iter().skip(history.len().saturating_sub(limit)).cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn get(&self, room_id: Uuid, message_id: Uuid) -> Option<ChatMessage> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms
+            .get(&room_id)?
+            .iter()
+            .find(|message| message.id == message_id && !message.deleted)
+            .cloned()
+    }
+
+    fn room_of(&self, message_id: Uuid) -> Option<Uuid> {
+        self.locations.lock().unwrap().get(&message_id).copied()
+    }
+
+    fn edit(&self, room_id: Uuid, message_id: Uuid, f: &mut dyn FnMut(&mut ChatMessage)) -> bool {
+        let mut rooms = self.rooms.lock().unwrap();
+        let message = rooms
+            .get_mut(&room_id)
+            .and_then(|history| history.iter_mut().find(|message| message.id == message_id && !message.deleted));
+        match message {
+            Some(message) => {
+                f(message);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn delete(&self, room_id: Uuid, message_id: Uuid) -> bool {
+        self.edit(room_id, message_id, &mut |message| {
+            message.deleted = true;
+            message.message.clear();
+            message.reactions.clear();
+            message.pinned = false;
+            message.attachment = None;
+            message.signature = None;
+            message.forwarded_from = None;
+        })
+    }
+
+    fn count(&self, room_id: Uuid) -> usize {
+        self.rooms.lock().unwrap().get(&room_id).map_or(0, VecDeque::len)
+    }
+
+    fn remove(&self, room_id: Uuid, message_id: Uuid) -> bool {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(history) = rooms.get_mut(&room_id) else {
+            return false;
+        };
+        let before = history.len();
+        history.retain(|message| message.id != message_id);
+        if history.len() == before {
+            return false;
+        }
+        self.locations.lock().unwrap().remove(&message_id);
+        true
+    }
+
+    fn clear(&self, room_id: Uuid) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(history) = rooms.remove(&room_id) {
+            let mut locations = self.locations.lock().unwrap();
+            for message in history {
+                locations.remove(&message.id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(room_id: Uuid, seq: u64, text: &str) -> ChatMessage {
+        serde_json::from_value(serde_json::json!({
+            "id": Uuid::new_v4(),
+            "room_id": room_id,
+            "seq": seq,
+            "username": "alice",
+            "message": text,
+        }))
+        .unwrap()
+    }
+
+    fn texts(messages: &[ChatMessage]) -> Vec<&str> {
+        messages.iter().map(|message| message.message.as_str()).collect()
+    }
+
+    // What every MessageStore has to do, whatever keeps the messages
+    fn check_conformance(store: &dyn MessageStore) {
+        let room = Uuid::new_v4();
+        let other_room = Uuid::new_v4();
+        let first = message(room, 1, "one");
+        let (first_id, second_id) = (first.id, Uuid::new_v4());
+        store.store(first, 3);
+        let second: ChatMessage = serde_json::from_value(serde_json::json!({
+            "id": second_id,
+            "room_id": room,
+            "seq": 2,
+            "username": "alice",
+            "message": "two",
+            "signature": "c2lnbmF0dXJl",
+            "attachment": { "mime": "image/png", "data": "iVBORw==" },
+            "forwarded_from": { "room_id": other_room, "message_id": Uuid::new_v4(), "username": "bob" },
+        }))
+        .unwrap();
+        store.store(second, 3);
+        store.store(message(room, 3, "three"), 3);
+        store.store(message(other_room, 1, "elsewhere"), 3);
+
+        assert_eq!(texts(&store.recent(room, 10)), ["one", "two", "three"]);
+        assert_eq!(texts(&store.recent(room, 2)), ["two", "three"]);
+        assert_eq!(store.count(room), 3);
+        assert_eq!(store.room_of(first_id), Some(room));
+        assert_eq!(store.get(room, first_id).unwrap().message, "one");
+        assert!(store.get(other_room, first_id).is_none());
+
+        // Beyond capacity the oldest message goes
+        store.store(message(room, 4, "four"), 3);
+        assert_eq!(texts(&store.recent(room, 10)), ["two", "three", "four"]);
+        assert!(store.get(room, first_id).is_none());
+        assert_eq!(store.room_of(first_id), None);
+
+        assert!(store.edit(room, second_id, &mut |message| message.message = "2".to_string()));
+        assert_eq!(store.get(room, second_id).unwrap().message, "2");
+        assert!(!store.edit(room, first_id, &mut |_| {}));
+
+        // A tombstone stays in history but can't be looked up or edited
+        assert!(store.delete(room, second_id));
+        assert!(store.get(room, second_id).is_none());
+        assert!(!store.edit(room, second_id, &mut |_| {}));
+        let history = store.recent(room, 10);
+        assert!(history[0].deleted && history[0].message.is_empty());
+        assert!(history[0].attachment.is_none() && history[0].signature.is_none());
+        assert!(history[0].forwarded_from.is_none());
+        assert_eq!(store.count(room), 3);
+
+        assert!(store.remove(room, second_id));
+        assert!(!store.remove(room, second_id));
+        assert_eq!(texts(&store.recent(room, 10)), ["three", "four"]);
+        assert_eq!(store.room_of(second_id), None);
+
+        store.clear(room);
+        assert!(store.recent(room, 10).is_empty());
+        assert_eq!(store.count(room), 0);
+        assert_eq!(texts(&store.recent(other_room, 10)), ["elsewhere"]);
+    }
+
+    #[test]
+    fn in_memory_store_conforms() {
+        check_conformance(&InMemoryStore::default());
+    }
+
+    #[test]
+    fn memory_backend_conforms() {
+        check_conformance(from_backend(StoreBackend::Memory).as_ref());
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+// Words that may not appear in messages, read from WORDLIST_PATH: one per line, blank lines
+// and lines starting with '#' ignored. Matching is per word and case-insensitive.
+//
+// A reload builds the new set completely before swapping it in, so a message is always
+// checked against either the old list or the new one, never a mix.
+pub struct WordList {
+    path: Option<PathBuf>,
+    words: RwLock<Arc<HashSet<String>>>,
+}
+
+impl WordList {
+    // A missing or unreadable file at startup leaves the list empty rather than refusing to start
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let words = match &path {
+            Some(path) => read(path).unwrap_or_else(|err| {
+                log::warn!("Could not read word list {}: {}", path.display(), err);
+                HashSet::new()
+            }),
+            None => HashSet::new(),
+        };
+        WordList {
+            path,
+            words: RwLock::new(Arc::new(words)),
+        }
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.path.is_some()
+    }
+
+    // Re-reads the file; on failure the current list stays in place. Returns the new size.
+    pub fn reload(&self) -> Result<usize, String> {
+        let path = self.path.as_ref().ok_or("WORDLIST_PATH is not set")?;
+        let words = read(path).map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+        let count = words.len();
+        *self.words.write().unwrap() = Arc::new(words);
+        Ok(count)
+    }
+
+    // The first listed word in `text`, if any
+    pub fn find_in(&self, text: &str) -> Option<String> {
+        let words = self.words.read().unwrap().clone();
+        if words.is_empty() {
+            return None;
+        }
+        text.split(|c: char| !c.is_alphanumeric())
+            .map(str::to_lowercase)
+            .find(|word| words.contains(word))
+    }
+}
+
+fn read(path: &Path) -> std::io::Result<HashSet<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_lowercase)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload_swaps_in_the_new_file_and_keeps_the_old_list_on_failure() {
+        let path = std::env::temp_dir().join(format!("wordlist-{}.txt", std::process::id()));
+        fs::write(&path, "# comments and blank lines are skipped\n\nDarn\n").unwrap();
+        let list = WordList::load(Some(path.clone()));
+        assert_eq!(list.find_in("well, darn!").as_deref(), Some("darn"));
+        // Whole words only
+        assert_eq!(list.find_in("darning socks"), None);
+
+        fs::write(&path, "heck\nblast\n").unwrap();
+        assert_eq!(list.reload(), Ok(2));
+        assert_eq!(list.find_in("darn"), None);
+        assert_eq!(list.find_in("HECK no").as_deref(), Some("heck"));
+
+        fs::remove_file(&path).unwrap();
+        assert!(list.reload().is_err());
+        assert_eq!(list.find_in("blast").as_deref(), Some("blast"));
+    }
+}
@@ -0,0 +1,169 @@
+mod common;
+
+use awc::http::StatusCode;
+use common::Server;
+use serde_json::json;
+
+#[actix_web::test]
+async fn username_availability() {
+    let server = Server::start();
+    server.user("alice").await;
+
+    let (status, body) = server.get("/users/available?username=bob", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "available": true }));
+
+    let (status, body) = server.get("/users/available?username=alice", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "available": false }));
+
+    for invalid in ["a", "has%20space", "x".repeat(100).as_str()] {
+        let (status, body) = server.get(&format!("/users/available?username={}", invalid), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}: {}", invalid, body);
+        assert_eq!(body["code"], "bad_request");
+    }
+}
+
+#[actix_web::test]
+async fn username_availability_is_rate_limited() {
+    let server = Server::start();
+    let mut statuses = Vec::new();
+    for _ in 0..25 {
+        statuses.push(server.get("/users/available?username=bob", None).await.0);
+    }
+    assert!(statuses[..20].iter().all(|status| *status == StatusCode::OK), "{:?}", statuses);
+    assert_eq!(statuses[24], StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[actix_web::test]
+async fn account_errors_carry_a_code() {
+    let server = Server::start();
+    server.user("alice").await;
+
+    let (status, body) = server.register("alice").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body, json!({ "code": "conflict", "message": "User already exists" }));
+    let (status, body) = server.login("alice", "wrong password").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body, json!({ "code": "unauthorized", "message": "Invalid username or password" }));
+}
+
+#[actix_web::test]
+async fn profile_leaves_out_the_password_hash() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+
+    let (status, profile) = server.get("/users/alice", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let mut keys: Vec<&str> = profile.as_object().unwrap().keys().map(String::as_str).collect();
+    keys.sort();
+    assert_eq!(keys, ["created_at", "online", "roles", "username"]);
+    assert_eq!(profile["username"], "alice");
+    assert_eq!(profile["online"], false);
+    assert!(!profile.to_string().contains("argon2"));
+
+    let room = server.room_id(&alice, "general").await;
+    let _ws = server.connect(&room, &alice).await;
+    assert_eq!(server.get("/users/alice", None).await.1["online"], true);
+    assert_eq!(server.get("/users/nobody", None).await.0, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn repeated_bad_passwords_lock_the_account() {
+    let server = Server::start();
+    server.user("alice").await;
+    server.user("bob").await;
+
+    for _ in 0..5 {
+        assert_eq!(server.login("alice", "wrong password").await.0, StatusCode::UNAUTHORIZED);
+    }
+    let (status, body) = server.login("alice", common::PASSWORD).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(body["code"], "rate_limited");
+    assert!(body["retry_after"].as_u64().unwrap() > 0);
+    // Other addresses, and other accounts, aren't affected
+    assert_eq!(server.login_from("192.0.2.7", "alice", common::PASSWORD).await.0, StatusCode::OK);
+    assert_eq!(server.login("bob", common::PASSWORD).await.0, StatusCode::OK);
+
+    // A successful login starts the count over
+    for _ in 0..4 {
+        assert_eq!(server.login("bob", "wrong password").await.0, StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(server.login("bob", common::PASSWORD).await.0, StatusCode::OK);
+    for _ in 0..4 {
+        assert_eq!(server.login("bob", "wrong password").await.0, StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(server.login("bob", common::PASSWORD).await.0, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn me_returns_the_token_owner() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let general = server.room_id(&alice, "general").await;
+    server.room_id(&bob, "elsewhere").await;
+
+    let (status, me) = server.get("/me", Some(&alice)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(me["username"], "alice");
+    assert_eq!(me["roles"], json!(["user"]));
+    let rooms = me["rooms"].as_array().unwrap();
+    assert_eq!(rooms.len(), 1);
+    assert_eq!(rooms[0]["id"], general);
+
+    assert_eq!(server.get("/me", None).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(server.get("/me", Some("not-a-token")).await.0, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn blocklisted_names_are_refused_whatever_their_case() {
+    let server = Server::with_env(&[("USERNAME_BLOCKLIST", "Admin, system")]);
+    for name in ["admin", "ADMIN", "System"] {
+        let (status, body) = server.register(name).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", name);
+        assert_eq!(body["code"], "bad_request");
+    }
+    let alice = server.user("alice").await;
+
+    let room = server.room_id(&alice, "general").await;
+    let refused = server.try_connect(&format!("roomId={}&username=SyStEm", room)).await;
+    assert_eq!(refused.err(), Some(StatusCode::BAD_REQUEST));
+    server.connect_guest(&room, "visitor").await;
+}
+
+#[actix_web::test]
+async fn users_list_and_end_their_own_sessions() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let general = server.room_id(&alice, "general").await;
+    let random = server.room_id(&alice, "random").await;
+    let mut laptop = server.connect(&general, &alice).await;
+    let mut phone = server.connect(&random, &alice).await;
+    let _bob = server.connect(&general, &bob).await;
+
+    let (status, sessions) = server.get("/me/sessions", Some(&alice)).await;
+    assert_eq!(status, StatusCode::OK);
+    let sessions = sessions.as_array().unwrap().clone();
+    let mut rooms: Vec<&str> = sessions.iter().map(|session| session["room_id"].as_str().unwrap()).collect();
+    rooms.sort();
+    let mut expected = [general.as_str(), random.as_str()];
+    expected.sort();
+    assert_eq!(rooms, expected);
+    for session in &sessions {
+        assert_eq!(session["client_ip"], "127.0.0.1");
+        assert!(session["connected_at"].as_u64().unwrap() > 0);
+    }
+
+    let on_phone = sessions.iter().find(|session| session["room_id"] == random.as_str()).unwrap();
+    let path = format!("/me/sessions/{}", on_phone["session_id"].as_str().unwrap());
+    assert_eq!(server.delete(&path, Some(&bob)).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(server.delete(&path, Some(&alice)).await.0, StatusCode::NO_CONTENT);
+    assert_eq!(phone.expect("closed").await["reason"], "Session ended by its user");
+    laptop.say("still here").await;
+    assert_eq!(server.delete(&path, Some(&alice)).await.0, StatusCode::NOT_FOUND);
+    let (_, left) = server.get("/me/sessions", Some(&alice)).await;
+    assert_eq!(left.as_array().unwrap().len(), 1);
+    assert_eq!(left[0]["room_id"], general.as_str());
+}
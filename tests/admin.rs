@@ -0,0 +1,290 @@
+mod common;
+
+use awc::http::{Method, StatusCode};
+use common::Server;
+use serde_json::{json, Value};
+
+#[actix_web::test]
+async fn message_counts_are_sorted_busiest_first() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let quiet = server.room_id(&alice, "quiet").await;
+    let busy = server.room_id(&alice, "busy").await;
+    let mut ws = server.connect(&quiet, &alice).await;
+    ws.say("hello").await;
+    let mut ws = server.connect(&busy, &alice).await;
+    for text in ["one", "two", "three"] {
+        ws.say(text).await;
+    }
+
+    let (status, counts) = server.admin(Method::GET, "/admin/message_counts", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        counts,
+        json!([
+            { "room_id": busy, "name": "busy", "count": 3 },
+            { "room_id": quiet, "name": "quiet", "count": 1 },
+        ])
+    );
+
+    let (status, _) = server.get("/admin/message_counts", None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn only_admins_grant_roles() {
+    let server = Server::with_env(&[("SEED_ADMIN_USER", "root"), ("SEED_ADMIN_PASS", common::PASSWORD)]);
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let (_, body) = server.login("root", common::PASSWORD).await;
+    let root = body["token"].as_str().unwrap().to_string();
+    let room = server.room_id(&alice, "general").await;
+    let grant = json!({ "username": "bob", "role": "moderator" });
+
+    let (status, _) = server.post("/admin/roles", Some(&bob), grant.clone()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = server.get(&format!("/rooms/{}/banned", room), Some(&bob)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, body) = server.post("/admin/roles", Some(&root), grant).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "username": "bob", "roles": ["user", "moderator"] }));
+    let (_, body) = server.login("bob", common::PASSWORD).await;
+    let bob = body["token"].as_str().unwrap();
+    let (status, _) = server.get(&format!("/rooms/{}/banned", room), Some(bob)).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn disconnect_closes_every_session_of_the_user() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let first = server.room_id(&alice, "first").await;
+    let second = server.room_id(&alice, "second").await;
+    let mut sessions = [server.connect(&first, &alice).await, server.connect(&second, &alice).await];
+    let mut bystander = server.connect(&first, &bob).await;
+
+    let body = json!({ "username": "alice", "reason": "Incident response" });
+    assert_eq!(server.post("/admin/disconnect", None, body.clone()).await.0, StatusCode::UNAUTHORIZED);
+    let (status, result) = server.admin(Method::POST, "/admin/disconnect", Some(body)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(result["closed"], 2);
+    for ws in &mut sessions {
+        assert_eq!(ws.expect("closed").await["reason"], "Incident response");
+    }
+    assert!(bystander.drain().await.iter().all(|event| event["type"] != "closed"));
+}
+
+#[actix_web::test]
+async fn tokens_survive_a_key_rotation_for_the_grace_period() {
+    let server = Server::with_env(&[("KEY_ROTATION_GRACE_SECS", "1")]);
+    let old_token = server.user("alice").await;
+
+    assert_eq!(server.post("/admin/rotate_keys", None, json!({})).await.0, StatusCode::UNAUTHORIZED);
+    let (status, body) = server.admin(Method::POST, "/admin/rotate_keys", Some(json!({}))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "retired_keys": 1, "grace_secs": 1 }));
+    let new_token = server.login("alice", common::PASSWORD).await.1["token"].as_str().unwrap().to_string();
+    assert_eq!(server.get("/me", Some(&old_token)).await.0, StatusCode::OK);
+
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(1100)).await;
+    assert_eq!(server.get("/me", Some(&old_token)).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(server.get("/me", Some(&new_token)).await.0, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn debug_broadcast_injects_events_only_when_enabled() {
+    let server = Server::with_env(&[("DEBUG_ENDPOINTS", "true")]);
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut ws = server.connect(&room, &alice).await;
+
+    let event = json!({ "type": "rare_event", "detail": { "n": 1 } });
+    let body = json!({ "room_id": room, "event_json": event });
+    assert_eq!(server.post("/admin/debug/broadcast", Some(&alice), body.clone()).await.0, StatusCode::FORBIDDEN);
+    let (status, _) = server.admin(Method::POST, "/admin/debug/broadcast", Some(body)).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(ws.expect("rare_event").await, json!({ "type": "rare_event", "v": 1, "detail": { "n": 1 } }));
+    let untyped = json!({ "room_id": room, "event_json": { "detail": 1 } });
+    assert_eq!(server.admin(Method::POST, "/admin/debug/broadcast", Some(untyped)).await.0, StatusCode::BAD_REQUEST);
+
+    let disabled = Server::start();
+    let body = json!({ "room_id": room, "event_json": event });
+    assert_eq!(disabled.admin(Method::POST, "/admin/debug/broadcast", Some(body)).await.0, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn finished_sessions_are_recorded_in_the_duration_metric() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let metrics = || server.admin(Method::GET, "/admin/metrics", None);
+    assert_eq!(metrics().await.1["ws_session_duration"]["count"], 0);
+
+    let ws = server.connect(&room, &alice).await;
+    assert_eq!(metrics().await.1["ws_sessions_open"], 1);
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(100)).await;
+    ws.close().await;
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    let (_, body) = metrics().await;
+    assert_eq!(body["ws_sessions_open"], 0);
+    let duration = &body["ws_session_duration"];
+    assert_eq!(duration["count"], 1);
+    assert_eq!(duration["buckets"][0], json!({ "le": 1.0, "count": 1 }));
+    assert!(duration["sum_secs"].as_f64().unwrap() >= 0.1, "{}", duration);
+}
+
+#[actix_web::test]
+async fn reloading_the_wordlist_changes_what_is_blocked() {
+    let path = std::env::temp_dir().join(format!("wordlist-reload-{}.txt", std::process::id()));
+    std::fs::write(&path, "darn\n").unwrap();
+    let server = Server::with_env(&[("WORDLIST_PATH", path.to_str().unwrap())]);
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut ws = server.connect(&room, &alice).await;
+    ws.send(json!({ "type": "message", "text": "darn it" })).await;
+    assert_eq!(ws.expect("error").await["code"], "blocked_word");
+
+    std::fs::write(&path, "heck\nblast\n").unwrap();
+    assert_eq!(server.post("/admin/reload_wordlist", None, json!({})).await.0, StatusCode::UNAUTHORIZED);
+    let (status, body) = server.admin(Method::POST, "/admin/reload_wordlist", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "words": 2 }));
+    ws.say("darn it").await;
+    ws.send(json!({ "type": "message", "text": "heck" })).await;
+    assert_eq!(ws.expect("error").await["code"], "blocked_word");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[actix_web::test]
+async fn exported_state_imports_into_a_fresh_server() {
+    let source = Server::start();
+    let alice = source.user("alice").await;
+    source.user("bob").await;
+    let room = source.room_id(&alice, "general").await;
+    source.add_member(&alice, &room, "bob").await;
+    let mut ws = source.connect(&room, &alice).await;
+    ws.say("before the move").await;
+    ws.close().await;
+    let (status, export) = source.admin(Method::GET, "/admin/export?messages=true", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(export["users"].to_string().contains("$argon2"));
+    assert!(!export.to_string().contains(common::PASSWORD));
+
+    let target = Server::start();
+    let mut old = export.clone();
+    old["version"] = json!(0);
+    assert_eq!(target.admin(Method::POST, "/admin/import", Some(old)).await.0, StatusCode::BAD_REQUEST);
+    let (status, counts) = target.admin(Method::POST, "/admin/import", Some(export)).await;
+    assert_eq!(status, StatusCode::OK, "{}", counts);
+    assert_eq!(counts["users"], 2);
+    assert_eq!(counts["messages"], 1);
+
+    let names = |rooms: Value| -> Vec<(Value, Value)> {
+        let rooms = rooms.as_array().unwrap().iter();
+        let mut rooms: Vec<_> = rooms.map(|room| (room["id"].clone(), room["name"].clone())).collect();
+        rooms.sort_by_key(|room| room.0.to_string());
+        rooms
+    };
+    let before = names(source.get("/list_rooms", None).await.1);
+    assert_eq!(names(target.get("/list_rooms", None).await.1), before);
+    let (status, login) = target.login("bob", common::PASSWORD).await;
+    assert_eq!(status, StatusCode::OK);
+    let bob = login["token"].as_str().unwrap();
+    let (_, members) = target.get(&format!("/rooms/{}/members", room), Some(bob)).await;
+    assert!(members.to_string().contains("bob"), "{}", members);
+    let (_, history) = target.get(&format!("/get_chat_history/{}", room), Some(bob)).await;
+    assert_eq!(history[0]["message"], "before the move");
+
+    // Importing over live sessions would strand them
+    let _bob = target.connect(&room, bob).await;
+    let again = source.admin(Method::GET, "/admin/export", None).await.1;
+    assert_eq!(target.admin(Method::POST, "/admin/import", Some(again)).await.0, StatusCode::CONFLICT);
+}
+
+#[actix_web::test]
+async fn importing_drops_what_belonged_to_the_old_state() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    server.add_member(&alice, &room, "bob").await;
+    let mut ws = server.connect(&room, &alice).await;
+    let message = ws.say("something rude").await;
+    ws.close().await;
+    let report = json!({ "message_id": message["id"], "reason": "rude" });
+    assert_eq!(server.post(&format!("/rooms/{}/report", room), Some(&bob), report).await.0, StatusCode::CREATED);
+    let (_, invite) = server.post(&format!("/rooms/{}/invite", room), Some(&alice), json!({})).await;
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+    let schedule = format!("/rooms/{}/schedule", room);
+    let later = json!({ "text": "later", "send_at": now + 60_000 });
+    let (_, scheduled) = server.post(&schedule, Some(&alice), later).await;
+
+    let export = server.admin(Method::GET, "/admin/export?messages=true", None).await.1;
+    let mut status = StatusCode::CONFLICT;
+    // The closed session may take a moment to leave the room
+    for _ in 0..50 {
+        status = server.admin(Method::POST, "/admin/import", Some(export.clone())).await.0;
+        if status != StatusCode::CONFLICT {
+            break;
+        }
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(status, StatusCode::OK);
+
+    assert_eq!(server.get(&format!("/rooms/{}/reports", room), Some(&alice)).await.1, json!([]));
+    let join = format!("/join?invite={}", invite["invite_token"].as_str().unwrap());
+    assert_eq!(server.post(&join, Some(&bob), json!({})).await.0, StatusCode::NOT_FOUND);
+    let cancel = format!("{}/{}", schedule, scheduled["id"].as_str().unwrap());
+    assert_eq!(server.delete(&cancel, Some(&alice)).await.0, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn admins_set_the_message_of_the_day() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    assert_eq!(server.get("/motd", None).await.1, json!({ "text": "", "level": "info" }));
+
+    let notice = json!({ "text": "  Maintenance at 22:00  ", "level": "warning" });
+    assert_eq!(server.post("/admin/motd", Some(&alice), notice.clone()).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(server.post("/admin/motd", None, notice.clone()).await.0, StatusCode::UNAUTHORIZED);
+    let (status, motd) = server.admin(Method::POST, "/admin/motd", Some(notice)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(motd, json!({ "text": "Maintenance at 22:00", "level": "warning" }));
+    assert_eq!(server.get("/motd", Some(&alice)).await.1, motd);
+
+    server.admin(Method::POST, "/admin/motd", Some(json!({ "text": "" }))).await;
+    assert_eq!(server.get("/motd", None).await.1, json!({ "text": "", "level": "info" }));
+}
+
+#[actix_web::test]
+async fn admin_connections_show_each_sessions_address_and_user_agent() {
+    for record_ips in [true, false] {
+        let server = Server::with_env(&[("RECORD_CLIENT_IPS", if record_ips { "true" } else { "false" })]);
+        let alice = server.user("alice").await;
+        let room = server.room_id(&alice, "general").await;
+        let query = format!("roomId={}&token={}", room, alice);
+        let mut ws = server.try_connect_with(&query, &[("User-Agent", "chat-cli/1.2")]).await.unwrap();
+        ws.expect("connected").await;
+        let long_agent = "x".repeat(1000);
+        let mut other = server.try_connect_with(&query, &[("User-Agent", long_agent.as_str())]).await.unwrap();
+        other.expect("connected").await;
+
+        let (status, connections) = server.admin(Method::GET, "/admin/connections", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(connections.as_array().unwrap().len(), 2);
+        let first = &connections[0];
+        assert_eq!(first["username"], "alice");
+        assert_eq!(first["room_id"], room.as_str());
+        assert_eq!(first["user_agent"], "chat-cli/1.2");
+        assert_eq!(connections[1]["user_agent"].as_str().unwrap().len(), 256);
+        if record_ips {
+            assert_eq!(first["client_ip"], "127.0.0.1");
+        } else {
+            assert!(first.get("client_ip").is_none(), "{}", first);
+        }
+        assert_eq!(server.get("/admin/connections", Some(&alice)).await.0, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,347 @@
+// Shared helpers for the integration tests: each test runs its own server process on a free
+// port and talks to it over HTTP and WebSocket, like a real client would.
+#![allow(dead_code)] // every test file uses a different subset
+
+use actix_codec::Framed;
+use actix_web::web::Bytes;
+use awc::http::{Method, StatusCode};
+use awc::ws::{Codec, Frame, Message};
+use awc::BoxedSocket;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+pub const JWT_SECRET: &str = "integration-test-secret-0123456789abcdef";
+pub const ADMIN_TOKEN: &str = "integration-admin-token";
+pub const PASSWORD: &str = "correct horse battery";
+
+// How long to wait for an event that should arrive, and how long silence has to last to count
+// as nothing arriving
+pub const EVENT_TIMEOUT: Duration = Duration::from_secs(3);
+pub const QUIET: Duration = Duration::from_millis(300);
+
+static REGISTRATIONS: AtomicU32 = AtomicU32::new(1);
+
+pub struct Server {
+    child: Child,
+    pub port: u16,
+    client: awc::Client,
+}
+
+impl Server {
+    pub fn start() -> Server {
+        Server::with_env(&[])
+    }
+
+    // Extra environment on top of the defaults below, e.g. [("MAX_ACTIVE_POLLS", "2")]
+    pub fn with_env(env: &[(&str, &str)]) -> Server {
+        let port = free_port();
+        let child = Command::new(env!("CARGO_BIN_EXE_rust_hw4"))
+            .env_clear()
+            .env("PORT", port.to_string())
+            .env("JWT_SECRET", JWT_SECRET)
+            .env("ADMIN_TOKEN", ADMIN_TOKEN)
+            .env("WORKERS", "2")
+            .env("TRUSTED_PROXIES", "127.0.0.1") // see `register`
+            .envs(env.iter().copied())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start the server");
+        let mut server = Server {
+            child,
+            port,
+            client: awc::Client::builder().timeout(Duration::from_secs(10)).finish(),
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            if let Ok(Some(status)) = server.child.try_wait() {
+                panic!("server exited during startup: {}", status);
+            }
+            assert!(Instant::now() < deadline, "server did not start listening");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        server
+    }
+
+    // Whether the process exited by itself, e.g. after refusing its configuration
+    pub fn exit_status(&mut self) -> Option<std::process::ExitStatus> {
+        self.child.try_wait().unwrap()
+    }
+
+    // Sends SIGTERM, as an orchestrator stopping the container would
+    pub fn terminate(&self) {
+        let status = Command::new("kill").args(["-TERM", &self.child.id().to_string()]).status().unwrap();
+        assert!(status.success(), "kill failed");
+    }
+
+    // Waits up to `timeout` for the process to exit by itself
+    pub fn wait_for_exit(&mut self, timeout: Duration) -> Option<std::process::ExitStatus> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(status) = self.exit_status() {
+                return Some(status);
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        None
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+
+    pub fn request(&self, method: Method, path: &str) -> awc::ClientRequest {
+        self.client.request(method, self.url(path))
+    }
+
+    // Sends a request with an optional bearer token and JSON body; the response body is parsed
+    // as JSON, or returned as a JSON string if it isn't any
+    pub async fn call(
+        &self,
+        method: Method,
+        path: &str,
+        token: Option<&str>,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let mut request = self.request(method, path);
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        let response = match body {
+            Some(body) => request.send_json(&body).await,
+            None => request.send().await,
+        };
+        read(response.expect("request failed")).await
+    }
+
+    pub async fn get(&self, path: &str, token: Option<&str>) -> (StatusCode, Value) {
+        self.call(Method::GET, path, token, None).await
+    }
+
+    pub async fn post(&self, path: &str, token: Option<&str>, body: Value) -> (StatusCode, Value) {
+        self.call(Method::POST, path, token, Some(body)).await
+    }
+
+    pub async fn delete(&self, path: &str, token: Option<&str>) -> (StatusCode, Value) {
+        self.call(Method::DELETE, path, token, None).await
+    }
+
+    pub async fn admin(&self, method: Method, path: &str, body: Option<Value>) -> (StatusCode, Value) {
+        let request = self.request(method, path).insert_header(("X-Admin-Token", ADMIN_TOKEN));
+        let response = match body {
+            Some(body) => request.send_json(&body).await,
+            None => request.send().await,
+        };
+        read(response.expect("request failed")).await
+    }
+
+    pub async fn register(&self, username: &str) -> (StatusCode, Value) {
+        self.register_with(json!({ "username": username, "password": PASSWORD })).await
+    }
+
+    // Each registration claims to come from a different address, so that tests can create more
+    // accounts than the per-address register limit allows
+    pub async fn register_with(&self, body: Value) -> (StatusCode, Value) {
+        let n = REGISTRATIONS.fetch_add(1, Ordering::Relaxed);
+        let client = format!("10.{}.{}.{}", n >> 16 & 0xff, n >> 8 & 0xff, n & 0xff);
+        let response = self
+            .request(Method::POST, "/register")
+            .insert_header(("X-Forwarded-For", client))
+            .send_json(&body)
+            .await;
+        read(response.expect("request failed")).await
+    }
+
+    pub async fn login(&self, username: &str, password: &str) -> (StatusCode, Value) {
+        self.post("/login", None, json!({ "username": username, "password": password }))
+            .await
+    }
+
+    pub async fn login_from(&self, client: &str, username: &str, password: &str) -> (StatusCode, Value) {
+        let response = self
+            .request(Method::POST, "/login")
+            .insert_header(("X-Forwarded-For", client))
+            .send_json(&json!({ "username": username, "password": password }))
+            .await;
+        read(response.expect("request failed")).await
+    }
+
+    // Registers the account and returns a token for it
+    pub async fn user(&self, username: &str) -> String {
+        let (status, body) = self.register(username).await;
+        assert_eq!(status, StatusCode::CREATED, "register {}: {}", username, body);
+        let (status, body) = self.login(username, PASSWORD).await;
+        assert_eq!(status, StatusCode::OK, "login {}: {}", username, body);
+        body["token"].as_str().unwrap().to_string()
+    }
+
+    // Creates a room as the token's user; `extra` is merged into the request body
+    pub async fn room(&self, token: &str, name: &str, extra: Value) -> Value {
+        let mut body = json!({ "name": name });
+        if let Value::Object(extra) = extra {
+            body.as_object_mut().unwrap().extend(extra);
+        }
+        let (status, room) = self.post("/create_room", Some(token), body).await;
+        assert_eq!(status, StatusCode::CREATED, "create_room {}: {}", name, room);
+        room
+    }
+
+    pub async fn room_id(&self, token: &str, name: &str) -> String {
+        self.room(token, name, json!({})).await["id"].as_str().unwrap().to_string()
+    }
+
+    pub async fn add_member(&self, token: &str, room_id: &str, username: &str) {
+        let (status, body) = self
+            .post("/add_user", Some(token), json!({ "room_id": room_id, "username": username }))
+            .await;
+        assert_eq!(status, StatusCode::OK, "add_user {}: {}", username, body);
+    }
+
+    // Opens /ws/ with the given query string; Err with the status if the upgrade was refused
+    pub async fn try_connect(&self, query: &str) -> Result<Ws, StatusCode> {
+        self.try_connect_with(query, &[]).await
+    }
+
+    pub async fn try_connect_with(&self, query: &str, headers: &[(&str, &str)]) -> Result<Ws, StatusCode> {
+        let mut request = awc::Client::new().ws(format!("ws://127.0.0.1:{}/ws/?{}", self.port, query));
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        match request.connect().await {
+            Ok((_, framed)) => Ok(Ws { framed }),
+            Err(awc::error::WsClientError::InvalidResponseStatus(status)) => Err(status),
+            Err(err) => panic!("WebSocket connect failed: {}", err),
+        }
+    }
+
+    // Connects with a token and waits for the connected frame
+    pub async fn connect(&self, room_id: &str, token: &str) -> Ws {
+        self.connect_query(&format!("roomId={}&token={}", room_id, token)).await
+    }
+
+    pub async fn connect_guest(&self, room_id: &str, username: &str) -> Ws {
+        self.connect_query(&format!("roomId={}&username={}", room_id, username)).await
+    }
+
+    pub async fn connect_query(&self, query: &str) -> Ws {
+        let mut ws = self.try_connect(query).await.expect("upgrade refused");
+        ws.expect("connected").await;
+        ws
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+async fn read<S>(mut response: awc::ClientResponse<S>) -> (StatusCode, Value)
+where
+    S: futures_util::Stream<Item = Result<Bytes, awc::error::PayloadError>> + Unpin,
+{
+    let status = response.status();
+    let bytes = response.body().limit(16 * 1024 * 1024).await.expect("reading the body failed");
+    let body = serde_json::from_slice(&bytes)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into_owned()));
+    (status, body)
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+pub struct Ws {
+    framed: Framed<BoxedSocket, Codec>,
+}
+
+impl Ws {
+    pub async fn send(&mut self, event: Value) {
+        self.send_text(&event.to_string()).await;
+    }
+
+    pub async fn send_text(&mut self, text: &str) {
+        self.framed.send(Message::Text(text.to_string().into())).await.unwrap();
+    }
+
+    pub async fn send_message(&mut self, framed: Message) {
+        self.framed.send(framed).await.unwrap();
+    }
+
+    pub async fn close(mut self) {
+        let _ = self.framed.send(Message::Close(None)).await;
+    }
+
+    // The next frame within `timeout`: Some(event) for text, Some({"type": "closed"}) once the
+    // server closed the socket, None if nothing came
+    pub async fn next_within(&mut self, timeout: Duration) -> Option<Value> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.checked_duration_since(Instant::now())?;
+            let frame = match actix_web::rt::time::timeout(left, self.framed.next()).await {
+                Err(_) => return None,
+                Ok(None) | Ok(Some(Err(_))) => return Some(json!({ "type": "closed" })),
+                Ok(Some(Ok(frame))) => frame,
+            };
+            match frame {
+                Frame::Text(bytes) => return Some(serde_json::from_slice(&bytes).expect("frame is not JSON")),
+                Frame::Close(reason) => {
+                    let reason = reason.and_then(|reason| reason.description).unwrap_or_default();
+                    return Some(json!({ "type": "closed", "reason": reason }));
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    // Skips frames until one of the given type arrives
+    pub async fn expect(&mut self, event_type: &str) -> Value {
+        let deadline = Instant::now() + EVENT_TIMEOUT;
+        loop {
+            let left = deadline.checked_duration_since(Instant::now()).unwrap_or_default();
+            match self.next_within(left).await {
+                Some(event) if event["type"] == event_type => return event,
+                Some(event) if event["type"] == "closed" && event_type != "closed" => {
+                    panic!("socket closed while waiting for {:?}: {}", event_type, event)
+                }
+                Some(_) => continue,
+                None => panic!("no {:?} event within {:?}", event_type, EVENT_TIMEOUT),
+            }
+        }
+    }
+
+    // Everything that arrives until the socket has been quiet for QUIET
+    pub async fn drain(&mut self) -> Vec<Value> {
+        let mut events = Vec::new();
+        while let Some(event) = self.next_within(QUIET).await {
+            let closed = event["type"] == "closed";
+            events.push(event);
+            if closed {
+                break;
+            }
+        }
+        events
+    }
+
+    // The frames of one type among what arrives until things go quiet
+    pub async fn drain_type(&mut self, event_type: &str) -> Vec<Value> {
+        self.drain().await.into_iter().filter(|event| event["type"] == event_type).collect()
+    }
+
+    // Sends a chat message and waits for its echo, skipping other people's messages
+    pub async fn say(&mut self, text: &str) -> Value {
+        self.send(json!({ "type": "message", "text": text })).await;
+        loop {
+            let message = self.expect("message").await;
+            if message["message"] == text {
+                return message;
+            }
+        }
+    }
+}
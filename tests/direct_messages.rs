@@ -0,0 +1,86 @@
+mod common;
+
+use awc::http::StatusCode;
+use common::Server;
+use serde_json::{json, Value};
+
+#[actix_web::test]
+async fn dm_is_delivered_then_seen() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut sender = server.connect(&room, &alice).await;
+    let mut recipient = server.connect(&room, &bob).await;
+
+    sender.send(json!({ "type": "dm", "to": "bob", "text": "psst" })).await;
+    let dm = recipient.expect("dm").await;
+    assert_eq!(dm["from"], "alice");
+    assert_eq!(dm["text"], "psst");
+    let id = dm["id"].clone();
+    assert_eq!(sender.expect("delivered").await["message_id"], id);
+
+    recipient.send(json!({ "type": "seen", "message_id": id })).await;
+    let seen = sender.expect("seen").await;
+    assert_eq!(seen["message_id"], id);
+    assert_eq!(seen["by"], "bob");
+}
+
+#[actix_web::test]
+async fn control_characters_are_stripped_from_dms() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut sender = server.connect(&room, &alice).await;
+    let mut recipient = server.connect(&room, &bob).await;
+
+    sender.send(json!({ "type": "dm", "to": "bob", "text": "\u{1b}[31mred\u{7}" })).await;
+    assert_eq!(recipient.expect("dm").await["text"], "[31mred");
+    let (_, dms) = server.get("/me/dms?with=alice", Some(&bob)).await;
+    assert_eq!(dms[0]["text"], "[31mred");
+}
+
+#[actix_web::test]
+async fn dms_to_unknown_users_are_refused() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut sender = server.connect(&room, &alice).await;
+
+    sender.send(json!({ "type": "dm", "to": "nobody", "text": "hello?" })).await;
+    let error = sender.expect("error").await;
+    assert_eq!(error["code"], "user_not_found");
+    assert_eq!(error["message"], "User not found");
+    assert_eq!(server.get("/me/dms?with=nobody", Some(&alice)).await.1, json!([]));
+}
+
+#[actix_web::test]
+async fn conversation_history_is_listed_newest_first() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let carol = server.user("carol").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut sessions = [
+        server.connect(&room, &alice).await,
+        server.connect(&room, &bob).await,
+        server.connect(&room, &carol).await,
+    ];
+    for (from, to, text) in [(0, "bob", "one"), (1, "alice", "two"), (0, "bob", "three"), (2, "alice", "aside")] {
+        sessions[from].send(json!({ "type": "dm", "to": to, "text": text })).await;
+        sessions[from].expect("delivered").await;
+    }
+
+    let texts = |dms: &Value| -> Vec<String> {
+        dms.as_array().unwrap().iter().map(|dm| dm["text"].as_str().unwrap().to_string()).collect()
+    };
+    let (status, dms) = server.get("/me/dms?with=bob", Some(&alice)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(texts(&dms), ["three", "two", "one"]);
+    assert_eq!(dms[1]["from"], "bob");
+    assert_eq!(dms[1]["to"], "alice");
+    assert_eq!(texts(&server.get("/me/dms?with=alice&limit=2", Some(&bob)).await.1), ["three", "two"]);
+    assert_eq!(server.get("/me/dms?with=carol", Some(&bob)).await.1, json!([]));
+    assert_eq!(server.get("/me/dms?with=bob", None).await.0, StatusCode::UNAUTHORIZED);
+}
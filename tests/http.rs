@@ -0,0 +1,140 @@
+mod common;
+
+use awc::http::{Method, StatusCode};
+use common::Server;
+use serde_json::json;
+
+#[actix_web::test]
+async fn oversized_json_body_is_a_structured_413() {
+    let server = Server::with_env(&[("JSON_LIMIT", "1024")]);
+    let alice = server.user("alice").await;
+
+    let body = json!({ "name": "general", "description": "x".repeat(2048) });
+    let (status, error) = server.post("/create_room", Some(&alice), body).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(error["code"], "payload_too_large");
+    assert!(error["message"].as_str().unwrap().contains("1024"), "{}", error);
+
+    let (_, rooms) = server.get("/list_rooms", None).await;
+    assert_eq!(rooms, json!([]));
+    server.room(&alice, "general", json!({})).await;
+}
+
+#[actix_web::test]
+async fn version_reports_the_crate_version_and_features() {
+    let server = Server::start();
+    let (status, body) = server.get("/version", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    let features = body["features"].as_array().unwrap();
+    for feature in ["reactions", "presence", "resume", "batching", "message_seq"] {
+        assert!(features.contains(&json!(feature)), "{} missing from {:?}", feature, features);
+    }
+}
+
+#[actix_web::test]
+async fn unknown_routes_get_a_json_404() {
+    let server = Server::start();
+    let (status, body) = server.get("/no/such/route", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body, json!({ "code": "not_found", "message": "No route for GET /no/such/route" }));
+}
+
+// The error message says which field was too long
+fn assert_names(error: &serde_json::Value, field: &str) {
+    let message = error["message"].as_str().unwrap().to_lowercase();
+    assert!(message.contains(field), "{} doesn't name {}", error, field);
+}
+
+#[actix_web::test]
+async fn over_limit_fields_are_rejected_with_the_field_named() {
+    let server = Server::start();
+    let (status, body) = server.register(&"a".repeat(33)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_names(&body, "username");
+    // Surrounding whitespace doesn't count, and isn't kept
+    assert_eq!(server.register(&format!("  {}  ", "b".repeat(32))).await.0, StatusCode::CREATED);
+    assert_eq!(server.login(&"b".repeat(32), common::PASSWORD).await.0, StatusCode::OK);
+
+    let alice = server.user("alice").await;
+    let (status, body) = server.post("/create_room", Some(&alice), json!({ "name": "r".repeat(65) })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_names(&body, "name");
+    let room = server.room(&alice, &format!(" {} ", "r".repeat(64)), json!({})).await;
+    assert_eq!(room["name"], "r".repeat(64));
+
+    let mut ws = server.connect(room["id"].as_str().unwrap(), &alice).await;
+    ws.send(json!({ "type": "message", "text": "m".repeat(4001) })).await;
+    let error = ws.expect("error").await;
+    assert_eq!(error["code"], "too_long");
+    assert_names(&error, "text");
+    ws.send(json!({ "type": "update_settings", "topic": "t".repeat(257) })).await;
+    let error = ws.expect("error").await;
+    assert_eq!(error["code"], "invalid_event");
+    assert_names(&error, "topic");
+    assert_eq!(ws.say(&"m".repeat(4000)).await["message"], "m".repeat(4000));
+}
+
+#[actix_web::test]
+async fn capacity_reports_usage_against_the_caps() {
+    let server = Server::with_env(&[("MAX_ROOMS", "3"), ("MAX_CONNECTIONS", "5")]);
+    let alice = server.user("alice").await;
+    let first = server.room_id(&alice, "first").await;
+    let second = server.room_id(&alice, "second").await;
+    let _sessions = [server.connect(&first, &alice).await, server.connect(&second, &alice).await];
+
+    let (status, capacity) = server.get("/capacity", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(capacity, json!({ "rooms": 2, "max_rooms": 3, "connections": 2, "max_connections": 5 }));
+
+    let uncapped = Server::start();
+    let capacity = uncapped.get("/capacity", None).await.1;
+    assert_eq!(capacity, json!({ "rooms": 0, "max_rooms": null, "connections": 0, "max_connections": null }));
+}
+
+#[actix_web::test]
+async fn malformed_path_ids_are_a_structured_400() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    for path in ["/rooms/not-a-uuid", "/rooms/not-a-uuid/members"] {
+        let (status, body) = server.get(path, Some(&alice)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+        assert_eq!(body["code"], "bad_request");
+        let message = body["message"].as_str().unwrap();
+        assert!(message.starts_with(&format!("Invalid path segment in {}: ", path)), "{}", message);
+    }
+}
+
+#[actix_web::test]
+async fn rest_clients_are_warned_before_being_limited() {
+    let server = Server::start();
+    let mut warnings = Vec::new();
+    loop {
+        let response = server.request(Method::GET, "/users/available?username=bob").send().await.unwrap();
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            break;
+        }
+        let warning = response.headers().get("x-ratelimit-warning");
+        warnings.push(warning.map(|value| value.to_str().unwrap().to_string()));
+        assert!(warnings.len() <= 21, "never limited");
+    }
+    // 20 requests are allowed and the last fifth of them carry the warning
+    assert!(warnings[..15].iter().all(Option::is_none), "{:?}", warnings);
+    assert_eq!(warnings[15].as_deref(), Some("approaching_limit; remaining=4"));
+    assert_eq!(warnings[19].as_deref(), Some("approaching_limit; remaining=0"));
+}
+
+#[actix_web::test]
+async fn uptime_counts_from_a_fixed_start() {
+    let server = Server::start();
+    let (status, first) = server.get("/uptime", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let now_ms = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+    let started_at = first["started_at"].as_u64().unwrap();
+    assert!(started_at <= now_ms && now_ms - started_at < 60_000, "{}", first);
+
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let (_, second) = server.get("/uptime", None).await;
+    assert_eq!(second["started_at"], started_at);
+    assert!(second["uptime_secs"].as_u64().unwrap() > first["uptime_secs"].as_u64().unwrap());
+}
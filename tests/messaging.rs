@@ -0,0 +1,937 @@
+mod common;
+
+use awc::http::StatusCode;
+use common::Server;
+use serde_json::json;
+
+#[actix_web::test]
+async fn purge_mine_deletes_every_message_of_the_sender() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+
+    let mut watcher = server.connect(&room, &alice).await;
+    let mut sender = server.connect(&room, &bob).await;
+    let mut ids = Vec::new();
+    for text in ["one", "two", "three"] {
+        ids.push(sender.say(text).await["id"].clone());
+    }
+    watcher.say("mine stays").await;
+    watcher.drain().await;
+
+    sender.send(json!({ "type": "purge_mine" })).await;
+    assert_eq!(sender.expect("purged").await["count"], 3);
+    let deleted: Vec<_> = watcher
+        .drain_type("deleted")
+        .await
+        .into_iter()
+        .map(|event| event["message_id"].clone())
+        .collect();
+    assert_eq!(deleted, ids);
+
+    let (_, history) = server.get(&format!("/get_chat_history/{}", room), Some(&alice)).await;
+    let history = history.as_array().unwrap();
+    assert!(history.iter().filter(|message| message["username"] == "bob").all(|message| message["deleted"] == true));
+    assert!(history.iter().any(|message| message["username"] == "alice" && message["deleted"] == false));
+}
+
+#[actix_web::test]
+async fn unreact_removes_the_reaction_from_history() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let history = format!("/get_chat_history/{}", room);
+
+    let mut ws = server.connect(&room, &alice).await;
+    let mut other = server.connect(&room, &bob).await;
+    let id = ws.say("react to me").await["id"].clone();
+    ws.send(json!({ "type": "react", "message_id": id, "emoji": "👍" })).await;
+    other.expect("reaction_added").await;
+    let (_, messages) = server.get(&history, Some(&alice)).await;
+    assert_eq!(messages[0]["reactions"]["👍"], json!(["alice"]));
+
+    // Past REACTION_COOLDOWN_MS, which would refuse a change this quick
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(300)).await;
+    ws.send(json!({ "type": "unreact", "message_id": id, "emoji": "👍" })).await;
+    let removed = other.expect("reaction_removed").await;
+    assert_eq!(removed["username"], "alice");
+    let (_, messages) = server.get(&history, Some(&alice)).await;
+    assert_eq!(messages[0]["reactions"], json!({}));
+}
+
+#[actix_web::test]
+async fn history_false_replays_nothing_until_asked() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut writer = server.connect(&room, &alice).await;
+    for text in ["one", "two", "three"] {
+        writer.say(text).await;
+    }
+
+    let mut reader = server.connect_query(&format!("roomId={}&token={}&history=false", room, bob)).await;
+    assert!(reader.drain_type("history").await.is_empty());
+
+    reader.send(json!({ "type": "history", "limit": 2 })).await;
+    let history = reader.expect("history").await;
+    let texts: Vec<_> = history["messages"].as_array().unwrap().iter().map(|m| m["message"].clone()).collect();
+    assert_eq!(texts, [json!("two"), json!("three")]);
+    assert!(writer.drain_type("history").await.is_empty());
+}
+
+#[actix_web::test]
+async fn resume_token_replays_only_the_missed_messages() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut writer = server.connect(&room, &bob).await;
+
+    let query = format!("roomId={}&token={}", room, alice);
+    let mut ws = server.try_connect(&query).await.unwrap();
+    let resume_token = ws.expect("connected").await["resume_token"].as_str().unwrap().to_string();
+    ws.say("seen").await;
+    ws.close().await;
+    writer.drain().await;
+
+    writer.say("missed 1").await;
+    writer.say("missed 2").await;
+
+    let mut ws = server.try_connect(&format!("{}&resume={}", query, resume_token)).await.unwrap();
+    let history = ws.expect("history").await;
+    let texts: Vec<_> = history["messages"].as_array().unwrap().iter().map(|m| m["message"].clone()).collect();
+    assert_eq!(texts, [json!("missed 1"), json!("missed 2")]);
+    ws.close().await;
+
+    // The token is spent, so using it again gets the full replay
+    let mut ws = server.try_connect(&format!("{}&resume={}", query, resume_token)).await.unwrap();
+    let history = ws.expect("history").await;
+    assert_eq!(history["messages"].as_array().unwrap().len(), 3);
+}
+
+#[actix_web::test]
+async fn batching_coalesces_rapid_messages_into_one_frame() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut writer = server.connect(&room, &alice).await;
+    let mut reader = server.connect(&room, &bob).await;
+    reader.drain().await;
+
+    reader.send(json!({ "type": "set_batching", "interval_ms": 1000 })).await;
+    reader.send(json!({ "type": "history", "limit": 1 })).await;
+    reader.expect("history").await;
+    for text in ["one", "two", "three"] {
+        writer.send(json!({ "type": "message", "text": text })).await;
+    }
+
+    let batch = reader.expect("batch").await;
+    let texts: Vec<_> = batch["events"].as_array().unwrap().iter().map(|event| event["message"].clone()).collect();
+    assert_eq!(texts, [json!("one"), json!("two"), json!("three")]);
+
+    reader.send(json!({ "type": "set_batching", "interval_ms": 0 })).await;
+    reader.send(json!({ "type": "history", "limit": 1 })).await;
+    reader.expect("history").await;
+    writer.say("four").await;
+    assert_eq!(reader.expect("message").await["message"], "four");
+}
+
+#[actix_web::test]
+async fn messages_carry_consecutive_sequence_numbers() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut writer = server.connect(&room, &alice).await;
+    let mut reader = server.connect(&room, &bob).await;
+
+    for text in ["one", "two", "three"] {
+        writer.send(json!({ "type": "message", "text": text })).await;
+    }
+    let mut seqs = Vec::new();
+    for _ in 0..3 {
+        seqs.push(reader.expect("message").await["seq"].clone());
+    }
+    assert_eq!(seqs, [json!(1), json!(2), json!(3)]);
+}
+
+#[actix_web::test]
+async fn repeated_message_is_suppressed() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room(&alice, "general", json!({ "suppress_duplicates": true })).await;
+    let room = room["id"].as_str().unwrap();
+    let mut writer = server.connect(room, &alice).await;
+    let mut reader = server.connect(room, &bob).await;
+
+    writer.say("hello").await;
+    writer.send(json!({ "type": "message", "text": "hello" })).await;
+    writer.expect("duplicate_suppressed").await;
+    writer.say("hello there").await;
+
+    let texts: Vec<_> = reader.drain_type("message").await.into_iter().map(|m| m["message"].clone()).collect();
+    assert_eq!(texts, [json!("hello"), json!("hello there")]);
+}
+
+#[actix_web::test]
+async fn pins_are_listed_and_limited() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut moderator = server.connect(&room, &alice).await;
+    let mut member = server.connect(&room, &bob).await;
+    let mut ids = Vec::new();
+    for n in 0..11 {
+        let ws = if n % 2 == 0 { &mut moderator } else { &mut member };
+        ids.push(ws.say(&n.to_string()).await["id"].clone());
+    }
+    moderator.drain().await;
+
+    member.send(json!({ "type": "pin", "message_id": ids[0] })).await;
+    assert_eq!(member.expect("error").await["code"], "forbidden");
+
+    for id in &ids[..10] {
+        moderator.send(json!({ "type": "pin", "message_id": id })).await;
+        assert_eq!(member.expect("pinned").await["message_id"], *id);
+    }
+    moderator.send(json!({ "type": "pin", "message_id": ids[10] })).await;
+    assert_eq!(moderator.expect("error").await["code"], "pin_limit");
+
+    let (status, pinned) = server.get(&format!("/rooms/{}/pinned", room), Some(&bob)).await;
+    assert_eq!(status, StatusCode::OK);
+    let pinned: Vec<_> = pinned.as_array().unwrap().iter().map(|m| m["id"].clone()).collect();
+    assert_eq!(pinned, ids[..10]);
+
+    moderator.send(json!({ "type": "unpin", "message_id": ids[0] })).await;
+    assert_eq!(member.expect("unpinned").await["message_id"], ids[0]);
+    moderator.send(json!({ "type": "pin", "message_id": ids[10] })).await;
+    assert_eq!(member.expect("pinned").await["message_id"], ids[10]);
+}
+
+#[actix_web::test]
+async fn get_message_returns_the_stored_message() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut writer = server.connect(&room, &alice).await;
+    let sent = writer.say("find me").await;
+
+    let mut reader = server.connect(&room, &bob).await;
+    reader.send(json!({ "type": "get_message", "message_id": sent["id"] })).await;
+    let detail = reader.expect("message_detail").await;
+    assert_eq!(detail["message"]["id"], sent["id"]);
+    assert_eq!(detail["message"]["message"], "find me");
+    assert_eq!(detail["message"]["username"], "alice");
+    assert!(writer.drain_type("message_detail").await.is_empty());
+}
+
+#[actix_web::test]
+async fn rate_status_reports_the_remaining_budget() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut ws = server.connect(&room, &alice).await;
+
+    ws.send(json!({ "type": "rate_status" })).await;
+    let before = ws.expect("rate_status").await["remaining"].as_u64().unwrap();
+    for text in ["one", "two", "three"] {
+        ws.say(text).await;
+    }
+    ws.send(json!({ "type": "rate_status" })).await;
+    let status = ws.expect("rate_status").await;
+    assert!(status["remaining"].as_u64().unwrap() < before, "{} then {}", before, status);
+    assert!(status["reset_in_ms"].as_u64().unwrap() > 0);
+}
+
+#[actix_web::test]
+async fn muted_users_are_not_heard_until_unmuted() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let carol = server.user("carol").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut moderator = server.connect(&room, &alice).await;
+    let mut muted = server.connect(&room, &bob).await;
+    let mut observer = server.connect(&room, &carol).await;
+
+    moderator.send(json!({ "type": "mute", "username": "bob", "duration_secs": 60 })).await;
+    moderator.expect("mute_updated").await;
+    muted.send(json!({ "type": "message", "text": "can you hear me" })).await;
+    muted.expect("muted").await;
+    assert!(observer.drain_type("message").await.is_empty());
+
+    moderator.send(json!({ "type": "unmute", "username": "bob" })).await;
+    moderator.expect("mute_updated").await;
+    muted.say("back").await;
+    assert_eq!(observer.expect("message").await["message"], "back");
+
+    // A mute also ends by itself
+    moderator.send(json!({ "type": "mute", "username": "bob", "duration_secs": 1 })).await;
+    moderator.expect("mute_updated").await;
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(1100)).await;
+    muted.say("again").await;
+    assert_eq!(observer.expect("message").await["message"], "again");
+}
+
+#[actix_web::test]
+async fn message_format_round_trips() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut writer = server.connect(&room, &alice).await;
+    let mut reader = server.connect(&room, &bob).await;
+
+    writer.send(json!({ "type": "message", "text": "**bold**", "format": "markdown" })).await;
+    assert_eq!(reader.expect("message").await["format"], "markdown");
+    writer.say("plain by default").await;
+    assert_eq!(reader.expect("message").await["format"], "plain");
+
+    writer.send(json!({ "type": "message", "text": "<b>", "format": "html" })).await;
+    assert_eq!(writer.expect("error").await["code"], "invalid_event");
+
+    let (_, history) = server.get(&format!("/get_chat_history/{}", room), Some(&alice)).await;
+    let formats: Vec<_> = history.as_array().unwrap().iter().map(|m| m["format"].clone()).collect();
+    assert_eq!(formats, [json!("markdown"), json!("plain")]);
+}
+
+#[actix_web::test]
+async fn clearing_history_empties_the_replay() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut writer = server.connect(&room, &alice).await;
+    let mut listener = server.connect(&room, &bob).await;
+    writer.say("one").await;
+    writer.say("two").await;
+
+    let (status, _) = server.post(&format!("/rooms/{}/clear", room), Some(&bob), json!({})).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = server.post(&format!("/rooms/{}/clear", room), Some(&alice), json!({})).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    listener.expect("history_cleared").await;
+
+    let mut late = server.try_connect(&format!("roomId={}&token={}", room, bob)).await.unwrap();
+    let history: Vec<_> = late.drain_type("history").await;
+    assert!(history.iter().all(|frame| frame["messages"] == json!([])), "{:?}", history);
+}
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+#[actix_web::test]
+async fn scheduled_messages_arrive_later_unless_cancelled() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut listener = server.connect(&room, &bob).await;
+    let schedule = format!("/rooms/{}/schedule", room);
+
+    let (status, _) = server.post(&schedule, Some(&alice), json!({ "text": "late", "send_at": now_ms() - 1 })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let send_at = now_ms() + 600;
+    let (status, _) = server.post(&schedule, Some(&alice), json!({ "text": "later", "send_at": send_at })).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (_, cancelled) = server.post(&schedule, Some(&alice), json!({ "text": "never", "send_at": send_at })).await;
+    let cancel = format!("{}/{}", schedule, cancelled["id"].as_str().unwrap());
+    assert_eq!(server.delete(&cancel, Some(&bob)).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(server.delete(&cancel, Some(&alice)).await.0, StatusCode::NO_CONTENT);
+
+    let message = listener.expect("message").await;
+    assert_eq!(message["message"], "later");
+    assert_eq!(message["username"], "alice");
+    assert!(now_ms() >= send_at);
+    assert!(listener.drain_type("message").await.is_empty());
+}
+
+#[actix_web::test]
+async fn scheduling_is_checked_like_sending() {
+    let path = std::env::temp_dir().join(format!("wordlist-schedule-{}.txt", std::process::id()));
+    std::fs::write(&path, "darn\n").unwrap();
+    let server = Server::with_env(&[("WORDLIST_PATH", path.to_str().unwrap())]);
+    let alice = server.user("alice").await;
+    let mallory = server.user("mallory").await;
+    let private = server.room(&alice, "private", json!({ "private": true })).await;
+    let schedule = format!("/rooms/{}/schedule", private["id"].as_str().unwrap());
+    let send_at = now_ms() + 60_000;
+
+    let (status, body) = server.post(&schedule, Some(&mallory), json!({ "text": "hi", "send_at": send_at })).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["message"], "Room not found");
+
+    let (status, body) = server.post(&schedule, Some(&alice), json!({ "text": "darn it", "send_at": send_at })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["message"], "The message contains a blocked word: darn");
+
+    let mut status = StatusCode::CREATED;
+    for _ in 0..20 {
+        status = server.post(&schedule, Some(&alice), json!({ "text": "hi", "send_at": send_at })).await.0;
+        if status != StatusCode::CREATED {
+            break;
+        }
+    }
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[actix_web::test]
+async fn unread_counts_cover_every_room_of_the_user() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let first = server.room_id(&alice, "first").await;
+    let second = server.room_id(&alice, "second").await;
+    server.add_member(&alice, &first, "bob").await;
+    server.add_member(&alice, &second, "bob").await;
+
+    let mut writer = server.connect(&first, &alice).await;
+    writer.say("one").await;
+    let last = writer.say("two").await;
+    let mut writer = server.connect(&second, &alice).await;
+    writer.say("three").await;
+
+    let mut ws = server.connect(&first, &bob).await;
+    ws.send(json!({ "type": "unread" })).await;
+    assert_eq!(ws.expect("unread").await["counts"], json!({ first.clone(): 2, second.clone(): 1 }));
+
+    ws.send(json!({ "type": "seen", "message_id": last["id"] })).await;
+    ws.send(json!({ "type": "unread" })).await;
+    assert_eq!(ws.expect("unread").await["counts"], json!({ first: 0, second: 1 }));
+}
+
+#[actix_web::test]
+async fn everyone_sees_interleaved_messages_in_the_same_order() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let carol = server.user("carol").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut first = server.connect(&room, &alice).await;
+    let mut second = server.connect(&room, &bob).await;
+    let mut reader = server.connect(&room, &carol).await;
+
+    for n in 0..10 {
+        first.send(json!({ "type": "message", "text": format!("a{}", n) })).await;
+        second.send(json!({ "type": "message", "text": format!("b{}", n) })).await;
+    }
+
+    let mut orders = Vec::new();
+    for ws in [&mut first, &mut second, &mut reader] {
+        let mut order = Vec::new();
+        for _ in 0..20 {
+            let message = ws.expect("message").await;
+            order.push((message["seq"].as_u64().unwrap(), message["message"].as_str().unwrap().to_string()));
+        }
+        orders.push(order);
+    }
+    assert_eq!(orders[0], orders[1]);
+    assert_eq!(orders[0], orders[2]);
+    let seqs: Vec<u64> = orders[0].iter().map(|(seq, _)| *seq).collect();
+    assert_eq!(seqs, (1..=20).collect::<Vec<_>>());
+}
+
+#[actix_web::test]
+async fn signed_rooms_accept_only_valid_signatures() {
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use ed25519_dalek::{Signer, SigningKey};
+
+    let server = Server::start();
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let public_key = BASE64.encode(key.verifying_key().as_bytes());
+    let body = json!({ "username": "alice", "password": common::PASSWORD, "public_key": public_key });
+    assert_eq!(server.register_with(body).await.0, StatusCode::CREATED);
+    let alice = server.login("alice", common::PASSWORD).await.1["token"].as_str().unwrap().to_string();
+    let bob = server.user("bob").await;
+    let room = server.room(&alice, "signed", json!({ "require_signatures": true })).await;
+    let room = room["id"].as_str().unwrap();
+    let mut writer = server.connect(room, &alice).await;
+    let mut reader = server.connect(room, &bob).await;
+
+    let signature = BASE64.encode(key.sign("hello".as_bytes()).to_bytes());
+    writer.send(json!({ "type": "message", "text": "hello", "signature": signature })).await;
+    let message = reader.expect("message").await;
+    assert_eq!(message["message"], "hello");
+    assert_eq!(message["signature"], signature);
+
+    writer.send(json!({ "type": "message", "text": "hello!", "signature": signature })).await;
+    assert_eq!(writer.expect("error").await["code"], "invalid_signature");
+    writer.send(json!({ "type": "message", "text": "unsigned" })).await;
+    assert_eq!(writer.expect("error").await["code"], "invalid_signature");
+    assert!(reader.drain_type("message").await.is_empty());
+}
+
+#[actix_web::test]
+async fn edits_are_only_allowed_within_the_window() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut ws = server.connect(&room, &alice).await;
+    let message = ws.say("helo").await;
+    ws.send(json!({ "type": "edit", "message_id": message["id"], "text": "hello" })).await;
+    let edited = ws.expect("edited").await;
+    assert_eq!(edited["message_id"], message["id"]);
+    assert_eq!(edited["text"], "hello");
+
+    let strict = server.room(&alice, "strict", json!({ "edit_window_secs": 0 })).await;
+    let mut ws = server.connect(strict["id"].as_str().unwrap(), &alice).await;
+    let message = ws.say("helo").await;
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(20)).await;
+    ws.send(json!({ "type": "edit", "message_id": message["id"], "text": "hello" })).await;
+    assert_eq!(ws.expect("error").await["code"], "edit_window_expired");
+    assert!(ws.drain_type("edited").await.is_empty());
+}
+
+#[actix_web::test]
+async fn forwarded_messages_appear_in_the_target_room() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let source = server.room_id(&bob, "source").await;
+    let target = server.room_id(&alice, "target").await;
+    let elsewhere = server.room_id(&bob, "elsewhere").await;
+    server.add_member(&bob, &source, "alice").await;
+    let mut sender = server.connect(&source, &bob).await;
+    let mut forwarder = server.connect(&source, &alice).await;
+    let mut reader = server.connect(&target, &alice).await;
+
+    let original = sender.say("worth sharing").await;
+    forwarder.send(json!({ "type": "forward", "message_id": original["id"], "to_room": target })).await;
+    assert_eq!(forwarder.expect("forwarded").await["to_room"], target);
+    let copy = reader.expect("message").await;
+    assert_eq!(copy["message"], "worth sharing");
+    assert_eq!(copy["username"], "alice");
+    assert_ne!(copy["id"], original["id"]);
+    assert_eq!(
+        copy["forwarded_from"],
+        json!({ "room_id": source, "message_id": original["id"], "username": "bob" })
+    );
+
+    // alice isn't a member of bob's other room
+    forwarder.send(json!({ "type": "forward", "message_id": original["id"], "to_room": elsewhere })).await;
+    assert_eq!(forwarder.expect("error").await["code"], "forbidden");
+}
+
+#[actix_web::test]
+async fn inline_attachments_are_size_and_type_checked() {
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut sender = server.connect(&room, &alice).await;
+    let mut reader = server.connect(&room, &bob).await;
+
+    let small = json!({ "mime": "image/png", "data": BASE64.encode([0x89, b'P', b'N', b'G']) });
+    sender.send(json!({ "type": "message", "text": "look", "attachment": small })).await;
+    let message = reader.expect("message").await;
+    assert_eq!(message["message"], "look");
+    assert_eq!(message["attachment"], small);
+
+    let oversized = json!({ "mime": "image/png", "data": BASE64.encode(vec![0; 64 * 1024 + 1]) });
+    sender.send(json!({ "type": "message", "text": "too big", "attachment": oversized })).await;
+    assert_eq!(sender.expect("error").await["code"], "invalid_attachment");
+    let script = json!({ "mime": "text/html", "data": BASE64.encode("<script>") });
+    sender.send(json!({ "type": "message", "text": "sneaky", "attachment": script })).await;
+    assert_eq!(sender.expect("error").await["code"], "invalid_attachment");
+    assert!(reader.drain_type("message").await.is_empty());
+}
+
+#[actix_web::test]
+async fn history_pages_by_sequence_number() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut ws = server.connect_query(&format!("roomId={}&token={}&history=false", room, alice)).await;
+    let mut seqs = Vec::new();
+    for n in 1..=6 {
+        seqs.push(ws.say(&format!("message {}", n)).await["seq"].as_u64().unwrap());
+    }
+
+    let page = |after_seq: u64, limit: usize| json!({ "type": "history", "after_seq": after_seq, "limit": limit });
+    let history_seqs = |history: serde_json::Value| -> Vec<u64> {
+        let messages = history["messages"].as_array().unwrap();
+        messages.iter().map(|message| message["seq"].as_u64().unwrap()).collect()
+    };
+    ws.send(page(seqs[1], 3)).await;
+    assert_eq!(history_seqs(ws.expect("history").await), seqs[2..5]);
+    ws.send(page(seqs[4], 50)).await;
+    assert_eq!(history_seqs(ws.expect("history").await), seqs[5..]);
+    ws.send(page(seqs[5], 50)).await;
+    assert!(history_seqs(ws.expect("history").await).is_empty());
+}
+
+#[actix_web::test]
+async fn reactions_summary_counts_per_emoji_and_marks_mine() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let carol = server.user("carol").await;
+    let dave = server.user("dave").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut watcher = server.connect(&room, &dave).await;
+    let mut sessions = [
+        server.connect(&room, &alice).await,
+        server.connect(&room, &bob).await,
+        server.connect(&room, &carol).await,
+    ];
+    let id = sessions[0].say("react to me").await["id"].clone();
+    for (who, emoji) in [(0, "👍"), (1, "👍"), (2, "👍"), (1, "❤️")] {
+        // Past REACTION_COOLDOWN_MS for bob's second reaction
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(300)).await;
+        sessions[who].send(json!({ "type": "react", "message_id": id, "emoji": emoji })).await;
+        assert_eq!(watcher.expect("reaction_added").await["emoji"], emoji);
+    }
+
+    sessions[1].send(json!({ "type": "reactions", "message_id": id })).await;
+    let reactions = sessions[1].expect("reactions").await;
+    assert_eq!(reactions["message_id"], id);
+    assert_eq!(reactions["summary"], json!({ "👍": 3, "❤️": 1 }));
+    assert_eq!(reactions["mine"], json!(["❤️", "👍"]));
+    sessions[0].send(json!({ "type": "reactions", "message_id": id })).await;
+    assert_eq!(sessions[0].expect("reactions").await["mine"], json!(["👍"]));
+
+    let unknown = "00000000-0000-0000-0000-000000000000";
+    sessions[1].send(json!({ "type": "reactions", "message_id": unknown })).await;
+    assert_eq!(sessions[1].expect("error").await["code"], "message_not_found");
+}
+
+#[actix_web::test]
+async fn mark_read_moves_the_cursor_up_to_a_seq() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    server.add_member(&alice, &room, "bob").await;
+    let mut writer = server.connect(&room, &alice).await;
+    let mut seqs = Vec::new();
+    for n in 1..=5 {
+        seqs.push(writer.say(&format!("message {}", n)).await["seq"].clone());
+    }
+
+    let mut ws = server.connect_query(&format!("roomId={}&token={}&history=false", room, bob)).await;
+    ws.send(json!({ "type": "mark_read", "up_to_seq": seqs[2] })).await;
+    let marked = ws.expect("marked_read").await;
+    assert_eq!(marked, json!({ "type": "marked_read", "v": 1, "read_seq": seqs[2], "unread": 2 }));
+    // The cursor never moves back, nor past the last message
+    ws.send(json!({ "type": "mark_read", "up_to_seq": seqs[0] })).await;
+    assert_eq!(ws.expect("marked_read").await["read_seq"], seqs[2]);
+    ws.send(json!({ "type": "mark_read", "up_to_seq": 1000 })).await;
+    let marked = ws.expect("marked_read").await;
+    assert_eq!(marked["read_seq"], seqs[4]);
+    assert_eq!(marked["unread"], 0);
+    ws.send(json!({ "type": "unread" })).await;
+    assert_eq!(ws.expect("unread").await["counts"][&room], 0);
+}
+
+#[actix_web::test]
+async fn read_cursors_are_shared_only_where_the_room_allows_it() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let shared = server.room_id(&alice, "shared").await;
+    let private = server.room_id(&alice, "private").await;
+
+    let mut writer = server.connect(&shared, &alice).await;
+    writer.send(json!({ "type": "update_settings", "share_read_cursors": true })).await;
+    assert_eq!(writer.expect("settings").await["share_read_cursors"], true);
+    let seq = writer.say("read me").await["seq"].clone();
+    let mut reader = server.connect(&shared, &bob).await;
+    reader.send(json!({ "type": "mark_read", "up_to_seq": seq })).await;
+    let read = writer.expect("read").await;
+    assert_eq!(read, json!({ "type": "read", "v": 1, "username": "bob", "up_to_seq": seq }));
+    assert!(reader.drain_type("read").await.is_empty());
+
+    let mut writer = server.connect(&private, &alice).await;
+    let seq = writer.say("read me too").await["seq"].clone();
+    let mut reader = server.connect(&private, &bob).await;
+    reader.send(json!({ "type": "mark_read", "up_to_seq": seq })).await;
+    reader.expect("marked_read").await;
+    assert!(writer.drain_type("read").await.is_empty());
+}
+
+#[actix_web::test]
+async fn paused_sessions_get_what_they_missed_on_resume() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut writer = server.connect(&room, &alice).await;
+    let mut reader = server.connect(&room, &bob).await;
+    reader.send(json!({ "type": "pause" })).await;
+    // Answered in order, so the pause has been applied once this arrives
+    reader.send(json!({ "type": "time" })).await;
+    reader.expect("time").await;
+
+    for text in ["one", "two", "three"] {
+        writer.say(text).await;
+    }
+    writer.send(json!({ "type": "typing" })).await;
+    // Everything but chat messages still comes through
+    let live = reader.drain().await;
+    assert!(live.iter().any(|event| event["type"] == "typing"));
+    assert!(live.iter().all(|event| event["type"] != "message"), "{:?}", live);
+
+    reader.send(json!({ "type": "resume" })).await;
+    let mut held = Vec::new();
+    let missed = loop {
+        let event = reader.next_within(common::EVENT_TIMEOUT).await.expect("nothing after resume");
+        match event["type"].as_str() {
+            Some("message") => held.push(event["message"].clone()),
+            Some("missed") => break event,
+            _ => {}
+        }
+    };
+    assert_eq!(held, ["one", "two", "three"]);
+    assert_eq!(missed["count"], 3);
+    // Live again
+    writer.say("four").await;
+    assert_eq!(reader.expect("message").await["message"], "four");
+}
+
+#[actix_web::test]
+async fn newcomers_wait_out_the_cooldown_before_posting() {
+    let server = Server::with_env(&[("NEW_MEMBER_COOLDOWN_SECS", "1")]);
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut creator = server.connect(&room, &alice).await;
+    server.add_member(&alice, &room, "bob").await;
+    let mut newcomer = server.connect(&room, &bob).await;
+
+    newcomer.send(json!({ "type": "message", "text": "buy now" })).await;
+    assert_eq!(newcomer.expect("cooldown").await["retry_after"], 1);
+    creator.say("welcome").await;
+
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(1100)).await;
+    newcomer.say("hello").await;
+}
+
+#[actix_web::test]
+async fn moderators_send_canned_replies_from_templates() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "support").await;
+    server.add_member(&alice, &room, "bob").await;
+    let templates = format!("/rooms/{}/templates", room);
+    let greeting = json!({ "name": "greeting", "text": "Thanks for reaching out!" });
+
+    assert_eq!(server.post(&templates, Some(&bob), greeting.clone()).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(server.post(&templates, Some(&alice), greeting.clone()).await.0, StatusCode::CREATED);
+    assert_eq!(server.get(&templates, Some(&alice)).await.1, json!([greeting]));
+
+    let mut moderator = server.connect(&room, &alice).await;
+    let mut member = server.connect(&room, &bob).await;
+    moderator.send(json!({ "type": "template", "name": "greeting" })).await;
+    let message = member.expect("message").await;
+    assert_eq!(message["message"], "Thanks for reaching out!");
+    assert_eq!(message["username"], "alice");
+    moderator.send(json!({ "type": "template", "name": "farewell" })).await;
+    assert_eq!(moderator.expect("error").await["code"], "template_not_found");
+    member.send(json!({ "type": "template", "name": "greeting" })).await;
+    assert_eq!(member.expect("error").await["code"], "forbidden");
+
+    let (status, _) = server.delete(&format!("{}/greeting", templates), Some(&alice)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(server.get(&templates, Some(&alice)).await.1, json!([]));
+}
+
+#[actix_web::test]
+async fn reactions_are_capped_per_user_and_cooled_down() {
+    let server = Server::with_env(&[("MAX_REACTIONS_PER_USER", "2"), ("REACTION_COOLDOWN_MS", "300")]);
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut ws = server.connect(&room, &alice).await;
+    let mut other = server.connect(&room, &bob).await;
+    let id = ws.say("react to me").await["id"].clone();
+    let react = |emoji: &str| json!({ "type": "react", "message_id": id, "emoji": emoji });
+    let pause = || actix_web::rt::time::sleep(std::time::Duration::from_millis(350));
+
+    ws.send(react("👍")).await;
+    assert_eq!(other.expect("reaction_added").await["emoji"], "👍");
+    ws.send(react("❤️")).await;
+    assert_eq!(ws.expect("error").await["code"], "reaction_limited");
+    pause().await;
+    ws.send(react("❤️")).await;
+    assert_eq!(other.expect("reaction_added").await["emoji"], "❤️");
+    pause().await;
+    ws.send(react("🎉")).await;
+    let error = ws.expect("error").await;
+    assert_eq!(error["code"], "reaction_limited");
+    assert!(error["message"].as_str().unwrap().contains("At most 2"), "{}", error);
+    assert!(other.drain_type("reaction_added").await.is_empty());
+}
+
+#[actix_web::test]
+async fn disappearing_messages_leave_history_when_they_expire() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let history = format!("/get_chat_history/{}", room);
+    let mut ws = server.connect(&room, &alice).await;
+    let mut other = server.connect(&room, &bob).await;
+
+    ws.send(json!({ "type": "message", "text": "gone soon", "ttl_secs": 1 })).await;
+    let message = other.expect("message").await;
+    assert!(message["expires_at"].as_u64().is_some(), "{}", message);
+    ws.say("kept").await;
+    assert_eq!(server.get(&history, Some(&alice)).await.1.as_array().unwrap().len(), 2);
+
+    assert_eq!(other.expect("expired").await["message_id"], message["id"]);
+    let (_, kept) = server.get(&history, Some(&alice)).await;
+    let texts: Vec<&str> = kept.as_array().unwrap().iter().map(|m| m["message"].as_str().unwrap()).collect();
+    assert_eq!(texts, ["kept"]);
+
+    // The room default applies to messages without their own ttl_secs
+    ws.send(json!({ "type": "update_settings", "message_ttl_secs": 1 })).await;
+    other.expect("settings").await;
+    let id = ws.say("also gone").await["id"].clone();
+    assert_eq!(other.expect("expired").await["message_id"], id);
+    ws.send(json!({ "type": "message", "text": "bad", "ttl_secs": 0 })).await;
+    assert_eq!(ws.expect("error").await["code"], "invalid_event");
+}
+
+#[actix_web::test]
+async fn context_returns_the_messages_around_a_target() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut ws = server.connect(&room, &alice).await;
+    let mut ids = Vec::new();
+    for n in 0..10 {
+        ids.push(ws.say(&format!("message {}", n)).await["id"].clone());
+    }
+    let texts = |context: &serde_json::Value| -> Vec<String> {
+        let messages = context["messages"].as_array().unwrap();
+        messages.iter().map(|message| message["message"].as_str().unwrap().to_string()).collect()
+    };
+
+    ws.send(json!({ "type": "context", "message_id": ids[5], "before": 2, "after": 1 })).await;
+    let context = ws.expect("context").await;
+    assert_eq!(context["message_id"], ids[5]);
+    assert_eq!(texts(&context), ["message 3", "message 4", "message 5", "message 6"]);
+
+    // Windows are cut short at either end of the history
+    ws.send(json!({ "type": "context", "message_id": ids[1], "before": 5, "after": 0 })).await;
+    assert_eq!(texts(&ws.expect("context").await), ["message 0", "message 1"]);
+    ws.send(json!({ "type": "context", "message_id": ids[8] })).await;
+    assert_eq!(texts(&ws.expect("context").await).len(), 10);
+
+    let elsewhere = server.room_id(&alice, "elsewhere").await;
+    let mut other = server.connect(&elsewhere, &alice).await;
+    other.send(json!({ "type": "context", "message_id": ids[5] })).await;
+    assert_eq!(other.expect("error").await["code"], "message_not_found");
+}
+
+#[actix_web::test]
+async fn poll_tallies_are_broadcast_as_members_vote() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let carol = server.user("carol").await;
+    let dave = server.user("dave").await;
+    let room = server.room_id(&alice, "general").await;
+    server.add_member(&alice, &room, "bob").await;
+    server.add_member(&alice, &room, "carol").await;
+    let mut host = server.connect(&room, &alice).await;
+    let mut first = server.connect(&room, &bob).await;
+    let mut second = server.connect(&room, &carol).await;
+    let mut visitor = server.connect(&room, &dave).await;
+
+    let question = json!({ "type": "poll", "question": "Lunch?", "options": ["Pizza", "Sushi"] });
+    first.send(question.clone()).await;
+    assert_eq!(first.expect("error").await["code"], "forbidden");
+    host.send(question.clone()).await;
+    let poll = visitor.expect("poll").await;
+    assert_eq!(poll["tallies"], json!([0, 0]));
+    assert_eq!(poll["created_by"], "alice");
+    let poll_id = poll["poll_id"].clone();
+    host.send(question).await;
+    assert_eq!(host.expect("error").await["code"], "poll_limit");
+
+    let vote = |option: usize| json!({ "type": "vote", "poll_id": poll_id, "option": option });
+    first.send(vote(0)).await;
+    assert_eq!(host.expect("poll").await["tallies"], json!([1, 0]));
+    second.send(vote(1)).await;
+    assert_eq!(host.expect("poll").await["tallies"], json!([1, 1]));
+    first.send(vote(1)).await;
+    assert_eq!(host.expect("poll").await["tallies"], json!([0, 2]));
+    first.send(vote(2)).await;
+    assert_eq!(first.expect("error").await["code"], "invalid_event");
+    // Anyone may watch, but only members vote
+    visitor.send(vote(0)).await;
+    assert_eq!(visitor.expect("error").await["code"], "forbidden");
+
+    host.send(json!({ "type": "close_poll", "poll_id": poll_id })).await;
+    let closed = second.drain_type("poll").await.pop().unwrap();
+    assert_eq!(closed["closed"], true);
+    assert_eq!(closed["tallies"], json!([0, 2]));
+    second.send(vote(0)).await;
+    assert_eq!(second.expect("error").await["code"], "poll_not_found");
+}
+
+#[actix_web::test]
+async fn a_message_id_leads_back_to_its_room() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let hidden = server.room(&alice, "hidden", json!({ "private": true })).await;
+    let hidden = hidden["id"].as_str().unwrap();
+    let mut ws = server.connect(&room, &alice).await;
+    let id = ws.say("where am I?").await["id"].as_str().unwrap().to_string();
+    let secret = server.connect(hidden, &alice).await.say("secret").await["id"].as_str().unwrap().to_string();
+
+    let (status, found) = server.get(&format!("/messages/{}/room", id), Some(&bob)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(found, json!({ "message_id": id, "room_id": room }));
+    assert_eq!(server.get(&format!("/messages/{}/room", id), None).await.0, StatusCode::UNAUTHORIZED);
+    // Rooms the caller can't see hide their messages like unknown ids
+    let (status, _) = server.get(&format!("/messages/{}/room", secret), Some(&bob)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(server.get(&format!("/messages/{}/room", secret), Some(&alice)).await.1["room_id"], hidden);
+    let unknown = "/messages/00000000-0000-0000-0000-000000000000/room";
+    assert_eq!(server.get(unknown, Some(&alice)).await.0, StatusCode::NOT_FOUND);
+
+    ws.send(json!({ "type": "purge_mine" })).await;
+    ws.expect("purged").await;
+    assert_eq!(server.get(&format!("/messages/{}/room", id), Some(&alice)).await.0, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn senders_are_warned_before_their_messages_are_refused() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut ws = server.connect(&room, &alice).await;
+    ws.drain().await;
+
+    for n in 0..12 {
+        ws.send(json!({ "type": "message", "text": format!("burst {}", n) })).await;
+    }
+    let events = ws.drain().await;
+    let position = |kind: &str| events.iter().position(|event| event["type"] == kind);
+    let warning = position("warning").expect("no warning");
+    let refused = events.iter().position(|event| event["code"] == "rate_limited").expect("never limited");
+    assert!(warning < refused, "{:?}", events);
+    // Warnings are direct replies, so they can overtake the broadcast echoes
+    let warnings = events.iter().filter(|event| event["type"] == "warning");
+    let remaining: Vec<u64> = warnings.map(|event| event["remaining"].as_u64().unwrap()).collect();
+    assert_eq!(remaining, [2, 1, 0]);
+    assert_eq!(events[warning]["code"], "approaching_limit");
+    assert_eq!(events.iter().filter(|event| event["type"] == "message").count(), 10);
+}
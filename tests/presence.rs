@@ -0,0 +1,198 @@
+mod common;
+
+use awc::http::StatusCode;
+use common::Server;
+use serde_json::json;
+
+#[actix_web::test]
+async fn offline_only_after_the_last_session_closes() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut observer = server.connect(&room, &bob).await;
+
+    let first = server.connect(&room, &alice).await;
+    let second = server.connect(&room, &alice).await;
+    let presence = observer.drain_type("presence").await;
+    assert_eq!(presence.len(), 1, "{:?}", presence);
+    assert_eq!(presence[0]["username"], "alice");
+    assert_eq!(presence[0]["status"], "online");
+
+    first.close().await;
+    assert!(observer.drain_type("presence").await.is_empty());
+
+    second.close().await;
+    let presence = observer.drain_type("presence").await;
+    assert_eq!(presence.len(), 1, "{:?}", presence);
+    assert_eq!(presence[0]["status"], "offline");
+}
+
+#[actix_web::test]
+async fn status_text_reaches_the_room_and_is_cleared_with_the_session() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut observer = server.connect(&room, &bob).await;
+    let mut first = server.connect(&room, &alice).await;
+    let _second = server.connect(&room, &alice).await;
+    observer.drain().await;
+
+    first.send(json!({ "type": "status", "text": "  in a meeting " })).await;
+    let presence = observer.expect("presence").await;
+    assert_eq!(presence["username"], "alice");
+    assert_eq!(presence["status_text"], "in a meeting");
+
+    first.send(json!({ "type": "status", "text": "x".repeat(101) })).await;
+    assert_eq!(first.expect("error").await["code"], "too_long");
+    assert!(observer.drain_type("presence").await.is_empty());
+
+    first.close().await;
+    let presence = observer.expect("presence").await;
+    assert_eq!(presence["status_text"], "");
+}
+
+#[actix_web::test]
+async fn who_typing_lists_recent_typers_until_they_expire() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut typer = server.connect(&room, &alice).await;
+    let mut asker = server.connect(&room, &bob).await;
+
+    typer.send(json!({ "type": "typing" })).await;
+    assert_eq!(asker.expect("typing").await["username"], "alice");
+    asker.send(json!({ "type": "who_typing" })).await;
+    assert_eq!(asker.expect("who_typing").await["usernames"], json!(["alice"]));
+
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(3100)).await;
+    asker.send(json!({ "type": "who_typing" })).await;
+    assert_eq!(asker.expect("who_typing").await["usernames"], json!([]));
+}
+
+#[actix_web::test]
+async fn watchers_hear_about_a_user_in_any_room() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let lobby = server.room_id(&alice, "lobby").await;
+    let elsewhere = server.room_id(&bob, "elsewhere").await;
+    let mut watcher = server.connect(&lobby, &alice).await;
+    watcher.send(json!({ "type": "watch", "usernames": ["bob"] })).await;
+    watcher.drain().await;
+
+    let watched = server.connect(&elsewhere, &bob).await;
+    let presence = watcher.expect("presence").await;
+    assert_eq!(presence["username"], "bob");
+    assert_eq!(presence["status"], "online");
+
+    watched.close().await;
+    let presence = watcher.expect("presence").await;
+    assert_eq!(presence["username"], "bob");
+    assert_eq!(presence["status"], "offline");
+}
+
+#[actix_web::test]
+async fn display_color_reaches_the_room_and_ends_with_the_session() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut colored = server.connect(&room, &alice).await;
+    let mut other_tab = server.connect(&room, &alice).await;
+    let mut watcher = server.connect(&room, &bob).await;
+    // Every session has joined once the join and presence frames have settled
+    colored.drain().await;
+    other_tab.drain().await;
+    watcher.drain().await;
+
+    colored.send(json!({ "type": "set_meta", "color": "red" })).await;
+    assert_eq!(colored.expect("error").await["code"], "invalid_event");
+    colored.send(json!({ "type": "set_meta", "color": "#ff00aa" })).await;
+    let meta = watcher.expect("meta").await;
+    assert_eq!(meta["username"], "alice");
+    assert_eq!(meta["color"], "#ff00aa");
+
+    colored.close().await;
+    let meta = watcher.expect("meta").await;
+    assert_eq!(meta["username"], "alice");
+    assert_eq!(meta["color"], serde_json::Value::Null);
+}
+
+#[actix_web::test]
+async fn own_typing_and_reactions_reach_none_of_the_users_devices() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut phone = server.connect(&room, &alice).await;
+    let mut laptop = server.connect(&room, &alice).await;
+    let mut other = server.connect(&room, &bob).await;
+    phone.drain().await;
+    laptop.drain().await;
+
+    phone.send(json!({ "type": "typing" })).await;
+    assert_eq!(other.expect("typing").await["username"], "alice");
+    let message = phone.say("hello").await;
+    // Messages do reach every device, the sender's other ones included
+    assert_eq!(laptop.expect("message").await["id"], message["id"]);
+    laptop.send(json!({ "type": "react", "message_id": message["id"], "emoji": "👍" })).await;
+    assert_eq!(other.expect("reaction_added").await["username"], "alice");
+
+    for device in [&mut phone, &mut laptop] {
+        let echoed = device.drain().await;
+        let own = |event: &serde_json::Value| event["type"] == "typing" || event["type"] == "reaction_added";
+        assert!(!echoed.iter().any(own), "{:?}", echoed);
+    }
+}
+
+#[actix_web::test]
+async fn batch_presence_reports_each_users_status() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    server.user("carol").await;
+    let room = server.room_id(&alice, "general").await;
+    let _alice = server.connect(&room, &alice).await;
+    let _guest = server.connect_guest(&room, "visitor").await;
+    server.connect(&room, &bob).await.close().await;
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    let names = json!({ "usernames": ["alice", "bob", "carol", "visitor", "nobody"] });
+    let (status, statuses) = server.post("/presence/batch", None, names).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        statuses,
+        json!({ "alice": "online", "bob": "offline", "carol": "offline", "visitor": "online", "nobody": "unknown" })
+    );
+
+    let too_many: Vec<String> = (0..201).map(|n| format!("user{}", n)).collect();
+    let (status, _) = server.post("/presence/batch", None, json!({ "usernames": too_many })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn rapid_typing_frames_are_throttled_per_recipient() {
+    let server = Server::with_env(&[("TRANSIENT_EVENTS_PER_SEC", "5")]);
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut typer = server.connect(&room, &alice).await;
+    let mut reader = server.connect(&room, &bob).await;
+    reader.drain().await;
+
+    for _ in 0..20 {
+        typer.send(json!({ "type": "typing" })).await;
+    }
+    for text in ["one", "two", "three"] {
+        typer.say(text).await;
+    }
+    let events = reader.drain().await;
+    let typing = events.iter().filter(|event| event["type"] == "typing").count();
+    // The first goes out at once and the latest one at the next slot; the rest are dropped
+    assert!((1..=2).contains(&typing), "{} typing frames: {:?}", typing, events);
+    let messages: Vec<&serde_json::Value> = events.iter().filter(|event| event["type"] == "message").collect();
+    assert_eq!(messages.len(), 3);
+}
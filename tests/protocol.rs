@@ -0,0 +1,146 @@
+mod common;
+
+use actix_http::ws::Item;
+use actix_web::web::Bytes;
+use awc::ws::Message;
+use common::Server;
+use serde_json::{json, Value};
+
+// Error frames carry exactly the version, type, a stable code and a human readable message
+fn assert_error(frame: &Value, code: &str) {
+    assert_eq!(frame["type"], "error");
+    assert_eq!(frame["code"], code);
+    assert!(frame["message"].as_str().is_some_and(|message| !message.is_empty()));
+    let mut keys: Vec<&str> = frame.as_object().unwrap().keys().map(String::as_str).collect();
+    keys.sort();
+    assert_eq!(keys, ["code", "message", "type", "v"]);
+}
+
+#[actix_web::test]
+async fn error_frames_have_one_shape() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut ws = server.connect(&room, &alice).await;
+
+    ws.send_text(r#"{"type": "pin", "#).await;
+    assert_error(&ws.expect("error").await, "bad_json");
+
+    ws.send_text(r#"{"type": "pin", "message_id": 42}"#).await;
+    assert_error(&ws.expect("error").await, "invalid_event");
+
+    let unknown = "00000000-0000-0000-0000-000000000001";
+    ws.send(json!({ "type": "get_message", "message_id": unknown })).await;
+    assert_error(&ws.expect("error").await, "message_not_found");
+
+    ws.send(json!({ "type": "message", "text": "x".repeat(10_000) })).await;
+    assert_error(&ws.expect("error").await, "too_long");
+}
+
+#[actix_web::test]
+async fn every_frame_carries_the_protocol_version() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut writer = server.try_connect(&format!("roomId={}&token={}", room, alice)).await.unwrap();
+    let mut reader = server.connect(&room, &bob).await;
+
+    // Events without a version are taken as the current one, other versions are refused
+    writer.send(json!({ "type": "message", "text": "hi" })).await;
+    writer.send(json!({ "v": 1, "type": "message", "text": "versioned" })).await;
+    writer.send(json!({ "v": 99, "type": "message", "text": "future" })).await;
+    assert_error(&writer.expect("error").await, "unsupported_version");
+
+    let frames = writer.drain().await;
+    assert!(frames.iter().all(|frame| frame["v"] == 1), "{:?}", frames);
+    let messages = reader.drain_type("message").await;
+    let texts: Vec<_> = messages.iter().map(|message| message["message"].clone()).collect();
+    assert_eq!(texts, [json!("hi"), json!("versioned")]);
+    assert!(messages.iter().all(|message| message["v"] == 1));
+}
+
+#[actix_web::test]
+async fn time_reports_the_server_clock() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut ws = server.connect(&room, &alice).await;
+
+    let before = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+    ws.send(json!({ "type": "time" })).await;
+    let time = ws.expect("time").await;
+    let after = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+    let epoch_ms = time["epoch_ms"].as_u64().unwrap();
+    assert!((before..=after).contains(&epoch_ms), "{} not within {}..={}", epoch_ms, before, after);
+    let now = time["now"].as_str().unwrap();
+    assert!(now.ends_with('Z') && now.contains('T'), "{}", now);
+}
+
+#[actix_web::test]
+async fn strict_sessions_reject_unknown_event_types() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+
+    let mut strict = server.connect_query(&format!("roomId={}&token={}&strict=true", room, alice)).await;
+    strict.send(json!({ "type": "frobnicate" })).await;
+    assert_error(&strict.expect("error").await, "unknown_event");
+
+    // Otherwise the frame is posted as chat text, as it always was
+    let mut lenient = server.connect(&room, &alice).await;
+    lenient.send(json!({ "type": "frobnicate" })).await;
+    assert_eq!(lenient.expect("message").await["message"], r#"{"type":"frobnicate"}"#);
+}
+
+#[actix_web::test]
+async fn fragmented_messages_are_reassembled_up_to_the_frame_limit() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut ws = server.connect(&room, &alice).await;
+
+    let event = json!({ "type": "message", "text": "stitched together" }).to_string();
+    let (head, tail) = event.split_at(10);
+    let (middle, last) = tail.split_at(12);
+    ws.send_message(Message::Continuation(Item::FirstText(Bytes::copy_from_slice(head.as_bytes())))).await;
+    ws.send_message(Message::Continuation(Item::Continue(Bytes::copy_from_slice(middle.as_bytes())))).await;
+    ws.send_message(Message::Continuation(Item::Last(Bytes::copy_from_slice(last.as_bytes())))).await;
+    assert_eq!(ws.expect("message").await["message"], "stitched together");
+
+    let chunk = Bytes::from(vec![b' '; 50 * 1024]);
+    ws.send_message(Message::Continuation(Item::FirstText(chunk.clone()))).await;
+    ws.send_message(Message::Continuation(Item::Continue(chunk.clone()))).await;
+    ws.send_message(Message::Continuation(Item::Last(chunk))).await;
+    assert_error(&ws.expect("error").await, "too_long");
+    ws.say("still connected").await;
+}
+
+#[actix_web::test]
+async fn hello_enables_only_the_capabilities_both_sides_share() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut host = server.connect(&room, &alice).await;
+    let mut client = server.connect(&room, &bob).await;
+
+    client.send(json!({ "type": "hello", "supports": ["reactions", "compression", "batch", "teleport"] })).await;
+    assert_eq!(client.expect("hello_ack").await["enabled"], json!(["batch", "reactions"]));
+    client.send(json!({ "type": "hello", "supports": ["polls"] })).await;
+    assert_error(&client.expect("error").await, "invalid_event");
+
+    // Live events now come in batches, and polls, which the client didn't claim, are left out
+    host.send(json!({ "type": "poll", "question": "Lunch?", "options": ["Pizza", "Sushi"] })).await;
+    host.expect("poll").await;
+    let id = host.say("hello there").await["id"].clone();
+    host.send(json!({ "type": "react", "message_id": id, "emoji": "👍" })).await;
+    let events: Vec<Value> = client
+        .drain_type("batch")
+        .await
+        .into_iter()
+        .flat_map(|batch| batch["events"].as_array().unwrap().clone())
+        .collect();
+    let kinds: Vec<&str> = events.iter().map(|event| event["type"].as_str().unwrap()).collect();
+    assert_eq!(kinds, ["message", "reaction_added"]);
+}
@@ -0,0 +1,923 @@
+mod common;
+
+use awc::http::{header, Method, StatusCode};
+use common::Server;
+use serde_json::{json, Value};
+
+#[actix_web::test]
+async fn create_room_returns_201_with_location() {
+    let server = Server::start();
+    let token = server.user("alice").await;
+
+    let response = server
+        .request(Method::POST, "/create_room")
+        .bearer_auth(&token)
+        .send_json(&json!({ "name": "general" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let location = response.headers().get(header::LOCATION).unwrap().to_str().unwrap().to_string();
+
+    let (_, rooms) = server.get("/list_rooms", None).await;
+    let id = rooms.as_array().unwrap().iter().find(|room| room["name"] == "general").unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert_eq!(location, format!("/rooms/{}", id));
+
+    let (status, room) = server.get(&location, Some(&token)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(room["name"], "general");
+    assert_eq!(room["creator"], "alice");
+}
+
+#[actix_web::test]
+async fn ephemeral_rooms_keep_no_history() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room(&alice, "scratch", json!({ "ephemeral": true })).await;
+    let room = room["id"].as_str().unwrap();
+
+    let mut ws = server.connect(room, &alice).await;
+    let mut other = server.connect(room, &bob).await;
+    ws.say("gone").await;
+    ws.say("also gone").await;
+    assert_eq!(other.drain_type("message").await.len(), 2);
+
+    let (status, history) = server.get(&format!("/get_chat_history/{}", room), Some(&alice)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(history, json!([]));
+    let mut late = server.connect(room, &bob).await;
+    assert!(late.drain_type("history").await.iter().all(|event| event["messages"] == json!([])));
+}
+
+#[actix_web::test]
+async fn set_topic_reaches_other_clients() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut creator = server.connect(&room, &alice).await;
+    let mut other = server.connect(&room, &bob).await;
+
+    creator.send(json!({ "type": "set_topic", "text": "Release planning" })).await;
+    assert_eq!(other.expect("topic").await["text"], "Release planning");
+    let (_, info) = server.get(&format!("/rooms/{}", room), Some(&bob)).await;
+    assert_eq!(info["topic"], "Release planning");
+
+    other.send(json!({ "type": "set_topic", "text": "Hijacked" })).await;
+    assert_eq!(other.expect("error").await["code"], "forbidden");
+}
+
+#[actix_web::test]
+async fn members_are_paged_in_name_order() {
+    let server = Server::start();
+    let owner = server.user("owner").await;
+    let room = server.room_id(&owner, "big").await;
+    let mut names: Vec<String> = (0..7).map(|i| format!("member{}", i)).collect();
+    names.reverse(); // joined in the opposite order of the listing
+    for name in &names {
+        server.user(name).await;
+        server.add_member(&owner, &room, name).await;
+    }
+    names.sort();
+
+    let mut seen = Vec::new();
+    for offset in [0, 3, 6] {
+        let (status, page) = server
+            .get(&format!("/rooms/{}/members?limit=3&offset={}", room, offset), Some(&owner))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(page["total"], 7);
+        assert_eq!(page["limit"], 3);
+        assert_eq!(page["offset"], offset);
+        seen.extend(page["members"].as_array().unwrap().iter().map(|name| name.as_str().unwrap().to_string()));
+    }
+    assert_eq!(seen, names);
+
+    let (_, past_the_end) = server.get(&format!("/rooms/{}/members?offset=50", room), Some(&owner)).await;
+    assert_eq!(past_the_end["members"], json!([]));
+}
+
+#[actix_web::test]
+async fn dry_run_validates_without_creating() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    server.room(&alice, "general", json!({})).await;
+
+    let (status, body) = server.post("/create_room?dry_run=true", Some(&alice), json!({ "name": "random" })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "valid": true }));
+
+    let (status, body) = server.post("/create_room?dry_run=true", Some(&alice), json!({ "name": "general" })).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+
+    let (_, rooms) = server.get("/list_rooms", None).await;
+    let names: Vec<_> = rooms.as_array().unwrap().iter().map(|room| room["name"].clone()).collect();
+    assert_eq!(names, [json!("general")]);
+}
+
+#[actix_web::test]
+async fn private_room_names_only_conflict_for_those_who_see_them() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    server.room(&alice, "secret", json!({ "private": true })).await;
+
+    let (status, _) = server.post("/create_room?dry_run=true", Some(&alice), json!({ "name": "Secret" })).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, body) = server.post("/create_room?dry_run=true", Some(&bob), json!({ "name": "Secret" })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "valid": true }));
+}
+
+#[actix_web::test]
+async fn banned_users_are_listed_for_moderators_and_kept_out() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let carol = server.user("carol").await;
+    let room = server.room_id(&alice, "general").await;
+
+    let (status, _) = server.post(&format!("/rooms/{}/ban", room), Some(&alice), json!({ "username": "bob" })).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, banned) = server.get(&format!("/rooms/{}/banned", room), Some(&alice)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(banned, json!(["bob"]));
+
+    let (status, body) = server.get(&format!("/rooms/{}/banned", room), Some(&carol)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "forbidden");
+    let unknown = "00000000-0000-0000-0000-000000000001";
+    let (status, _) = server.get(&format!("/rooms/{}/banned", unknown), Some(&alice)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let refused = server.try_connect(&format!("roomId={}&token={}", room, bob)).await;
+    assert_eq!(refused.err(), Some(StatusCode::FORBIDDEN));
+}
+
+#[actix_web::test]
+async fn history_is_trimmed_to_each_rooms_own_size() {
+    let server = Server::with_env(&[("MAX_HISTORY", "5")]);
+    let alice = server.user("alice").await;
+    let small = server.room(&alice, "small", json!({ "history_size": 2 })).await["id"].as_str().unwrap().to_string();
+    let large = server.room_id(&alice, "large").await;
+    let (status, _) = server.post("/create_room", Some(&alice), json!({ "name": "huge", "history_size": 6 })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    for room in [&small, &large] {
+        let mut ws = server.connect(room, &alice).await;
+        for n in 1..=7 {
+            ws.say(&n.to_string()).await;
+        }
+    }
+
+    for (room, expected) in [(&small, vec!["6", "7"]), (&large, vec!["3", "4", "5", "6", "7"])] {
+        let (_, history) = server.get(&format!("/get_chat_history/{}", room), Some(&alice)).await;
+        let texts: Vec<_> = history.as_array().unwrap().iter().map(|m| m["message"].as_str().unwrap()).collect();
+        assert_eq!(texts, expected);
+    }
+}
+
+#[actix_web::test]
+async fn private_rooms_stay_hidden_from_non_members() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let carol = server.user("carol").await;
+    let lobby = server.room_id(&alice, "lobby").await;
+    let secret = server.room(&alice, "secret", json!({ "private": true })).await["id"].as_str().unwrap().to_string();
+
+    let mut ws = server.connect(&lobby, &carol).await;
+    ws.send(json!({ "type": "list_rooms" })).await;
+    let rooms = ws.expect("rooms").await;
+    let names: Vec<_> = rooms["rooms"].as_array().unwrap().iter().map(|room| room["name"].clone()).collect();
+    assert_eq!(names, [json!("lobby")]);
+
+    for path in ["/rooms/{}", "/rooms/{}/members", "/get_chat_history/{}"] {
+        let path = path.replace("{}", &secret);
+        assert_eq!(server.get(&path, Some(&carol)).await.0, StatusCode::NOT_FOUND, "{}", path);
+    }
+    let (status, _) = server.post("/add_user", Some(&carol), json!({ "room_id": secret, "username": "carol" })).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let refused = server.try_connect(&format!("roomId={}&token={}", secret, carol)).await;
+    assert_eq!(refused.err(), Some(StatusCode::FORBIDDEN));
+
+    server.add_member(&alice, &secret, "carol").await;
+    assert_eq!(server.get(&format!("/rooms/{}", secret), Some(&carol)).await.0, StatusCode::OK);
+    ws.send(json!({ "type": "list_rooms" })).await;
+    assert_eq!(ws.expect("rooms").await["rooms"].as_array().unwrap().len(), 2);
+}
+
+async fn invite(server: &Server, token: &str, room: &str, body: Value) -> String {
+    let (status, invite) = server.post(&format!("/rooms/{}/invite", room), Some(token), body).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", invite);
+    invite["invite_token"].as_str().unwrap().to_string()
+}
+
+#[actix_web::test]
+async fn invites_let_people_join_until_used_or_expired() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let carol = server.user("carol").await;
+    let room = server.room(&alice, "secret", json!({ "private": true })).await["id"].as_str().unwrap().to_string();
+
+    let single = invite(&server, &alice, &room, json!({})).await;
+    let (status, joined) = server.post(&format!("/join?invite={}", single), Some(&bob), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(joined["id"], room.as_str());
+    assert_eq!(server.get(&format!("/rooms/{}", room), Some(&bob)).await.0, StatusCode::OK);
+    let (status, _) = server.post(&format!("/join?invite={}", single), Some(&carol), json!({})).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let expiring = invite(&server, &alice, &room, json!({ "ttl_secs": 1, "single_use": false })).await;
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let (status, body) = server.post(&format!("/join?invite={}", expiring), Some(&carol), json!({})).await;
+    assert_eq!(status, StatusCode::GONE);
+    assert_eq!(body["code"], "invite_expired");
+    assert_eq!(server.get(&format!("/rooms/{}", room), Some(&carol)).await.0, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn batch_returns_the_known_rooms_only() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let general = server.room_id(&alice, "general").await;
+    let random = server.room_id(&alice, "random").await;
+    let secret = server.room(&alice, "secret", json!({ "private": true })).await["id"].clone();
+    let unknown = "00000000-0000-0000-0000-000000000001";
+
+    let ids = json!({ "ids": [general, unknown, random, secret] });
+    let (status, rooms) = server.post("/rooms/batch", Some(&bob), ids).await;
+    assert_eq!(status, StatusCode::OK);
+    let rooms = rooms.as_object().unwrap();
+    let mut found: Vec<_> = rooms.keys().cloned().collect();
+    found.sort();
+    let mut expected = vec![general.clone(), random];
+    expected.sort();
+    assert_eq!(found, expected);
+    assert_eq!(rooms[&general]["name"], "general");
+
+    let (status, body) = server.post("/rooms/batch", Some(&bob), json!({ "ids": [general, "nope"] })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "bad_request");
+    let too_many = vec![unknown; 101];
+    let (status, _) = server.post("/rooms/batch", Some(&bob), json!({ "ids": too_many })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn members_report_messages_to_moderators() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let mallory = server.user("mallory").await;
+    let room = server.room_id(&alice, "general").await;
+    server.add_member(&alice, &room, "bob").await;
+    let mut ws = server.connect(&room, &mallory).await;
+    let message = ws.say("something rude").await;
+    let reports = format!("/rooms/{}/report", room);
+    let report = json!({ "message_id": message["id"], "reason": "rude" });
+
+    let (status, _) = server.post(&reports, Some(&mallory), report.clone()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let unknown = json!({ "message_id": "00000000-0000-0000-0000-000000000001", "reason": "rude" });
+    assert_eq!(server.post(&reports, Some(&bob), unknown).await.0, StatusCode::NOT_FOUND);
+    let (status, created) = server.post(&reports, Some(&bob), report).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    assert_eq!(server.get(&format!("{}s", reports), Some(&bob)).await.0, StatusCode::FORBIDDEN);
+    let (status, listed) = server.get(&format!("{}s", reports), Some(&alice)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(listed, json!([created]));
+    assert_eq!(listed[0]["reporter"], "bob");
+    assert_eq!(listed[0]["message_id"], message["id"]);
+    assert_eq!(listed[0]["reason"], "rude");
+
+    // A private room the reporter can't see doesn't exist as far as they can tell
+    let hidden = server.room(&alice, "hidden", json!({ "private": true })).await;
+    let secret = server.connect(hidden["id"].as_str().unwrap(), &alice).await.say("secret").await;
+    let hidden_reports = format!("/rooms/{}/report", hidden["id"].as_str().unwrap());
+    let report = json!({ "message_id": secret["id"], "reason": "rude" });
+    let (status, error) = server.post(&hidden_reports, Some(&mallory), report).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(error["message"], "Room not found");
+}
+
+#[actix_web::test]
+async fn add_user_requires_a_registered_user() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+
+    server.add_member(&alice, &room, "bob").await;
+    let (status, body) = server.post("/add_user", Some(&alice), json!({ "room_id": room, "username": "ghost" })).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "not_found");
+
+    let (_, members) = server.get(&format!("/rooms/{}/members", room), Some(&alice)).await;
+    assert!(!members.to_string().contains("ghost"), "{}", members);
+}
+
+#[actix_web::test]
+async fn leave_drops_membership_and_closes_the_socket() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    server.add_member(&alice, &room, "bob").await;
+    let mut observer = server.connect(&room, &alice).await;
+    let mut leaver = server.connect(&room, &bob).await;
+
+    leaver.send(json!({ "type": "leave" })).await;
+    let closed = leaver.expect("closed").await;
+    assert_eq!(closed["reason"], "Left the room");
+    assert_eq!(observer.expect("left").await["username"], "bob");
+
+    let (_, room) = server.get(&format!("/rooms/{}", room), Some(&alice)).await;
+    assert_eq!(room["user_count"], 0);
+}
+
+#[actix_web::test]
+async fn rooms_record_when_they_were_created() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as i64;
+    let created = server.room(&alice, "general", json!({})).await;
+    let created_at = created["created_at"].as_i64().unwrap();
+    assert!((created_at - now).abs() < 1000, "created_at {} vs now {}", created_at, now);
+
+    let room = created["id"].as_str().unwrap();
+    let mut ws = server.connect(room, &alice).await;
+    ws.send(json!({ "type": "room_info" })).await;
+    let info = ws.expect("room_info").await;
+    assert_eq!(info["room"]["created_at"], created_at);
+    assert_eq!(info["room"]["creator"], "alice");
+}
+
+#[actix_web::test]
+async fn members_export_as_csv() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    server.user("carol").await;
+    let room = server.room_id(&alice, "general").await;
+    server.add_member(&alice, &room, "carol").await;
+    server.add_member(&alice, &room, "bob").await;
+    let path = format!("/rooms/{}/members.csv", room);
+
+    let mut response = server.request(Method::GET, &path).bearer_auth(&alice).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "text/csv; charset=utf-8");
+    let csv = String::from_utf8(response.body().await.unwrap().to_vec()).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines[0], "username,joined_at");
+    assert_eq!(lines.len(), 3);
+    assert!(lines[1].starts_with("bob,") && lines[2].starts_with("carol,"), "{}", csv);
+
+    assert_eq!(server.get(&path, Some(&bob)).await.0, StatusCode::FORBIDDEN);
+    let unknown = "/rooms/00000000-0000-0000-0000-000000000001/members.csv";
+    assert_eq!(server.get(unknown, Some(&alice)).await.0, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn adding_a_member_twice_reports_added_false() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let body = json!({ "room_id": room, "username": "bob" });
+
+    let (status, first) = server.post("/add_user", Some(&alice), body.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(first["added"], true);
+    assert_eq!(first["id"], room.as_str());
+    let (status, second) = server.post("/add_user", Some(&alice), body).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(second["added"], false);
+    assert_eq!(second["user_count"], 1);
+}
+
+#[actix_web::test]
+async fn new_accounts_join_the_default_room() {
+    let default_room = "7a1d3c5e-0000-4000-8000-000000000001";
+    let server = Server::with_env(&[("DEFAULT_ROOM_ID", default_room)]);
+    let alice = server.user("alice").await;
+    server.user("bob").await;
+
+    let (status, room) = server.get(&format!("/rooms/{}", default_room), Some(&alice)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(room["creator"], "system");
+    assert_eq!(room["persistent"], true);
+    let (_, page) = server.get(&format!("/rooms/{}/members", default_room), Some(&alice)).await;
+    assert_eq!(page["members"], json!(["alice", "bob"]));
+}
+
+#[actix_web::test]
+async fn settings_can_be_updated_and_read_back() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut creator = server.connect(&room, &alice).await;
+    let mut member = server.connect(&room, &bob).await;
+
+    let update = json!({ "type": "update_settings", "topic": "  Plans  ", "history_size": 20, "edit_window_secs": 60 });
+    creator.send(update.clone()).await;
+    let settings = member.expect("settings").await;
+    assert_eq!(settings["topic"], "Plans");
+    assert_eq!(settings["history_size"], 20);
+
+    member.send(update).await;
+    assert_eq!(member.expect("error").await["code"], "forbidden");
+    // One bad field and nothing is applied
+    creator.send(json!({ "type": "update_settings", "topic": "Other", "history_size": 0 })).await;
+    assert_eq!(creator.expect("error").await["code"], "invalid_event");
+
+    member.send(json!({ "type": "get_settings" })).await;
+    let settings = member.expect("settings").await;
+    assert_eq!(settings["topic"], "Plans");
+    assert_eq!(settings["history_size"], 20);
+    assert_eq!(settings["edit_window_secs"], 60);
+    assert_eq!(settings["ephemeral"], false);
+}
+
+#[actix_web::test]
+async fn joining_a_room_delivers_its_welcome_message_once() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let quiet = server.room_id(&alice, "quiet").await;
+    let mut creator = server.connect(&room, &alice).await;
+    creator.send(json!({ "type": "update_settings", "welcome_message": "Be kind" })).await;
+    creator.expect("settings").await;
+
+    let mut ws = server.connect(&room, &bob).await;
+    let welcome = ws.next_within(common::EVENT_TIMEOUT).await.unwrap();
+    assert_eq!(welcome["type"], "welcome");
+    assert_eq!(welcome["text"], "Be kind");
+    assert!(ws.drain_type("welcome").await.is_empty());
+    assert!(creator.drain_type("welcome").await.is_empty());
+
+    let mut ws = server.connect(&quiet, &bob).await;
+    assert!(ws.drain_type("welcome").await.is_empty());
+}
+
+#[actix_web::test]
+async fn room_summary_counts_without_sending_messages() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let lobby = server.room_id(&bob, "lobby").await;
+    server.add_member(&alice, &room, "bob").await;
+    let mut writer = server.connect(&room, &alice).await;
+    writer.say("one").await;
+    let last = writer.say("two").await;
+
+    let mut ws = server.connect(&lobby, &bob).await;
+    ws.send(json!({ "type": "room_summary", "room_id": room })).await;
+    let summary = ws.expect("room_summary").await;
+    assert_eq!(summary["room_id"], room);
+    // Counted like /members, which leaves out a creator who never joined
+    let (_, members) = server.get(&format!("/rooms/{}/members", room), Some(&bob)).await;
+    assert_eq!(summary["member_count"], members["total"]);
+    assert_eq!(summary["online_count"], 1);
+    assert_eq!(summary["message_count"], 2);
+    assert_eq!(summary["last_message_at"], last["sent_at"]);
+    assert!(summary.get("messages").is_none());
+
+    ws.send(json!({ "type": "room_summary", "room_id": "00000000-0000-0000-0000-000000000000" })).await;
+    assert_eq!(ws.expect("error").await["code"], "room_not_found");
+}
+
+#[actix_web::test]
+async fn sync_summarises_the_room_in_one_frame() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    server.user("carol").await;
+    let room = server.room_id(&alice, "general").await;
+    server.add_member(&alice, &room, "carol").await;
+    server.add_member(&alice, &room, "bob").await;
+    let mut writer = server.connect(&room, &alice).await;
+    writer.say("one").await;
+    let last = writer.say("two").await;
+
+    let mut ws = server.connect_query(&format!("roomId={}&token={}&history=false", room, bob)).await;
+    ws.send(json!({ "type": "sync" })).await;
+    let sync = ws.expect("sync").await;
+    assert_eq!(sync["room_id"], room);
+    assert_eq!(sync["members"], json!(["bob", "carol"]));
+    assert_eq!(sync["member_count"], 2);
+    assert_eq!(sync["online"], json!(["alice", "bob"]));
+    assert_eq!(sync["last_seq"], last["seq"]);
+    assert_eq!(sync["unread"], 2);
+    assert_eq!(sync["settings"]["topic"], "");
+    assert_eq!(sync["settings"]["ephemeral"], false);
+    assert!(sync.get("messages").is_none());
+}
+
+#[actix_web::test]
+async fn archived_rooms_are_read_only_and_listed_on_request() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "old-plans").await;
+    let mut ws = server.connect(&room, &alice).await;
+    let kept = ws.say("kept for the record").await;
+    let archive = format!("/rooms/{}/archive", room);
+
+    assert_eq!(server.post(&archive, Some(&bob), json!({})).await.0, StatusCode::FORBIDDEN);
+    let (status, body) = server.post(&archive, Some(&alice), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "room_id": room, "archived": true }));
+    assert_eq!(ws.expect("settings").await["archived"], true);
+
+    ws.send(json!({ "type": "message", "text": "too late" })).await;
+    assert_eq!(ws.expect("error").await["code"], "room_archived");
+    ws.send(json!({ "type": "edit", "message_id": kept["id"], "text": "rewritten" })).await;
+    let error = ws.expect("error").await;
+    assert_eq!(error["code"], "room_archived");
+    assert_eq!(error["message"], "This room is archived");
+    ws.send(json!({ "type": "react", "message_id": kept["id"], "emoji": "👍" })).await;
+    assert_eq!(ws.expect("error").await["code"], "room_archived");
+    ws.send(json!({ "type": "history" })).await;
+    let history = ws.expect("history").await;
+    let texts: Vec<&Value> = history["messages"].as_array().unwrap().iter().map(|m| &m["message"]).collect();
+    assert_eq!(texts, ["kept for the record"]);
+    assert!(history["messages"][0]["reactions"].as_object().is_none_or(|reactions| reactions.is_empty()));
+    assert_eq!(server.try_connect(&format!("roomId={}&token={}", room, bob)).await.err(), Some(StatusCode::FORBIDDEN));
+
+    let listed = |rooms: Value| rooms.as_array().unwrap().iter().any(|listed| listed["id"] == room);
+    assert!(!listed(server.get("/list_rooms", None).await.1));
+    assert!(listed(server.get("/list_rooms?include_archived=true", None).await.1));
+
+    let unarchive = format!("/rooms/{}/unarchive", room);
+    assert_eq!(server.post(&unarchive, Some(&alice), json!({})).await.0, StatusCode::OK);
+    assert!(listed(server.get("/list_rooms", None).await.1));
+    ws.say("back again").await;
+}
+
+#[actix_web::test]
+async fn create_room_can_join_the_creator_in_one_call() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let members = |room: &Value| format!("/rooms/{}/members", room["id"].as_str().unwrap());
+
+    let joined = server.room(&alice, "joined", json!({ "join": true })).await;
+    assert_eq!(joined["user_count"], 1);
+    assert_eq!(server.get(&members(&joined), Some(&alice)).await.1["members"], json!(["alice"]));
+
+    let plain = server.room(&alice, "plain", json!({})).await;
+    assert_eq!(plain["user_count"], 0);
+    assert_eq!(server.get(&members(&plain), Some(&alice)).await.1["members"], json!([]));
+}
+
+#[actix_web::test]
+async fn room_moderators_kick_members_but_not_each_other() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let carol = server.user("carol").await;
+    let dave = server.user("dave").await;
+    server.user("erin").await;
+    let room = server.room_id(&alice, "general").await;
+    for member in ["bob", "carol", "dave", "erin"] {
+        server.add_member(&alice, &room, member).await;
+    }
+    let moderators = format!("/rooms/{}/moderators", room);
+    let kick = format!("/rooms/{}/kick", room);
+    let ban = format!("/rooms/{}/ban", room);
+
+    let grant = |username: &str| json!({ "username": username });
+    assert_eq!(server.post(&moderators, Some(&bob), grant("bob")).await.0, StatusCode::FORBIDDEN);
+    server.post(&moderators, Some(&alice), grant("bob")).await;
+    let (status, body) = server.post(&moderators, Some(&alice), grant("erin")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["moderators"], json!(["bob", "erin"]));
+
+    let mut kicked = server.connect(&room, &carol).await;
+    assert_eq!(server.post(&kick, Some(&dave), grant("carol")).await.0, StatusCode::FORBIDDEN);
+    let (status, body) = server.post(&kick, Some(&bob), grant("carol")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["sessions_closed"], 1);
+    assert_eq!(kicked.expect("closed").await["reason"], "Kicked from the room");
+
+    // Moderators answer to the creator, not to each other
+    assert_eq!(server.post(&kick, Some(&bob), grant("erin")).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(server.post(&ban, Some(&bob), grant("erin")).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(server.post(&kick, Some(&bob), grant("alice")).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(server.post(&kick, Some(&alice), grant("erin")).await.0, StatusCode::OK);
+
+    let (status, _) = server.delete(&format!("{}/bob", moderators), Some(&alice)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(server.post(&kick, Some(&bob), grant("dave")).await.0, StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn active_rooms_are_sorted_by_their_latest_message() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let mut sessions = Vec::new();
+    for name in ["a", "b", "c", "quiet"] {
+        let id = server.room_id(&alice, name).await;
+        sessions.push(server.connect(&id, &alice).await);
+    }
+    let hidden = server.room(&alice, "hidden", json!({ "private": true })).await;
+    let mut hidden = server.connect(hidden["id"].as_str().unwrap(), &alice).await;
+
+    for (room, text) in [(1, "first"), (0, "second"), (2, "third"), (1, "fourth")] {
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(5)).await;
+        sessions[room].say(text).await;
+    }
+    hidden.say("secret").await;
+
+    let names = |rooms: Value| -> Vec<String> {
+        rooms.as_array().unwrap().iter().map(|room| room["name"].as_str().unwrap().to_string()).collect()
+    };
+    let (status, rooms) = server.get("/rooms/active", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(names(rooms), ["b", "c", "a"]);
+    assert_eq!(names(server.get("/rooms/active?limit=2", None).await.1), ["b", "c"]);
+}
+
+#[actix_web::test]
+async fn leaderboard_ranks_senders_by_message_count() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    for (name, count) in [("alice", 1), ("bob", 3), ("carol", 2), ("dave", 2)] {
+        let token = if name == "alice" { alice.clone() } else { server.user(name).await };
+        let mut ws = server.connect(&room, &token).await;
+        for n in 0..count {
+            ws.say(&format!("{} says {}", name, n)).await;
+        }
+    }
+
+    let leaderboard = format!("/rooms/{}/leaderboard", room);
+    let (status, board) = server.get(&leaderboard, Some(&alice)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        board,
+        json!([
+            { "username": "bob", "messages": 3 },
+            { "username": "carol", "messages": 2 },
+            { "username": "dave", "messages": 2 },
+            { "username": "alice", "messages": 1 },
+        ])
+    );
+    let (_, top) = server.get(&format!("{}?limit=1", leaderboard), Some(&alice)).await;
+    assert_eq!(top, json!([{ "username": "bob", "messages": 3 }]));
+    let unknown = "/rooms/00000000-0000-0000-0000-000000000000/leaderboard";
+    assert_eq!(server.get(unknown, Some(&alice)).await.0, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn creating_by_slug_returns_the_same_room_every_time() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let body = json!({ "name": "Team chat", "slug": "team-chat" });
+
+    let (status, created) = server.post("/create_room", Some(&alice), body.clone()).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, again) = server.post("/create_room", Some(&alice), body.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(again["id"], created["id"]);
+    // Whoever asks, the slug names that one room
+    let (status, other) = server.post("/create_room", Some(&bob), body).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(other["id"], created["id"]);
+    assert_eq!(other["creator"], "alice");
+    let rooms = server.get("/list_rooms", None).await.1;
+    assert_eq!(rooms.as_array().unwrap().len(), 1);
+
+    let different = server.room(&alice, "Other", json!({ "slug": "other-chat" })).await;
+    assert_ne!(different["id"], created["id"]);
+    for slug in ["Team-Chat", "-team", "team-", "team_chat", ""] {
+        let (status, _) = server.post("/create_room", Some(&alice), json!({ "name": "x", "slug": slug })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{:?}", slug);
+    }
+}
+
+#[actix_web::test]
+async fn full_member_list_annotates_roles_and_presence() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    server.user("carol").await;
+    let room = server.room_id(&alice, "general").await;
+    server.add_member(&alice, &room, "bob").await;
+    server.add_member(&alice, &room, "carol").await;
+    server.post(&format!("/rooms/{}/moderators", room), Some(&alice), json!({ "username": "bob" })).await;
+    let _bob = server.connect(&room, &bob).await;
+
+    let (status, members) = server.get(&format!("/rooms/{}/members/full", room), Some(&alice)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        members,
+        json!([
+            { "username": "alice", "role": "creator", "online": false },
+            { "username": "bob", "role": "moderator", "online": true },
+            { "username": "carol", "role": "member", "online": false },
+        ])
+    );
+    let unknown = "/rooms/00000000-0000-0000-0000-000000000000/members/full";
+    assert_eq!(server.get(unknown, Some(&alice)).await.0, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn sessions_stop_hearing_a_room_once_they_may_not_see_it() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let carol = server.user("carol").await;
+    let dave = server.user("dave").await;
+    let room = server.room_id(&alice, "general").await;
+    server.add_member(&alice, &room, "bob").await;
+    server.add_member(&alice, &room, "dave").await;
+    let mut creator = server.connect(&room, &alice).await;
+    let mut member = server.connect(&room, &bob).await;
+    let mut outsider = server.connect(&room, &carol).await;
+    creator.say("for everyone").await;
+    assert_eq!(outsider.expect("message").await["message"], "for everyone");
+
+    creator.send(json!({ "type": "update_settings", "private": true })).await;
+    member.expect("settings").await;
+    outsider.drain().await;
+    creator.say("members only").await;
+    let _late = server.connect(&room, &dave).await;
+    assert_eq!(member.expect("message").await["message"], "members only");
+    let leaked = outsider.drain().await;
+    assert!(leaked.iter().all(|event| event["type"] != "message" && event["type"] != "presence"), "{:?}", leaked);
+
+    // Membership is checked per event, so joining lets the session hear the room again
+    server.add_member(&alice, &room, "carol").await;
+    creator.say("welcome aboard").await;
+    assert_eq!(outsider.expect("message").await["message"], "welcome aboard");
+}
+
+#[actix_web::test]
+async fn idle_reports_time_since_the_last_message_and_session() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let idle = format!("/rooms/{}/idle", room);
+
+    let (status, fresh) = server.get(&idle, Some(&alice)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(fresh["message_idle_secs"], Value::Null);
+    assert_eq!(fresh["connection_idle_secs"], Value::Null);
+    assert_eq!(fresh["connections"], 0);
+
+    let mut ws = server.connect(&room, &alice).await;
+    ws.say("anyone here?").await;
+    let busy = server.get(&idle, Some(&alice)).await.1;
+    assert_eq!(busy["message_idle_secs"], 0);
+    assert_eq!(busy["connection_idle_secs"], 0);
+    assert_eq!(busy["connections"], 1);
+
+    ws.close().await;
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let quiet = server.get(&idle, Some(&alice)).await.1;
+    assert_eq!(quiet["connections"], 0);
+    for field in ["message_idle_secs", "connection_idle_secs", "idle_secs"] {
+        assert!((1..5).contains(&quiet[field].as_u64().unwrap()), "{}: {}", field, quiet);
+    }
+    let unknown = "/rooms/00000000-0000-0000-0000-000000000000/idle";
+    assert_eq!(server.get(unknown, Some(&alice)).await.0, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn top_reactions_count_emoji_across_the_room() {
+    let server = Server::with_env(&[("REACTION_COOLDOWN_MS", "1")]);
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut sessions = vec![server.connect(&room, &alice).await];
+    for name in ["bob", "carol", "dave"] {
+        let token = server.user(name).await;
+        sessions.push(server.connect(&room, &token).await);
+    }
+    let first = sessions[0].say("first").await["id"].clone();
+    let second = sessions[0].say("second").await["id"].clone();
+
+    let reactions = [
+        (0, &first, "👍"),
+        (1, &first, "👍"),
+        (0, &second, "🎉"),
+        (1, &second, "👍"),
+        (2, &second, "❤️"),
+        (1, &first, "❤️"),
+    ];
+    for (who, message_id, emoji) in reactions {
+        sessions[who].send(json!({ "type": "react", "message_id": message_id, "emoji": emoji })).await;
+        sessions[3].expect("reaction_added").await;
+        // Outwaits the 1 ms reaction cooldown
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+
+    let top = format!("/rooms/{}/reactions/top", room);
+    let (status, counts) = server.get(&top, Some(&alice)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        counts,
+        json!([
+            { "emoji": "👍", "count": 3 },
+            { "emoji": "❤️", "count": 2 },
+            { "emoji": "🎉", "count": 1 },
+        ])
+    );
+    let (_, limited) = server.get(&format!("{}?limit=1", top), Some(&alice)).await;
+    assert_eq!(limited, json!([{ "emoji": "👍", "count": 3 }]));
+    let unknown = "/rooms/00000000-0000-0000-0000-000000000000/reactions/top";
+    assert_eq!(server.get(unknown, Some(&alice)).await.0, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn add_user_reports_the_first_failed_check() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    server.user("carol").await;
+    let room = server.room_id(&alice, "general").await;
+    server.add_member(&alice, &room, "carol").await;
+    server.post(&format!("/rooms/{}/ban", room), Some(&alice), json!({ "username": "carol" })).await;
+    let unknown = "00000000-0000-0000-0000-000000000000";
+    let server = &server;
+    let add = |token: &str, room_id: &str, username: &str| {
+        let body = json!({ "room_id": room_id, "username": username });
+        let token = token.to_string();
+        async move { server.post("/add_user", Some(&token), body).await }
+    };
+    let expect = |(status, body): (StatusCode, Value), expected: StatusCode, message: &str| {
+        assert_eq!(status, expected, "{}", body);
+        assert_eq!(body["message"], message);
+    };
+
+    expect(add(&alice, unknown, "ghost").await, StatusCode::NOT_FOUND, "Room not found");
+    let forbidden = "Only the room creator or a moderator can do this";
+    expect(add(&bob, &room, "ghost").await, StatusCode::FORBIDDEN, forbidden);
+    expect(add(&bob, &room, "carol").await, StatusCode::FORBIDDEN, forbidden);
+    expect(add(&alice, &room, "ghost").await, StatusCode::NOT_FOUND, "User not found");
+    expect(add(&alice, &room, "carol").await, StatusCode::FORBIDDEN, "User is banned from this room");
+    let (status, body) = add(&alice, &room, "bob").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["added"], true);
+}
+
+#[actix_web::test]
+async fn text_only_rooms_refuse_other_message_types() {
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let carol = server.user("carol").await;
+    let room = server.room_id(&alice, "general").await;
+    server.add_member(&alice, &room, "bob").await;
+    server.add_member(&alice, &room, "carol").await;
+    server.post(&format!("/rooms/{}/moderators", room), Some(&alice), json!({ "username": "bob" })).await;
+    let mut moderator = server.connect(&room, &bob).await;
+    let mut member = server.connect(&room, &carol).await;
+
+    let text_only = json!({ "type": "update_settings", "allowed_message_types": ["message"] });
+    member.send(text_only.clone()).await;
+    assert_eq!(member.expect("error").await["code"], "forbidden");
+    // Moderators may change the allowed types, but nothing else
+    moderator.send(json!({ "type": "update_settings", "allowed_message_types": ["message"], "topic": "x" })).await;
+    assert_eq!(moderator.expect("error").await["code"], "forbidden");
+    moderator.send(text_only).await;
+    assert_eq!(member.expect("settings").await["allowed_message_types"], json!(["message"]));
+
+    let file = json!({ "mime": "image/png", "data": BASE64.encode([0x89, b'P', b'N', b'G']) });
+    member.send(json!({ "type": "message", "text": "look", "attachment": file })).await;
+    assert_eq!(member.expect("error").await["code"], "type_not_allowed");
+    let id = member.say("just text").await["id"].clone();
+    member.send(json!({ "type": "react", "message_id": id, "emoji": "👍" })).await;
+    assert_eq!(member.expect("error").await["code"], "type_not_allowed");
+    moderator.send(json!({ "type": "poll", "question": "Lunch?", "options": ["Pizza", "Sushi"] })).await;
+    assert_eq!(moderator.expect("error").await["code"], "type_not_allowed");
+    assert!(!moderator.drain().await.iter().any(|event| event["type"] == "reaction_added"));
+}
+
+#[actix_web::test]
+async fn a_private_rooms_slug_reveals_nothing_to_outsiders() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let body = json!({ "name": "Secret plans", "slug": "secret-plans", "private": true });
+    let created = server.room(&alice, "Secret plans", body.clone()).await;
+
+    let (status, error) = server.post("/create_room", Some(&bob), body.clone()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(error["message"], "Room not found");
+    assert!(!error.to_string().contains("alice"), "{}", error);
+    let (status, again) = server.post("/create_room", Some(&alice), body).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(again["id"], created["id"]);
+}
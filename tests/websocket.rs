@@ -0,0 +1,241 @@
+mod common;
+
+use awc::http::{Method, StatusCode};
+use common::Server;
+use serde_json::json;
+use std::time::Duration;
+
+#[actix_web::test]
+async fn upgrade_checks_the_origin_against_the_allowlist() {
+    let server = Server::with_env(&[("CORS_ALLOWED_ORIGINS", "https://chat.example")]);
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let query = format!("roomId={}&token={}", room, alice);
+
+    let allowed = server.try_connect_with(&query, &[("Origin", "https://chat.example")]).await;
+    assert!(allowed.is_ok());
+    let refused = server.try_connect_with(&query, &[("Origin", "https://evil.example")]).await;
+    assert_eq!(refused.err(), Some(StatusCode::FORBIDDEN));
+    assert!(server.try_connect(&query).await.is_ok());
+}
+
+#[actix_web::test]
+async fn without_an_allowlist_any_origin_may_upgrade() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let query = format!("roomId={}&token={}", room, alice);
+    assert!(server.try_connect_with(&query, &[("Origin", "https://evil.example")]).await.is_ok());
+}
+
+#[actix_web::test]
+async fn a_token_decides_who_the_session_is() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+
+    // ?username= can't override the account behind the token
+    let mut ws = server.try_connect(&format!("roomId={}&token={}&username=mallory", room, alice)).await.unwrap();
+    assert_eq!(ws.expect("session").await["username"], "alice");
+
+    let mut ws = server
+        .try_connect_with(&format!("roomId={}", room), &[("Authorization", &format!("Bearer {}", alice))])
+        .await
+        .unwrap();
+    assert_eq!(ws.expect("session").await["username"], "alice");
+
+    let forged = server.try_connect(&format!("roomId={}&token=not-a-jwt", room)).await;
+    assert_eq!(forged.err(), Some(StatusCode::UNAUTHORIZED));
+}
+
+#[actix_web::test]
+async fn guests_cant_take_a_name_in_use() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let _guest = server.connect_guest(&room, "visitor").await;
+
+    let mut twin = server.try_connect(&format!("roomId={}&username=visitor", room)).await.unwrap();
+    let error = twin.expect("error").await;
+    assert_eq!(error["code"], "name_taken", "{}", error);
+    twin.expect("closed").await;
+
+    // An account's name is reserved for whoever can log in to it
+    let impostor = server.try_connect(&format!("roomId={}&username=alice", room)).await;
+    assert_eq!(impostor.err(), Some(StatusCode::UNAUTHORIZED));
+    // The account itself may have several sessions
+    let _first = server.connect(&room, &alice).await;
+    let _second = server.connect(&room, &alice).await;
+}
+
+#[actix_web::test]
+async fn connections_per_address_are_capped() {
+    let server = Server::with_env(&[("MAX_WS_PER_IP", "2")]);
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let query = format!("roomId={}&token={}", room, alice);
+    let first = server.connect_query(&query).await;
+    let _second = server.connect_query(&query).await;
+
+    assert_eq!(server.try_connect(&query).await.err(), Some(StatusCode::TOO_MANY_REQUESTS));
+    let mut refused = server
+        .request(Method::GET, &format!("/ws/?{}", query))
+        .insert_header(("Upgrade", "websocket"))
+        .insert_header(("Sec-WebSocket-Version", "13"))
+        .insert_header(("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(refused.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(refused.headers().get("Retry-After").unwrap(), "5");
+    let body: serde_json::Value = refused.json().await.unwrap();
+    assert_eq!(body["code"], "rate_limited");
+    // Another address has slots of its own
+    assert!(server.try_connect_with(&query, &[("X-Forwarded-For", "10.200.0.1")]).await.is_ok());
+
+    first.close().await;
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(server.try_connect(&query).await.is_ok());
+}
+
+#[actix_web::test]
+async fn sigterm_warns_sessions_and_closes_them_after_the_grace_period() {
+    let mut server = Server::with_env(&[("SHUTDOWN_GRACE_SECS", "1")]);
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut leaving = server.connect(&room, &alice).await;
+    let mut staying = server.connect(&room, &bob).await;
+    leaving.drain().await;
+    staying.drain().await;
+
+    server.terminate();
+    let terminated = std::time::Instant::now();
+    for ws in [&mut leaving, &mut staying] {
+        assert_eq!(ws.expect("shutting_down").await["grace_ms"], 1000);
+    }
+    leaving.close().await;
+    let closed = staying.expect("closed").await;
+    assert_eq!(closed["reason"], "Server shutting down");
+    assert!(terminated.elapsed() >= Duration::from_millis(900), "closed before the grace period was over");
+
+    let status = server.wait_for_exit(Duration::from_secs(10)).expect("the server did not exit");
+    assert!(status.success());
+}
+
+#[actix_web::test]
+async fn forwarded_for_from_an_untrusted_peer_is_ignored() {
+    // The test client connects from 127.0.0.1, which isn't a trusted proxy here
+    let server = Server::with_env(&[("MAX_WS_PER_IP", "1"), ("TRUSTED_PROXIES", "10.0.0.1")]);
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let query = format!("roomId={}&token={}", room, alice);
+    let _ws = server.connect_query(&query).await;
+
+    let spoofed = server.try_connect_with(&query, &[("X-Forwarded-For", "10.200.0.1")]).await;
+    assert_eq!(spoofed.err(), Some(StatusCode::TOO_MANY_REQUESTS));
+    let spoofed = server.try_connect_with(&query, &[("Forwarded", "for=10.200.0.1")]).await;
+    assert_eq!(spoofed.err(), Some(StatusCode::TOO_MANY_REQUESTS));
+}
+
+#[actix_web::test]
+async fn subscribing_to_messages_filters_out_typing() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let mut sender = server.connect(&room, &alice).await;
+    let mut reader = server.connect(&room, &bob).await;
+    reader.send(json!({ "type": "subscribe", "events": ["message"] })).await;
+    assert_eq!(reader.expect("subscribed").await["events"], json!(["message"]));
+    reader.drain().await;
+
+    sender.send(json!({ "type": "typing" })).await;
+    sender.say("hello").await;
+    let events = reader.drain().await;
+    let kinds: Vec<&str> = events.iter().map(|event| event["type"].as_str().unwrap()).collect();
+    assert_eq!(kinds, ["message"]);
+
+    reader.send(json!({ "type": "subscribe", "events": null })).await;
+    assert_eq!(reader.expect("subscribed").await["events"], json!(null));
+    sender.send(json!({ "type": "typing" })).await;
+    reader.expect("typing").await;
+}
+
+#[actix_web::test]
+async fn the_first_frame_names_the_session() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let query = format!("roomId={}&token={}", room, alice);
+        let mut ws = server.try_connect(&query).await.expect("upgrade refused");
+        let first = ws.next_within(common::EVENT_TIMEOUT).await.unwrap();
+        assert_eq!(first["type"], "session");
+        assert_eq!(first["room_id"], room.as_str());
+        assert_eq!(first["username"], "alice");
+        let session_id = first["session_id"].as_str().unwrap().to_string();
+        uuid::Uuid::parse_str(&session_id).expect("session_id is not a UUID");
+        assert_eq!(ws.expect("connected").await["session_id"], session_id.as_str());
+        ids.push(session_id);
+    }
+    assert_ne!(ids[0], ids[1]);
+}
+
+#[actix_web::test]
+async fn overlong_query_strings_are_refused_before_parsing() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let room = server.room_id(&alice, "general").await;
+    let query = format!("roomId={}&token={}&pad=", room, alice);
+
+    let long = format!("{}{}", query, "x".repeat(2049 - query.len()));
+    assert_eq!(server.try_connect(&long).await.err(), Some(StatusCode::BAD_REQUEST));
+    let (status, body) = server.get(&format!("/ws/?{}", long), None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["message"], "Query string too long");
+
+    let longest = format!("{}{}", query, "x".repeat(2048 - query.len()));
+    server.connect_query(&longest).await;
+}
+
+#[actix_web::test]
+async fn rapid_reconnects_are_throttled_per_address_and_account() {
+    let server = Server::start();
+    let alice = server.user("alice").await;
+    let bob = server.user("bob").await;
+    let room = server.room_id(&alice, "general").await;
+    let query = |token: &str| format!("roomId={}&token={}", room, token);
+
+    // The burst allows 20 upgrades; a slow refill may let one more through
+    let mut accepted = 0;
+    while let Ok(ws) = server.try_connect_with(&query(&alice), &[("X-Forwarded-For", "10.1.0.1")]).await {
+        ws.close().await;
+        accepted += 1;
+        assert!(accepted <= 21, "reconnects were never throttled");
+    }
+    assert!(accepted >= 20, "only {} upgrades before the limit", accepted);
+    let response = server
+        .request(Method::GET, &format!("/ws/?{}", query(&bob)))
+        .insert_header(("X-Forwarded-For", "10.1.0.1"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().contains_key("retry-after"));
+
+    // Moving to another address doesn't reset the account's budget
+    let moved = server.try_connect_with(&query(&alice), &[("X-Forwarded-For", "10.1.0.2")]).await;
+    assert_eq!(moved.err(), Some(StatusCode::TOO_MANY_REQUESTS));
+    server.try_connect_with(&query(&bob), &[("X-Forwarded-For", "10.1.0.3")]).await.unwrap();
+
+    // A guest name isn't an account, so it gets no budget of its own for others to use up
+    for n in 0..25 {
+        let address = format!("10.2.0.{}", n);
+        let guest = format!("roomId={}&username=visitor", room);
+        let upgraded = server.try_connect_with(&guest, &[("X-Forwarded-For", address.as_str())]).await;
+        assert!(upgraded.is_ok(), "guest upgrade {} was refused", n);
+    }
+}